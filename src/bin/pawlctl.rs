@@ -0,0 +1,231 @@
+// pawlctl
+//
+// Local admin for a running ratchet-pawl, over its control socket
+//
+// (C) 2024 - T.J. Hampton
+//
+use std::env;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+const USAGE: &str = "Usage: pawlctl [--socket PATH] COMMAND
+  user list | user add NAME | user rm NAME | user passwd NAME        (TACACS+ users)
+  admin list | admin add NAME --role ROLE | admin rm NAME | admin passwd NAME | admin role NAME ROLE
+      (web admins; ROLE is viewer, operator, device-admin, policy-admin or superadmin)
+  dev list | dev add NETWORK_ID [--description TEXT] | dev rm NETWORK_ID
+  policy get | policy set FILE | policy validate FILE      (FILE can be -)
+  apikey list | apikey add NAME --scopes SCOPE,... [--expires-in-days N] | apikey rm NAME
+  session list | session rm USERNAME
+  lockout list | lockout rm USERNAME|IP                       (failed web logins)
+  audit [--actor NAME] [--object OBJ] [--event EVENT] [--since UNIX] [--until UNIX] [--after SEQ] [--limit N]
+The socket is RATCHET_PAWL_CONTROL_SOCKET unless given, same as pawl's.";
+
+/// What pawl answered, status and body.
+struct RatchetReply {
+    status: u16,
+    body: String,
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let socket = match args.iter().position(|a| a == "--socket") {
+        Some(i) if i + 1 < args.len() => {
+            let s = args.remove(i + 1);
+            args.remove(i);
+            s
+        },
+        Some(_) => rtp_usage(),
+        None => env::var("RATCHET_PAWL_CONTROL_SOCKET").unwrap_or_default(),
+    };
+    if socket.is_empty() {
+        eprintln!("pawlctl needs --socket or RATCHET_PAWL_CONTROL_SOCKET.");
+        std::process::exit(2);
+    }
+    let words: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+    let result = match words.as_slice() {
+        ["user", "list"] => rtp_get(&socket, "/getusers").map(rtp_pretty),
+        ["user", "add", name] => rtp_password("Password for new user: ")
+            .and_then(|p| rtp_post(&socket, "/adduser", &[("username", name), ("passhash", &p)])),
+        ["user", "passwd", name] => rtp_password("New password: ")
+            .and_then(|p| rtp_post(&socket, "/edituser", &[("username", name), ("passhash", &p)])),
+        ["user", "rm", name] => rtp_post(&socket, "/rmuser", &[("0", name)]),
+        ["admin", "list"] => rtp_get(&socket, "/getadmins").map(rtp_pretty),
+        ["admin", "add", name, rest @ ..] => {
+            let role = rtp_flag(rest, "--role").unwrap_or_default();
+            rtp_password("Password for new admin: ")
+                .and_then(|p| rtp_post(&socket, "/addadmin", &[("username", name), ("password", &p), ("role", &role)]))
+        },
+        ["admin", "passwd", name] => rtp_password("New password: ")
+            .and_then(|p| rtp_post(&socket, "/editadmin", &[("username", name), ("password", &p)])),
+        ["admin", "role", name, role] => rtp_post(&socket, "/editadmin", &[("username", name), ("role", role)]),
+        ["admin", "rm", name] => rtp_post(&socket, "/rmadmin", &[("0", name)]),
+        ["dev", "list"] => rtp_get(&socket, "/getdevs").map(rtp_pretty),
+        ["dev", "add", network_id, rest @ ..] => {
+            let description = rtp_flag(rest, "--description");
+            rtp_password("Device key: ").and_then(|k| {
+                let mut fields = vec![("network_id", *network_id), ("key", k.as_str())];
+                if let Some(d) = description.as_deref() {
+                    fields.push(("description", d));
+                }
+                rtp_post(&socket, "/adddev", &fields)
+            })
+        },
+        ["dev", "rm", network_id] => rtp_post(&socket, "/rmdev", &[("0", network_id)]),
+        ["policy", "get"] => rtp_get(&socket, "/getpolicy"),
+        ["policy", "set", file] => rtp_read_file(file).and_then(|p| rtp_post(&socket, "/pushpolicy", &[("0", &p)])),
+        ["policy", "validate", file] => rtp_read_file(file).and_then(|p| rtp_post(&socket, "/validatepolicy", &[("0", &p)]))
+            .map(|_| String::from("Policy is valid.")),
+        ["apikey", "list"] => rtp_get(&socket, "/getapikeys").map(rtp_pretty),
+        ["apikey", "add", name, rest @ ..] => {
+            let scopes = rtp_flag(rest, "--scopes").unwrap_or_default();
+            let days = rtp_flag(rest, "--expires-in-days");
+            let mut fields = vec![("name", *name), ("scopes", scopes.as_str())];
+            if let Some(d) = days.as_deref() {
+                fields.push(("expires_in_days", d));
+            }
+            rtp_post(&socket, "/addapikey", &fields)
+        },
+        ["apikey", "rm", name] => rtp_post(&socket, "/rmapikey", &[("0", name)]),
+        ["session", "list"] => rtp_get(&socket, "/getsessions").map(rtp_pretty),
+        ["session", "rm", username] => rtp_post(&socket, "/rmsessions", &[("0", username)]),
+        ["lockout", "list"] => rtp_get(&socket, "/getlockouts").map(rtp_pretty),
+        ["lockout", "rm", subject] => rtp_post(&socket, "/unlocklogin", &[("0", subject)]),
+        ["audit", rest @ ..] => {
+            let query: Vec<String> = ["actor", "object", "event", "since", "until", "after", "limit"]
+                .iter()
+                .filter_map(|f| rtp_flag(rest, &format!("--{}", f)).map(|v| format!("{}={}", f, rtp_urlencode(&v))))
+                .collect();
+            rtp_get(&socket, &format!("/getaudit?{}", query.join("&"))).map(rtp_pretty)
+        },
+        _ => rtp_usage(),
+    };
+
+    match result {
+        Ok(out) => {
+            if !out.is_empty() {
+                println!("{}", out.trim_end());
+            }
+        },
+        Err(e) => {
+            eprintln!("pawlctl: {}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn rtp_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn rtp_flag(rest: &[&str], flag: &str) -> Option<String> {
+    rest.iter()
+        .position(|a| *a == flag)
+        .and_then(|i| rest.get(i + 1))
+        .map(|v| v.to_string())
+}
+
+fn rtp_urlencode(s: &str) -> String {
+    s.bytes()
+     .map(|b| match b {
+         b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+         b => format!("%{:02X}", b),
+     })
+     .collect()
+}
+
+fn rtp_pretty(body: String) -> String {
+    match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(v) => serde_json::to_string_pretty(&v).unwrap_or(body),
+        Err(_) => body,
+    }
+}
+
+fn rtp_read_file(file: &str) -> Result<String, String> {
+    let mut s = String::new();
+    match file {
+        "-" => std::io::stdin().read_to_string(&mut s).map(|_| s).map_err(|e| format!("Unable to read stdin: {}", e)),
+        f => std::fs::read_to_string(f).map_err(|e| format!("Unable to read {}: {}", f, e)),
+    }
+}
+
+/// Reads a secret off stdin, without echoing it if that's a terminal.
+fn rtp_password(prompt: &str) -> Result<String, String> {
+    eprint!("{}", prompt);
+    let fd = libc::STDIN_FILENO;
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    let tty = unsafe { libc::tcgetattr(fd, &mut term) } == 0;
+    if tty {
+        let mut quiet = term;
+        quiet.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &quiet) };
+    }
+    let mut line = String::new();
+    let read = std::io::stdin().read_line(&mut line);
+    if tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+        eprintln!();
+    }
+    read.map_err(|e| format!("Unable to read: {}", e))?;
+    let line = line.trim_end_matches(['\r', '\n']).to_string();
+    if line.is_empty() {
+        return Err(String::from("Nothing entered, nothing changed."));
+    }
+    Ok(line)
+}
+
+fn rtp_get(socket: &str, path: &str) -> Result<String, String> {
+    rtp_request(socket, "GET", path, None).and_then(rtp_check)
+}
+
+/// Fields go as multipart/form-data, like the web frontend sends them.
+fn rtp_post(socket: &str, path: &str, fields: &[(&str, &str)]) -> Result<String, String> {
+    let boundary = format!("pawlctl-{}-{}", std::process::id(), rtp_boundary_suffix());
+    let mut body = String::new();
+    for (name, value) in fields {
+        body.push_str(&format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", boundary, name, value));
+    }
+    body.push_str(&format!("--{}--\r\n", boundary));
+    let content_type = format!("multipart/form-data; boundary={}", boundary);
+    rtp_request(socket, "POST", path, Some((&content_type, &body))).and_then(rtp_check)
+}
+
+fn rtp_boundary_suffix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Turns pawl's answer into output, or what went wrong.
+fn rtp_check(reply: RatchetReply) -> Result<String, String> {
+    match reply.status {
+        200 => Ok(reply.body),
+        400 => Err(format!("Rejected: {}", reply.body)),
+        409 => Err(String::from("Conflict: it already exists, isn't valid, or can't be removed.")),
+        410 => Err(String::from("No such thing.")),
+        s => Err(format!("pawl answered {} {}", s, reply.body)),
+    }
+}
+
+/// One HTTP/1.1 request, and the whole answer; pawl closes the connection.
+fn rtp_request(socket: &str, method: &str, path: &str, body: Option<(&str, &str)>) -> Result<RatchetReply, String> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| format!("Unable to connect to {}: {}", socket, e))?;
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: pawl\r\nConnection: close\r\n", method, path);
+    if let Some((content_type, body)) = body {
+        request.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}", content_type, body.len(), body));
+    } else {
+        request.push_str("\r\n");
+    }
+    stream.write_all(request.as_bytes()).map_err(|e| format!("Unable to send: {}", e))?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).map_err(|e| format!("Unable to read the answer: {}", e))?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or(String::from("Garbled answer from pawl."))?;
+    let status = head.split_whitespace()
+                     .nth(1)
+                     .and_then(|s| s.parse::<u16>().ok())
+                     .ok_or(String::from("Garbled answer from pawl."))?;
+    Ok(RatchetReply { status, body: body.to_string() })
+}
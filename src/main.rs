@@ -8,32 +8,115 @@
 extern crate rocket;
 
 use aes::Aes256;
+use aes_gcm::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, Aes256Gcm, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
 use fpe::ff1::{BinaryNumeralString, FF1};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use sharks::{Share, Sharks};
 use rocket::{
-    form::Form, fs::{relative, FileServer}, http::{Cookie, CookieJar, SameSite, Status}, request::{self, FromRequest}, response::status, time::Duration, tokio::sync::Mutex, tokio::sync::oneshot, tokio::sync::oneshot::Sender, Request};
+    form::Form, fs::{relative, FileServer, TempFile}, http::{ContentType, Cookie, CookieJar, SameSite, Status}, request::{self, FromRequest}, response::status, time::Duration, tokio::sync::Mutex, tokio::sync::oneshot, tokio::sync::oneshot::Sender, Request};
 
 use rocket::serde::{json::Json, Serialize};
+use rocket::response::content::RawHtml;
+use rocket::Shutdown;
 use rocket::{Rocket, Build};
 
 use lazy_static::lazy_static;
-use serde::Deserialize;
+use serde::{Deserialize, de::DeserializeOwned};
 use uuid::Uuid;
 use core::str;
-use std::{collections::{HashSet, HashMap}, env, marker::PhantomData, sync::{Arc, atomic::{AtomicU64,Ordering}}, time::Instant};
+use std::{collections::{BTreeMap, HashSet, HashMap}, env, marker::PhantomData, sync::{Arc, atomic::{AtomicBool, AtomicU64,Ordering}}, time::Instant};
 use std::str::FromStr;
 use pwhash::bcrypt;
 
-use redb::{Database, ReadableTable, TableDefinition};
+use redb::{Database, ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle, WriteTransaction};
 use precis_profiles::UsernameCasePreserved;
 use precis_profiles::precis_core::profile::Profile;
 use libc::{mlockall, MCL_CURRENT, MCL_FUTURE, MCL_ONFAULT};
 
 const THE_DATABASE: &str = "ratchet_db.redb";
+const THE_LOCK_FILE: &str = "ratchet-pawl.lock";
 
 lazy_static! {
-    static ref DB: Database = {
-        Database::create(THE_DATABASE).expect("Unable to create database")
+    static ref RATCHET_DATA_DIR: std::path::PathBuf = rtp_data_dir();
+    /// Behind a lock only so compaction can have it to itself, see rtp_compact.
+    static ref DB: std::sync::RwLock<Database> = {
+        rtp_lock_data_dir(&RATCHET_DATA_DIR);
+        let db = match Database::create(RATCHET_DATA_DIR.join(THE_DATABASE)) {
+            Ok(db) => db,
+            Err(e) => {
+                eprintln!("Ratchet-Pawl unable to create database: {:?}", e);
+                std::process::exit(1);
+            },
+        };
+        if let Err(e) = rtp_note_legacy_rows(&db) {
+            eprintln!("Ratchet-Pawl unable to read the database: {:?}", e);
+            std::process::exit(1);
+        }
+        std::sync::RwLock::new(db)
+    };
+}
+
+/// Don't hold onto this across an await, or while waiting on the persistence thread.
+fn rtp_db() -> std::sync::RwLockReadGuard<'static, Database> {
+    DB.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Held for as long as pawl runs, see rtp_lock_data_dir.
+static RATCHET_DATA_LOCK: std::sync::OnceLock<std::fs::File> = std::sync::OnceLock::new();
+
+/// Where the database lives: `--data-dir`, then RATCHET_PAWL_DATA_DIR, then
+/// `data_dir` in Rocket.toml, otherwise the working directory like always.
+fn rtp_data_dir() -> std::path::PathBuf {
+    let dir = rtp_arg("--data-dir")
+                  .or_else(|| Some(rtp_env_key("RATCHET_PAWL_DATA_DIR")).filter(|d| !d.is_empty()))
+                  .or_else(|| rocket::Config::figment().extract_inner::<String>("data_dir").ok());
+    match dir {
+        Some(d) => std::path::PathBuf::from(d),
+        None => std::path::PathBuf::from("."),
+    }
+}
+
+/// Creates the data directory owner-only if it's new, and takes an exclusive
+/// lock in it, so no two pawls ever open the same database. Exits otherwise,
+/// there's nothing pawl can do without its database.
+fn rtp_lock_data_dir(dir: &std::path::Path) {
+    use std::io::Write;
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+    if !dir.exists() {
+        if let Err(e) = std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir) {
+            eprintln!("Ratchet-Pawl unable to create data directory {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+    let path = dir.join(THE_LOCK_FILE);
+    let mut lock = match std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).mode(0o600).open(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unable to open lock file {}: {}", path.display(), e);
+            std::process::exit(1);
+        },
     };
+    if let Err(e) = rtp_flock(&lock, &path) {
+        eprintln!("Ratchet-Pawl data directory {} is in use by another pawl ({}), refusing to start.", dir.display(), e);
+        std::process::exit(1);
+    }
+    let _ = lock.set_len(0);
+    let _ = write!(lock, "{}", std::process::id());
+    let _ = RATCHET_DATA_LOCK.set(lock);
+}
+
+/// Takes the exclusive lock on an open lock file, or says which pid has it.
+fn rtp_flock(lock: &std::fs::File, path: &std::path::Path) -> Result<(), String> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let holder = std::fs::read_to_string(path).unwrap_or_default();
+        return Err(format!("pid {}", holder.trim()));
+    }
+    Ok(())
 }
 
 // XXX: this has to be less than i64::MAX.
@@ -47,260 +130,1440 @@ static GUTTER: std::sync::LazyLock<Arc<rocket::tokio::sync::RwLock<String>>> = s
 /// Each table is associated to the same struct type (i.e., the value is always the same)
 /// This enables serde to do its thing, reliably.
 /// 
-/// TODO:
-/// - Want to squash occasionally
-/// - Batching transactions would be more performant, but would likely decouple
-///   the UI from success/failure feedback. Possibly something aggressive like
-///   a channel that solves both neatly, tunable for disk i/o.
-/// - Suspect running Database::create repeatedly probably is also a perf impact
+/// Writes and removes go through the persistence thread, which groups them
+/// into as few transactions as it can, see rtp_persist_loop. At runtime
+/// rows are read through RATCHET_STORE with scan; the rest is for work on
+/// the redb file itself.
 /// 
 struct ReadWriteTable<'a, K, V, T>(TableDefinition<'a, K, V>, PhantomData<T>) where
 K: redb::Key + 'static,
 V: redb::Value + 'static,
-T: Serialize + Deserialize<'a> + RatchetKeyed;
-
-impl<'a, T> ReadWriteTable<'a, &'a str, Vec<u8>, T> where T: Serialize + Deserialize<'a> + RatchetKeyed {
-    /// Single write transaction, which due to nature of KVS includes
-    /// both 'add' entry and 'modify' by way of wholesale replacement.
-    pub async fn write(&'static self, item: &T) -> Result<(), redb::Error> {
-        let db = &DB;
-        let key: &[u8; 32]  = *PERM_DB_KEY.clone();
-        let ff = FF1::<Aes256>::new(key, 2).unwrap();
-        let write_txn = db.begin_write()?;
-        {
-            let mut table = write_txn.open_table(self.unwrap())?;
-            let ser = serde_json::to_string(&item).unwrap();
-            let ct = ff.encrypt(&[], &BinaryNumeralString::from_bytes_le(&ser.as_bytes())).unwrap();
-            let my_key = item.into_key();
-            let bytes = ct.to_bytes_le();
-            table.insert(my_key, bytes.clone())?;
-        }
-        write_txn.commit()?;
-        Ok(())
+T: Serialize + DeserializeOwned + RatchetKeyed;
+
+impl<'a, T> ReadWriteTable<'a, &'a str, Vec<u8>, T> where T: Serialize + DeserializeOwned + RatchetKeyed {
+    /// Which due to nature of KVS includes both 'add' entry and 'modify' by
+    /// way of wholesale replacement.
+    /// 
+    /// Rows are always written in the current envelope format, so legacy
+    /// rows are upgraded the next time they're saved.
+    pub async fn write(&'static self, item: &T, actor: &str) -> Result<(), RatchetStoreError> {
+        self.queue_write(item, actor).done().await
     }
 
-    /// Single remove transaction.
-    pub async fn rm(&'static self, item: &T) -> Result<(), redb::Error> {
-        let db = &DB;
-        let write_txn = db.begin_write()?;
-        {
-            let mut table = write_txn.open_table(self.unwrap())?;
-            let my_key = item.into_key();
-            
-            table.remove(my_key)?;
+    /// Hands the write to the persistence thread, so the caller can let go
+    /// of its map before waiting on the disk; writes land in queue order.
+    pub fn queue_write(&'static self, item: &T, actor: &str) -> RatchetPending {
+        match self.put(item, actor) {
+            Ok(change) => rtp_persist(change),
+            Err(e) => RatchetPending::failed(e),
         }
-        write_txn.commit()?;
-        Ok(())
-    } 
+    }
+
+    pub fn queue_rm(&'static self, item: &T, actor: &str) -> RatchetPending {
+        rtp_persist(self.remove(item.into_key(), actor))
+    }
+
+    pub fn put(&'static self, item: &T, actor: &str) -> Result<RatchetChange, RatchetStoreError> {
+        let ser = serde_json::to_vec(&item).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        Ok(RatchetChange { table: self.name(), record_key: item.into_key().to_string(), op: RatchetPersistOp::Put(ser), actor: actor.to_string() })
+    }
+
+    pub fn remove(&'static self, record_key: &str, actor: &str) -> RatchetChange {
+        RatchetChange { table: self.name(), record_key: record_key.to_string(), op: RatchetPersistOp::Remove, actor: actor.to_string() }
+    }
+
+    /// Every row in the table, in key order, deserialized. Rows that don't
+    /// open or deserialize as `R` are set aside for quarantine, instead of
+    /// failing the whole table.
+    pub fn scan<R: DeserializeOwned>(&'static self, txn: &mut RatchetEncryptedTxn<'_, impl RatchetBackendRead + ?Sized>) -> Result<RatchetSortedRows<R>, RatchetStoreError> {
+        Ok(self.sort_rows(txn.scan(self.name())?))
+    }
+
+    /// Like scan, straight off a redb file.
+    pub fn read_sorted<R: DeserializeOwned>(&'static self, read_txn: &ReadTransaction, key: &[u8; 32]) -> Result<RatchetSortedRows<R>, RatchetStoreError> {
+        let table = read_txn.open_table(self.unwrap())?;
+        let mut rows = vec![];
+        for tup in table.iter()? {
+            let (k, v) = tup?;
+            rows.push(RatchetEncryptedRow::open(key, self.name(), k.value().to_string(), v.value()));
+        }
+        Ok(self.sort_rows(rows))
+    }
+
+    fn sort_rows<R: DeserializeOwned>(&'static self, rows: Vec<RatchetEncryptedRow>) -> RatchetSortedRows<R> {
+        let mut good = vec![];
+        let mut bad = vec![];
+        for row in rows {
+            let item = row.opened.and_then(|(record_key, pt)| {
+                serde_json::from_slice::<R>(&pt).map(|item| (record_key, item))
+                                                .map_err(|e| RatchetStoreError::Format(e.to_string()))
+            });
+            match item {
+                Ok(item) => good.push(item),
+                Err(e) => bad.push(RatchetQuarantined {
+                    table: self.name().to_string(),
+                    // its own key stays sealed, quarantine isn't
+                    record_key: row.stored_key,
+                    reason: format!("{:?}", e),
+                    quarantined_at: rtp_unix_now(),
+                    stored: row.stored,
+                }),
+            }
+        }
+        (good, bad)
+    }
+
+    /// Moves a row whose own key isn't the one it says it belongs under,
+    /// inside the caller's transaction. False, and nothing moved, if that
+    /// key is already taken.
+    pub fn rekey(&'static self, write_txn: &WriteTransaction, key: &[u8; 32], record_key: &str, item: &T) -> Result<bool, RatchetStoreError> {
+        let mut table = write_txn.open_table(self.unwrap())?;
+        let ser = serde_json::to_vec(item).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        let (index_key, bytes) = rtp_seal_indexed_record(key, self.name(), item.into_key(), &ser)?;
+        if table.get(index_key.as_str())?.is_some() {
+            return Ok(false);
+        }
+        table.insert(index_key.as_str(), bytes)?;
+        table.remove(rtp_index_key(key, self.name(), record_key)?.as_str())?;
+        Ok(true)
+    }
+
+    /// Rewrites every row from an older stored shape to a newer one, inside
+    /// the caller's transaction, see RATCHET_MIGRATIONS. Rows come out
+    /// under their index keys, whatever they were stored under before.
+    pub fn migrate<Old, New>(&'static self, write_txn: &WriteTransaction, key: &[u8; 32], step: impl Fn(Old) -> New) -> Result<usize, RatchetStoreError>
+    where Old: DeserializeOwned, New: Serialize {
+        self.try_migrate(write_txn, key, |old| Ok(step(old)))
+    }
+
+    /// Like migrate, for steps that can fail; the first failure fails it all.
+    pub fn try_migrate<Old, New>(&'static self, write_txn: &WriteTransaction, key: &[u8; 32], step: impl Fn(Old) -> Result<New, RatchetStoreError>) -> Result<usize, RatchetStoreError>
+    where Old: DeserializeOwned, New: Serialize {
+        self.rewrite(write_txn, key, key, |record_key, pt| {
+            let old: Old = serde_json::from_slice(pt).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+            Ok((record_key.to_string(), serde_json::to_vec(&step(old)?).map_err(|e| RatchetStoreError::Format(e.to_string()))?))
+        })
+    }
+
+    /// Like migrate, for when the new shape has a different key; rows land
+    /// under whatever key the new item has.
+    pub fn migrate_keyed<Old, New>(&'static self, write_txn: &WriteTransaction, key: &[u8; 32], step: impl Fn(Old) -> New) -> Result<usize, RatchetStoreError>
+    where Old: DeserializeOwned, New: Serialize + RatchetKeyed {
+        self.rewrite(write_txn, key, key, |_, pt| {
+            let old: Old = serde_json::from_slice(pt).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+            let new = step(old);
+            Ok((new.into_key().to_string(), serde_json::to_vec(&new).map_err(|e| RatchetStoreError::Format(e.to_string()))?))
+        })
+    }
+
+    /// Opens every row under `old`, and stores what `f` makes of its own key
+    /// and record under `new`, at its index key.
+    fn rewrite(&'static self, write_txn: &WriteTransaction, old: &[u8; 32], new: &[u8; 32], f: impl Fn(&str, &[u8]) -> Result<(String, Vec<u8>), RatchetStoreError>) -> Result<usize, RatchetStoreError> {
+        let mut table = write_txn.open_table(self.unwrap())?;
+        let mut rows = vec![];
+        for tup in table.iter()? {
+            let (k, v) = tup?;
+            rows.push((k.value().to_string(), v.value()));
+        }
+        let mut resealed = vec![];
+        for (stored_key, stored) in rows.iter() {
+            let (record_key, pt) = rtp_open_indexed_record(old, self.name(), stored_key, stored)?;
+            let (record_key, pt) = f(&record_key, &pt)?;
+            resealed.push(rtp_seal_indexed_record(new, self.name(), &record_key, &pt)?);
+        }
+        // all out before any go back in, an index key could be someone's old key
+        for (stored_key, _) in rows.iter() {
+            table.remove(stored_key.as_str())?;
+        }
+        for (index_key, bytes) in resealed {
+            table.insert(index_key.as_str(), bytes)?;
+        }
+        Ok(rows.len())
+    }
+
+    /// Number of rows, a table that was never created has none.
+    pub fn count(&'static self, read_txn: &ReadTransaction) -> Result<u64, RatchetStoreError> {
+        match read_txn.open_table(self.unwrap()) {
+            Ok(table) => Ok(table.len()?),
+            Err(redb::TableError::TableDoesNotExist(_)) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Opens every row under `old` and seals it again under `new`, inside the
+    /// caller's transaction; index keys come from the key too, so every row
+    /// moves. Rows must still parse as JSON, which is the only way to notice
+    /// a wrong `old` key on legacy FF1 rows; not as `T`, since the database
+    /// may not be migrated yet.
+    pub fn reseal(&'static self, write_txn: &WriteTransaction, old: &[u8; 32], new: &[u8; 32]) -> Result<usize, RatchetStoreError> {
+        self.rewrite(write_txn, old, new, |record_key, pt| {
+            serde_json::from_slice::<serde_json::Value>(pt).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+            Ok((record_key.to_string(), pt.to_vec()))
+        })
+    }
 
     /// TODO: Ideally we don't have to repetedly clone this, not sure
     /// what that's doing to the vtables.
     pub fn unwrap(&self) -> TableDefinition<'static, &str, Vec<u8>> {
         self.0.to_owned()
     }
+
+    pub fn name(&'static self) -> &'static str {
+        self.0.name()
+    }
 }
 
-trait RatchetKeyed {
-    fn into_key<'k>(&self) -> &str;
+enum RatchetPersistOp {
+    /// The serialized row, it's sealed once the transaction is open.
+    Put(Vec<u8>),
+    Remove,
+    /// Done once everything queued before it is.
+    Barrier,
+    /// An audit entry that isn't a change, see RATCHET_AUDIT_TABLE; the
+    /// record key is its object.
+    Audit { event: &'static str, source_ip: Option<String>, detail: String },
+    /// A restore's API key secret, put in use alongside its keys so they
+    /// can't land hashed under the wrong one; not journaled, it's a secret.
+    ApiKeySecret([u8; 32]),
 }
 
-static mut DB_KEY: [u8; 32] = [0; 32];
+/// One write or remove, and who asked for it, for the journal.
+struct RatchetChange {
+    table: &'static str,
+    record_key: String,
+    op: RatchetPersistOp,
+    actor: String,
+}
 
-// TODO: What happens if we modify the user format by adding a field do we have to make it option type?
-const RATCHET_USERS_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetUserEntry> = 
-    ReadWriteTable::<&str, Vec<u8>, RatchetUserEntry>(TableDefinition::new("ratchet_users"), PhantomData);
-const RATCHET_DEVS_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetDevEntry> = 
-    ReadWriteTable::<&str, Vec<u8>, RatchetDevEntry>(TableDefinition::new("ratchet_devs"), PhantomData);
-const RATCHET_USER_CMD_POLICY_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetUserCmdPolicy> = 
-    ReadWriteTable::<&str, Vec<u8>, RatchetUserCmdPolicy>(TableDefinition::new("ratchet_user_cmd_policy"), PhantomData);
-const RATCHET_APIKEY_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetApiKey> =
-    ReadWriteTable::<&str, Vec<u8>, RatchetApiKey>(TableDefinition::new("ratchet_api_keys"), PhantomData);
+struct RatchetPersist {
+    change: RatchetChange,
+    done: Sender<Result<(), RatchetStoreError>>,
+}
+
+/// A queued write or remove, await done() for how it went.
+struct RatchetPending(oneshot::Receiver<Result<(), RatchetStoreError>>);
+
+impl RatchetPending {
+    fn failed(e: RatchetStoreError) -> RatchetPending {
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(Err(e));
+        RatchetPending(rx)
+    }
+
+    async fn done(self) -> Result<(), RatchetStoreError> {
+        match self.0.await {
+            Ok(r) => r,
+            Err(_) => Err(RatchetStoreError::Format(String::from("persistence thread stopped"))),
+        }
+    }
+}
 
 lazy_static! {
-    static ref RATCHET_APIKEYS: Mutex<HashMap<String, RatchetApiKey>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
-    static ref RATCHET_USERS: Mutex<HashMap<String, RatchetUserEntry>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
-    static ref RATCHET_DEVICES: Mutex<HashMap<String, RatchetDevEntry>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
-    static ref RATCHET_USER_CMD_POLICY: Mutex<RatchetUserCmdPolicy> = {
-        let s = String::new();
-        Mutex::new(RatchetUserCmdPolicy(s))
-    };
-    // TODO: It's pretty risky to leave these as separate mutex
-    static ref RATCHET_COOKIES: Mutex<HashMap<String, (Instant, String)>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
-    static ref RATCHET_USER_COOKIES: Mutex<HashMap<String, HashSet<String>>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
-    static ref RATCHET_POLL_PINS: Mutex<Vec<Sender<bool>>> = {
-        let p = Vec::new();
-        Mutex::new(p)
+    static ref RATCHET_PERSIST: std::sync::mpsc::Sender<RatchetPersist> = {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || rtp_persist_loop(rx));
+        tx
     };
-    static ref PERM_DB_KEY: Arc<&'static [u8; 32]> = {
-        // SAFETY: DB_KEY must not be mutated after init, see rtp_take_key
-        // if anyone else touches DB_KEY and not PERM_DB_KEY, slap them.
-        unsafe {
-            #[allow(static_mut_refs)]
-            Arc::new(&DB_KEY) 
+}
+
+fn rtp_persist(change: RatchetChange) -> RatchetPending {
+    let (tx, rx) = oneshot::channel();
+    let _ = RATCHET_PERSIST.send(RatchetPersist { change, done: tx });
+    RatchetPending(rx)
+}
+
+/// Resolves once everything queued so far is on disk.
+fn rtp_persist_flush() -> RatchetPending {
+    rtp_persist(RatchetChange {
+        table: "",
+        record_key: String::new(),
+        op: RatchetPersistOp::Barrier,
+        actor: String::new(),
+    })
+}
+
+/// Takes whatever is queued, waiting up to RATCHET_PAWL_WRITE_LATENCY_MS
+/// (default 0, don't wait) for more, and up to RATCHET_PAWL_WRITE_BATCH
+/// (default 256) at once, and commits them together. If that fails, each
+/// is tried on its own, so every caller hears about its own write.
+fn rtp_persist_loop(rx: std::sync::mpsc::Receiver<RatchetPersist>) {
+    let latency = std::time::Duration::from_millis(rtp_env_key("RATCHET_PAWL_WRITE_LATENCY_MS").parse::<u64>().unwrap_or(0));
+    let batch_size = rtp_env_key("RATCHET_PAWL_WRITE_BATCH").parse::<usize>().unwrap_or(256).max(1);
+    while let Ok(first) = rx.recv() {
+        let deadline = Instant::now() + latency;
+        let mut batch = vec![first];
+        while batch.len() < batch_size {
+            let next = match deadline.checked_duration_since(Instant::now()) {
+                Some(wait) if !wait.is_zero() => rx.recv_timeout(wait).ok(),
+                _ => rx.try_recv().ok(),
+            };
+            match next {
+                Some(p) => batch.push(p),
+                None => break,
+            }
         }
-    };
-    // Recommend polling upon attach to subscribers.
-    static ref LONG_POLL_EPOCH: AtomicU64 = AtomicU64::new(1);
+
+        let (changes, dones): (Vec<RatchetChange>, Vec<_>) = batch.into_iter().map(|p| (p.change, p.done)).unzip();
+        if rtp_apply_changes(&changes).is_ok() {
+            dones.into_iter().for_each(|done| { let _ = done.send(Ok(())); });
+        } else {
+            for (change, done) in changes.iter().zip(dones) {
+                let _ = done.send(rtp_apply_changes(std::slice::from_ref(change)));
+            }
+        }
+    }
 }
 
-/// SAFETY: This must only be called once prior to operation of any 
-/// accessors or users of the Arc<PERM_DB_KEY>.
-/// 
-/// 😭 i dont want to make good abstractions right now!!
-/// I think this is actually a 'std::LazyLock' maybe use it 
-/// 
-pub unsafe fn rtp_take_key (key: &String) {
-    // SAFETY: Nothing else writes the DB_KEY.
-    unsafe {
-        #[allow(static_mut_refs)]
-        DB_KEY.iter_mut()
-            .enumerate()
-            .for_each(|(i,k)| *k = *key.as_bytes()
-                                                                .get(i)
-                                                                .unwrap_or(&0));
+/// Applies changes in one transaction, journaling and auditing each, see
+/// RATCHET_JOURNAL_TABLE and RATCHET_AUDIT_TABLE. Once it's committed, the
+/// audit entries are forwarded, see rtp_start_events.
+fn rtp_apply_changes(changes: &[RatchetChange]) -> Result<(), RatchetStoreError> {
+    let mut txn = RATCHET_STORE.begin()?;
+    let mut audited = vec![];
+    let mut secret = None;
+    for c in changes.iter() {
+        match &c.op {
+            RatchetPersistOp::Barrier => continue,
+            RatchetPersistOp::ApiKeySecret(s) => {
+                txn.put(RATCHET_META_TABLE.name(), RATCHET_META_API_KEY_HMAC, s)?;
+                secret = Some(*s);
+                continue;
+            },
+            RatchetPersistOp::Audit { event, source_ip, detail } => {
+                audited.push(rtp_audit_append(&mut txn, event, &c.actor, &c.record_key, source_ip.clone(), detail.clone())?);
+                continue;
+            },
+            _ => (),
+        }
+        // rows that don't open were quarantined at startup, so this is the rare case
+        let previous = match txn.get(c.table, &c.record_key) {
+            Ok(pt) => pt.and_then(|pt| serde_json::from_slice::<serde_json::Value>(&pt).ok()),
+            Err(RatchetStoreError::Db(e)) => return Err(RatchetStoreError::Db(e)),
+            Err(_) => None,
+        };
+        let new = match &c.op {
+            RatchetPersistOp::Put(ser) => {
+                txn.put(c.table, &c.record_key, ser)?;
+                Some(serde_json::from_slice::<serde_json::Value>(ser).map_err(|e| RatchetStoreError::Format(e.to_string()))?)
+            },
+            RatchetPersistOp::Remove => {
+                txn.delete(c.table, &c.record_key)?;
+                None
+            },
+            RatchetPersistOp::Barrier | RatchetPersistOp::Audit { .. } | RatchetPersistOp::ApiKeySecret(_) => continue,
+        };
+        let entry = rtp_journal(&mut txn, c, previous, new)?;
+        audited.push(rtp_audit_journaled(&mut txn, &entry)?);
+    }
+    rtp_trim_journal(&mut txn)?;
+    txn.commit()?;
+    if let Some(secret) = secret {
+        *RATCHET_API_KEY_HMAC.write().unwrap_or_else(|e| e.into_inner()) = secret;
     }
+    rtp_forward_events(audited);
+    Ok(())
 }
 
-/// Backend data for users, used for authentication
-/// 
-/// Should not be sent over any unsecure channel, since
-/// hashes are subject to attacks.
-/// 
-#[derive(Clone, FromForm, Debug, Serialize, Deserialize)]
-struct RatchetUserEntry {
-    username: String,
-    passhash: String,
+/// Anything that can go wrong between the in-memory maps and the disk.
+enum RatchetStoreError {
+    Db(Box<redb::Error>),
+    /// A record couldn't be sealed or opened: wrong key, or it was tampered with.
+    Crypto,
+    /// A record opened fine, but isn't shaped like what we expected.
+    Format(String),
+    /// The key provider couldn't produce the database key.
+    KeyProvider(String),
+    /// The key opens nothing in this database, as opposed to a few bad rows.
+    WrongKey,
 }
 
-impl RatchetKeyed for RatchetUserEntry{
-    fn into_key(&self) -> &str {
-        &self.username.as_str()
+impl std::fmt::Debug for RatchetStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RatchetStoreError::Db(e) => write!(f, "Database error, {}", e),
+            RatchetStoreError::Crypto => write!(f, "Record failed authentication, wrong key or tampering"),
+            RatchetStoreError::Format(e) => write!(f, "Record format error, {}", e),
+            RatchetStoreError::KeyProvider(e) => write!(f, "Key provider error, {}", e),
+            RatchetStoreError::WrongKey => write!(f, "Wrong key, the masking key does not open this database"),
+        }
     }
 }
 
-/// For long-pollers, each API change results in sending a notification out.
-/// This notificaation is associated with a serial number for the total number of
-/// changes seen so far.
-/// 
-/// Then on re-attach, a poller who may have missed multiple updates is 
-/// immediately notified again, rather than leaving open the possibility of 
-/// missed updates, and a subscriber in a stale state.
-/// 
-/// It's also really really cheap.
-/// 
-/// TODO: Frontend pollers for AJAX / real-time updates.
-/// 
-async fn rtp_notify_pollers() {
-    LONG_POLL_EPOCH.fetch_add(1, Ordering::Relaxed);
-    let mut pins = RATCHET_POLL_PINS.lock().await;
-    while let Some(pin) = pins.pop() {
-        let _ = pin.send(true); // it's pub-sub, so it will leak; 
-                        // we don't know the subscriber is gone until after they fail to reconnect.
-    }
+macro_rules! rtp_store_error_from {
+    ($($t:ty),*) => {$(
+        impl From<$t> for RatchetStoreError {
+            fn from(e: $t) -> Self { RatchetStoreError::Db(Box::new(e.into())) }
+        }
+    )*};
 }
+rtp_store_error_from!(redb::Error, redb::TransactionError, redb::TableError, redb::StorageError, redb::CommitError, redb::CompactionError);
 
-/// Frontend API for removing a user by username.
-/// 
-/// TODO: Don't remove the bottom dollar
+/// Every sealed record starts with this, then a version byte.
+/// Rows without it predate the envelope, and are FF1-masked.
+const RATCHET_ENVELOPE_MAGIC: &[u8; 3] = b"RPW";
+const RATCHET_ENVELOPE_V1: u8 = 1;
+/// Like V1, but the row's own key is sealed in with it, and the row is
+/// stored under its index key instead, see rtp_index_key.
+const RATCHET_ENVELOPE_V2: u8 = 2;
+const RATCHET_ENVELOPE_NONCE_LEN: usize = 12;
+
+/// The table name and redb key are bound into each record, so a
+/// sealed row moved anywhere else in the database won't open.
+fn rtp_record_aad(table: &str, record_key: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(4 + table.len() + record_key.len());
+    aad.extend_from_slice(&(table.len() as u32).to_be_bytes());
+    aad.extend_from_slice(table.as_bytes());
+    aad.extend_from_slice(record_key.as_bytes());
+    aad
+}
+
+/// Seals a serialized record with AES-256-GCM under a fresh random nonce.
 /// 
-#[post("/rmuser", format = "multipart/form-data", data = "<username>")]
-async fn rm_user(_admin: RatchetUser, username: Form<String>) -> status::Custom<&'static str> {
-    let mut users = RATCHET_USERS.lock().await;
-    // and deauthorize from web shell
-    let mut cookie_store = RATCHET_COOKIES.lock().await;
-    let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
-    match users.remove(&*username) {
-        Some(user) => {
-            RATCHET_USERS_TABLE.rm(&user).await.expect("Database error");
-            match user_cookies.remove(&user.username) {
-                Some(active_cookies) => {
-                    active_cookies.into_iter().for_each(|each_cookie| {cookie_store.remove(&each_cookie);});
+/// Layout is `RPW | version | nonce | ciphertext+tag`
+fn rtp_seal_record(key: &[u8; 32], table: &str, record_key: &str, pt: &[u8]) -> Result<Vec<u8>, RatchetStoreError> {
+    rtp_seal_envelope(key, RATCHET_ENVELOPE_V1, table, record_key, pt)
+}
+
+/// Seals a record of the five tables, which is stored under its index key
+/// rather than its own. The plaintext is `key length (u32 BE) | key | record`.
+/// Returns the index key along with the sealed row.
+fn rtp_seal_indexed_record(key: &[u8; 32], table: &str, record_key: &str, pt: &[u8]) -> Result<(String, Vec<u8>), RatchetStoreError> {
+    let index_key = rtp_index_key(key, table, record_key)?;
+    let mut framed = Vec::with_capacity(4 + record_key.len() + pt.len());
+    framed.extend_from_slice(&(record_key.len() as u32).to_be_bytes());
+    framed.extend_from_slice(record_key.as_bytes());
+    framed.extend_from_slice(pt);
+    let sealed = rtp_seal_envelope(key, RATCHET_ENVELOPE_V2, table, &index_key, &framed)?;
+    Ok((index_key, sealed))
+}
+
+/// What a row of the five tables is stored under: an HMAC of its table and
+/// key, under a subkey of the database key, so that without the key the file
+/// doesn't give away who the users are or which devices there are.
+fn rtp_index_key(key: &[u8; 32], table: &str, record_key: &str) -> Result<String, RatchetStoreError> {
+    let mut subkey = [0u8; 32];
+    // can't fail, 32 bytes is well under what HKDF-SHA256 can expand to
+    let _ = Hkdf::<Sha256>::new(None, key).expand(b"ratchet-pawl index key", &mut subkey);
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&subkey).map_err(|_| RatchetStoreError::Crypto)?;
+    mac.update(&rtp_record_aad(table, record_key));
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+fn rtp_seal_envelope(key: &[u8; 32], version: u8, table: &str, record_key: &str, pt: &[u8]) -> Result<Vec<u8>, RatchetStoreError> {
+    let cipher = Aes256Gcm::new(key.into());
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let aad = rtp_record_aad(table, record_key);
+    let ct = cipher.encrypt(&nonce, Payload { msg: pt, aad: &aad })
+                   .map_err(|_| RatchetStoreError::Crypto)?;
+
+    let mut out = Vec::with_capacity(RATCHET_ENVELOPE_MAGIC.len() + 1 + nonce.len() + ct.len());
+    out.extend_from_slice(RATCHET_ENVELOPE_MAGIC);
+    out.push(version);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ct);
+    Ok(out)
+}
+
+/// Opens a stored record, whichever format it was written in.
+fn rtp_open_record(key: &[u8; 32], table: &str, record_key: &str, stored: &[u8]) -> Result<Vec<u8>, RatchetStoreError> {
+    rtp_open_indexed_record(key, table, record_key, stored).map(|(_, pt)| pt)
+}
+
+/// Opens a stored record, whichever format it was written in, along with
+/// its own key: the one sealed in with it for V2, otherwise the one it's
+/// stored under. Once the database is past RATCHET_META_ENVELOPE_ONLY the
+/// five tables only hold V2, and nothing is FF1.
+fn rtp_open_indexed_record(key: &[u8; 32], table: &str, stored_key: &str, stored: &[u8]) -> Result<(String, Vec<u8>), RatchetStoreError> {
+    let legacy = RATCHET_LEGACY_ROWS.load(Ordering::SeqCst);
+    match stored.strip_prefix(RATCHET_ENVELOPE_MAGIC) {
+        Some([RATCHET_ENVELOPE_V1, ..]) if !legacy && rtp_table_by_name(table).is_some() => Err(RatchetStoreError::Crypto),
+        Some([version @ (RATCHET_ENVELOPE_V1 | RATCHET_ENVELOPE_V2), rest @ ..]) if rest.len() >= RATCHET_ENVELOPE_NONCE_LEN => {
+            let (nonce, ct) = rest.split_at(RATCHET_ENVELOPE_NONCE_LEN);
+            let cipher = Aes256Gcm::new(key.into());
+            let aad = rtp_record_aad(table, stored_key);
+            let pt = cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ct, aad: &aad })
+                           .map_err(|_| RatchetStoreError::Crypto)?;
+            if *version == RATCHET_ENVELOPE_V1 {
+                return Ok((stored_key.to_string(), pt));
+            }
+            match pt.split_first_chunk::<4>() {
+                Some((len, rest)) if rest.len() >= u32::from_be_bytes(*len) as usize => {
+                    let (own_key, record) = rest.split_at(u32::from_be_bytes(*len) as usize);
+                    let own_key = String::from_utf8(own_key.to_vec()).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+                    Ok((own_key, record.to_vec()))
                 },
-                None => (),
+                _ => Err(RatchetStoreError::Format(String::from("record key is cut short"))),
             }
-            rocket::tokio::spawn(rtp_notify_pollers());
-            status::Custom(Status::Ok, "")
         },
-        None => status::Custom(Status::Gone, ""),
+        Some([RATCHET_ENVELOPE_V1 | RATCHET_ENVELOPE_V2, ..]) => Err(RatchetStoreError::Crypto),
+        _ if !legacy => Err(RatchetStoreError::Crypto),
+        _ => {
+            // Legacy: FF1 masking under an empty tweak, unauthenticated.
+            let ff = FF1::<Aes256>::new(key, 2).map_err(|_| RatchetStoreError::Crypto)?;
+            ff.decrypt(&[], &BinaryNumeralString::from_bytes_le(stored))
+              .map(|pt| (stored_key.to_string(), pt.to_bytes_le()))
+              .map_err(|_| RatchetStoreError::Crypto)
+        },
     }
 }
 
-/// Frontend API for adding a user.
-/// 
-/// TODO: Input validation, password policy
+/// Where the records actually live, as opaque bytes by table and record key.
+/// pawl runs on RatchetRedbBackend, or with RATCHET_PAWL_STORAGE=memory on
+/// RatchetMemBackend, which starts empty and is gone on exit. Either one is
+/// wrapped in RatchetEncrypted, which does the encryption.
 /// 
-#[post("/adduser", format = "multipart/form-data", data = "<newuser>")]
-async fn add_user(_admin: RatchetUser, newuser: Form<RatchetUserEntry>) -> status::Custom<&'static str> {
-    let mut users = RATCHET_USERS.lock().await;
-    if !users.contains_key(&newuser.username) {
-        if let Ok(h) = bcrypt::hash(&newuser.passhash) {
-            let new_entry = RatchetUserEntry {
-                username: newuser.username.clone(),
-                passhash: h.clone(),
-            };
+/// Key rotation, migrations, compaction and `check` are about the redb file,
+/// and stay on rtp_db().
+trait RatchetBackend: Send + Sync {
+    /// Nobody else sees anything until commit, and only one is open at a time.
+    fn begin(&self) -> Result<Box<dyn RatchetBackendTxn + '_>, RatchetStoreError>;
+    /// What was last committed, for reads that change nothing.
+    fn begin_read(&self) -> Result<Box<dyn RatchetBackendRead + '_>, RatchetStoreError>;
+}
 
-            RATCHET_USERS_TABLE.write(&new_entry).await.expect("Database error");
+trait RatchetBackendRead {
+    fn get(&mut self, table: &str, record_key: &str) -> Result<Option<Vec<u8>>, RatchetStoreError>;
+    /// Every row, in key order; a table that was never written is empty.
+    fn scan(&mut self, table: &str) -> Result<Vec<(String, Vec<u8>)>, RatchetStoreError>;
+    /// Logs, the journal and the audit log, are numbered instead of keyed,
+    /// see RATCHET_JOURNAL_TABLE. 0 when it's empty.
+    fn log_last(&mut self, log: &str) -> Result<u64, RatchetStoreError>;
+    /// Up to `limit` entries after `after`, in order.
+    fn log_scan(&mut self, log: &str, after: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>, RatchetStoreError>;
+}
 
-            users.insert(
-                newuser.username.clone(), new_entry
-            );
-            rocket::tokio::spawn(rtp_notify_pollers());
-            status::Custom(Status::Ok, "")
-        } else {
-            // TODO: This doesn't exactly mean this anymore
-            status::Custom(Status::Conflict, "")
-        }
-    } else {
-        status::Custom(Status::Conflict, "")
+trait RatchetBackendTxn: RatchetBackendRead {
+    fn put(&mut self, table: &str, record_key: &str, value: Vec<u8>) -> Result<(), RatchetStoreError>;
+    /// True if there was something to delete.
+    fn delete(&mut self, table: &str, record_key: &str) -> Result<bool, RatchetStoreError>;
+    fn log_put(&mut self, log: &str, seq: u64, value: Vec<u8>) -> Result<(), RatchetStoreError>;
+    /// Drops the entries up to and including `through`, see rtp_trim_journal.
+    fn log_trim(&mut self, log: &str, through: u64) -> Result<(), RatchetStoreError>;
+    fn commit(self: Box<Self>) -> Result<(), RatchetStoreError>;
+}
+
+struct RatchetRedbBackend;
+
+impl RatchetBackend for RatchetRedbBackend {
+    fn begin(&self) -> Result<Box<dyn RatchetBackendTxn + '_>, RatchetStoreError> {
+        RatchetRedbTxn::begin(&rtp_db())
+    }
+
+    fn begin_read(&self) -> Result<Box<dyn RatchetBackendRead + '_>, RatchetStoreError> {
+        RatchetRedbRead::begin(&rtp_db())
     }
 }
 
-/// Frontend API for editing a user.
-/// 
-/// TODO: Input validation, password policy
-/// 
-#[post("/edituser", format = "multipart/form-data", data = "<edited>")]
-async fn edit_user(_admin: RatchetUser, edited: Form<RatchetUserEntry>) -> status::Custom<&'static str> {
-    let mut users = RATCHET_USERS.lock().await;
-    // and deauthorize from web shell
-    let mut cookie_store = RATCHET_COOKIES.lock().await;
-    let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
-    if !users.contains_key(&edited.username) {
-        status::Custom(Status::Gone, "")
-    } else {
-        let mut user_update = edited.to_owned();
-        if let Ok(h) = bcrypt::hash(user_update.passhash)  {
-            user_update.passhash = h;
-            RATCHET_USERS_TABLE.write(&user_update).await.expect("Database error");
-            users.insert(user_update.username.clone(), user_update.clone());
+/// Also how the startup sweep and `check`, which have a Database of their
+/// own, get at quarantine. Dropped without commit, nothing is written.
+struct RatchetRedbTxn(WriteTransaction);
 
-            match user_cookies.remove(&user_update.username) {
-                Some(active_cookies) => {
-                    active_cookies.into_iter().for_each(|each_cookie| {cookie_store.remove(&each_cookie);});
-                },
-                None => (),
-            }
-            rocket::tokio::spawn(rtp_notify_pollers());
-            status::Custom(Status::Ok, "")
-        } else {
-            // TODO: This doesn't exactly mean this anymore
-            status::Custom(Status::Gone, "")
-        }
+impl RatchetRedbTxn {
+    fn begin(db: &Database) -> Result<Box<dyn RatchetBackendTxn>, RatchetStoreError> {
+        Ok(Box::new(RatchetRedbTxn(db.begin_write()?)))
+    }
+}
+
+impl RatchetBackendRead for RatchetRedbTxn {
+    fn get(&mut self, table: &str, record_key: &str) -> Result<Option<Vec<u8>>, RatchetStoreError> {
+        let table = self.0.open_table(TableDefinition::<&str, Vec<u8>>::new(table))?;
+        let value = table.get(record_key)?.map(|v| v.value());
+        Ok(value)
+    }
+
+    fn scan(&mut self, table: &str) -> Result<Vec<(String, Vec<u8>)>, RatchetStoreError> {
+        let table = self.0.open_table(TableDefinition::<&str, Vec<u8>>::new(table))?;
+        rtp_redb_rows(&table)
+    }
+
+    fn log_last(&mut self, log: &str) -> Result<u64, RatchetStoreError> {
+        let log = self.0.open_table(TableDefinition::<u64, Vec<u8>>::new(log))?;
+        let last = log.last()?.map(|(k, _)| k.value()).unwrap_or(0);
+        Ok(last)
+    }
+
+    fn log_scan(&mut self, log: &str, after: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>, RatchetStoreError> {
+        let log = self.0.open_table(TableDefinition::<u64, Vec<u8>>::new(log))?;
+        rtp_redb_log_rows(&log, after, limit)
+    }
+}
+
+impl RatchetBackendTxn for RatchetRedbTxn {
+    fn put(&mut self, table: &str, record_key: &str, value: Vec<u8>) -> Result<(), RatchetStoreError> {
+        let mut table = self.0.open_table(TableDefinition::<&str, Vec<u8>>::new(table))?;
+        table.insert(record_key, value)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, table: &str, record_key: &str) -> Result<bool, RatchetStoreError> {
+        let mut table = self.0.open_table(TableDefinition::<&str, Vec<u8>>::new(table))?;
+        let removed = table.remove(record_key)?.is_some();
+        Ok(removed)
+    }
+
+    fn log_put(&mut self, log: &str, seq: u64, value: Vec<u8>) -> Result<(), RatchetStoreError> {
+        let mut log = self.0.open_table(TableDefinition::<u64, Vec<u8>>::new(log))?;
+        log.insert(seq, value)?;
+        Ok(())
+    }
+
+    fn log_trim(&mut self, log: &str, through: u64) -> Result<(), RatchetStoreError> {
+        let mut log = self.0.open_table(TableDefinition::<u64, Vec<u8>>::new(log))?;
+        log.retain_in(..=through, |_, _| false)?;
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<(), RatchetStoreError> {
+        self.0.commit()?;
+        Ok(())
+    }
+}
+
+/// A redb read transaction: it sees the last commit, and doesn't wait on
+/// the writer. Tables that were never written read as empty.
+struct RatchetRedbRead(ReadTransaction);
+
+impl RatchetRedbRead {
+    fn begin(db: &Database) -> Result<Box<dyn RatchetBackendRead>, RatchetStoreError> {
+        Ok(Box::new(RatchetRedbRead(db.begin_read()?)))
+    }
+
+    fn open<K: redb::Key + 'static>(&self, table: &str) -> Result<Option<redb::ReadOnlyTable<K, Vec<u8>>>, RatchetStoreError> {
+        match self.0.open_table(TableDefinition::<K, Vec<u8>>::new(table)) {
+            Ok(t) => Ok(Some(t)),
+            Err(redb::TableError::TableDoesNotExist(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl RatchetBackendRead for RatchetRedbRead {
+    fn get(&mut self, table: &str, record_key: &str) -> Result<Option<Vec<u8>>, RatchetStoreError> {
+        match self.open::<&str>(table)? {
+            Some(table) => Ok(table.get(record_key)?.map(|v| v.value())),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&mut self, table: &str) -> Result<Vec<(String, Vec<u8>)>, RatchetStoreError> {
+        match self.open::<&str>(table)? {
+            Some(table) => rtp_redb_rows(&table),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn log_last(&mut self, log: &str) -> Result<u64, RatchetStoreError> {
+        match self.open::<u64>(log)? {
+            Some(log) => Ok(log.last()?.map(|(k, _)| k.value()).unwrap_or(0)),
+            None => Ok(0),
+        }
+    }
+
+    fn log_scan(&mut self, log: &str, after: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>, RatchetStoreError> {
+        match self.open::<u64>(log)? {
+            Some(log) => rtp_redb_log_rows(&log, after, limit),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+fn rtp_redb_rows(table: &impl ReadableTable<&'static str, Vec<u8>>) -> Result<Vec<(String, Vec<u8>)>, RatchetStoreError> {
+    let mut rows = vec![];
+    for tup in table.iter()? {
+        let (k, v) = tup?;
+        rows.push((k.value().to_string(), v.value()));
+    }
+    Ok(rows)
+}
+
+fn rtp_redb_log_rows(log: &impl ReadableTable<u64, Vec<u8>>, after: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>, RatchetStoreError> {
+    let mut rows = vec![];
+    for tup in log.range((after + 1)..)?.take(limit) {
+        let (k, v) = tup?;
+        rows.push((k.value(), v.value()));
+    }
+    Ok(rows)
+}
+
+/// Throwaway state, for trying pawl out or for Rocket's local client.
+/// A transaction changes the tables in place, and puts them back if it's
+/// dropped without commit.
+#[derive(Default)]
+struct RatchetMemBackend(std::sync::Mutex<RatchetMemTables>);
+
+#[derive(Default)]
+struct RatchetMemTables {
+    tables: HashMap<String, BTreeMap<String, Vec<u8>>>,
+    logs: HashMap<String, BTreeMap<u64, Vec<u8>>>,
+}
+
+/// What a row or log entry was before a transaction changed it.
+enum RatchetMemUndo {
+    Row(String, String, Option<Vec<u8>>),
+    Log(String, u64, Option<Vec<u8>>),
+}
+
+struct RatchetMemTxn<'t> {
+    tables: std::sync::MutexGuard<'t, RatchetMemTables>,
+    /// Oldest first, undone newest first.
+    undo: Vec<RatchetMemUndo>,
+}
+
+struct RatchetMemRead<'t>(std::sync::MutexGuard<'t, RatchetMemTables>);
+
+impl RatchetBackend for RatchetMemBackend {
+    fn begin(&self) -> Result<Box<dyn RatchetBackendTxn + '_>, RatchetStoreError> {
+        let tables = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(Box::new(RatchetMemTxn { tables, undo: vec![] }))
+    }
+
+    fn begin_read(&self) -> Result<Box<dyn RatchetBackendRead + '_>, RatchetStoreError> {
+        Ok(Box::new(RatchetMemRead(self.0.lock().unwrap_or_else(|e| e.into_inner()))))
+    }
+}
+
+impl RatchetBackendRead for RatchetMemTables {
+    fn get(&mut self, table: &str, record_key: &str) -> Result<Option<Vec<u8>>, RatchetStoreError> {
+        Ok(self.tables.get(table).and_then(|t| t.get(record_key)).cloned())
+    }
+
+    fn scan(&mut self, table: &str) -> Result<Vec<(String, Vec<u8>)>, RatchetStoreError> {
+        Ok(self.tables.get(table)
+               .map(|t| t.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+               .unwrap_or_default())
+    }
+
+    fn log_last(&mut self, log: &str) -> Result<u64, RatchetStoreError> {
+        Ok(self.logs.get(log).and_then(|l| l.last_key_value()).map(|(k, _)| *k).unwrap_or(0))
+    }
+
+    fn log_scan(&mut self, log: &str, after: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>, RatchetStoreError> {
+        Ok(self.logs.get(log)
+               .map(|l| l.range((after + 1)..).take(limit).map(|(k, v)| (*k, v.clone())).collect())
+               .unwrap_or_default())
+    }
+}
+
+impl RatchetBackendRead for RatchetMemRead<'_> {
+    fn get(&mut self, table: &str, record_key: &str) -> Result<Option<Vec<u8>>, RatchetStoreError> {
+        self.0.get(table, record_key)
+    }
+
+    fn scan(&mut self, table: &str) -> Result<Vec<(String, Vec<u8>)>, RatchetStoreError> {
+        self.0.scan(table)
+    }
+
+    fn log_last(&mut self, log: &str) -> Result<u64, RatchetStoreError> {
+        self.0.log_last(log)
+    }
+
+    fn log_scan(&mut self, log: &str, after: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>, RatchetStoreError> {
+        self.0.log_scan(log, after, limit)
+    }
+}
+
+impl RatchetBackendRead for RatchetMemTxn<'_> {
+    fn get(&mut self, table: &str, record_key: &str) -> Result<Option<Vec<u8>>, RatchetStoreError> {
+        self.tables.get(table, record_key)
+    }
+
+    fn scan(&mut self, table: &str) -> Result<Vec<(String, Vec<u8>)>, RatchetStoreError> {
+        self.tables.scan(table)
+    }
+
+    fn log_last(&mut self, log: &str) -> Result<u64, RatchetStoreError> {
+        self.tables.log_last(log)
+    }
+
+    fn log_scan(&mut self, log: &str, after: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>, RatchetStoreError> {
+        self.tables.log_scan(log, after, limit)
+    }
+}
+
+impl RatchetBackendTxn for RatchetMemTxn<'_> {
+    fn put(&mut self, table: &str, record_key: &str, value: Vec<u8>) -> Result<(), RatchetStoreError> {
+        let was = self.tables.tables.entry(table.to_string()).or_default().insert(record_key.to_string(), value);
+        self.undo.push(RatchetMemUndo::Row(table.to_string(), record_key.to_string(), was));
+        Ok(())
+    }
+
+    fn delete(&mut self, table: &str, record_key: &str) -> Result<bool, RatchetStoreError> {
+        let was = self.tables.tables.get_mut(table).and_then(|t| t.remove(record_key));
+        let removed = was.is_some();
+        self.undo.push(RatchetMemUndo::Row(table.to_string(), record_key.to_string(), was));
+        Ok(removed)
+    }
+
+    fn log_put(&mut self, log: &str, seq: u64, value: Vec<u8>) -> Result<(), RatchetStoreError> {
+        let was = self.tables.logs.entry(log.to_string()).or_default().insert(seq, value);
+        self.undo.push(RatchetMemUndo::Log(log.to_string(), seq, was));
+        Ok(())
+    }
+
+    fn log_trim(&mut self, log: &str, through: u64) -> Result<(), RatchetStoreError> {
+        let l = self.tables.logs.entry(log.to_string()).or_default();
+        let kept = l.split_off(&(through + 1));
+        let trimmed = std::mem::replace(l, kept);
+        self.undo.extend(trimmed.into_iter().map(|(seq, v)| RatchetMemUndo::Log(log.to_string(), seq, Some(v))));
+        Ok(())
+    }
+
+    fn commit(mut self: Box<Self>) -> Result<(), RatchetStoreError> {
+        self.undo.clear();
+        Ok(())
+    }
+}
+
+impl Drop for RatchetMemTxn<'_> {
+    fn drop(&mut self) {
+        while let Some(undo) = self.undo.pop() {
+            match undo {
+                RatchetMemUndo::Row(table, record_key, was) => {
+                    let t = self.tables.tables.entry(table).or_default();
+                    match was {
+                        Some(v) => { t.insert(record_key, v); },
+                        None => { t.remove(&record_key); },
+                    }
+                },
+                RatchetMemUndo::Log(log, seq, was) => {
+                    let l = self.tables.logs.entry(log).or_default();
+                    match was {
+                        Some(v) => { l.insert(seq, v); },
+                        None => { l.remove(&seq); },
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Record encryption over any backend: values are sealed under the live
+/// database key, bound to their table and record key, see rtp_seal_record.
+/// Rows of the five tables are stored under their index keys, see
+/// rtp_seal_indexed_record; meta under its own.
+struct RatchetEncrypted(Box<dyn RatchetBackend>);
+
+/// A sealed transaction. `raw` is for what isn't sealed, e.g. quarantine,
+/// which keeps rows exactly as they were found. Over a read transaction
+/// it's a RatchetEncryptedRead, which only has the reads.
+struct RatchetEncryptedTxn<'t, B: RatchetBackendRead + ?Sized + 't = dyn RatchetBackendTxn + 't> {
+    raw: Box<B>,
+    key: Arc<[u8; 32]>,
+    txn: PhantomData<&'t ()>,
+}
+
+type RatchetEncryptedRead<'t> = RatchetEncryptedTxn<'t, dyn RatchetBackendRead + 't>;
+
+/// A row as stored, and its own key and what it opened to, if it did.
+struct RatchetEncryptedRow {
+    stored_key: String,
+    stored: Vec<u8>,
+    opened: Result<(String, Vec<u8>), RatchetStoreError>,
+}
+
+impl RatchetEncryptedRow {
+    fn open(key: &[u8; 32], table: &str, stored_key: String, stored: Vec<u8>) -> RatchetEncryptedRow {
+        RatchetEncryptedRow {
+            opened: rtp_open_indexed_record(key, table, &stored_key, &stored),
+            stored_key,
+            stored,
+        }
+    }
+}
+
+impl RatchetEncrypted {
+    fn begin(&self) -> Result<RatchetEncryptedTxn<'_>, RatchetStoreError> {
+        let raw = self.0.begin()?;
+        // after begin, so a concurrent rotation can't slip in between
+        Ok(RatchetEncryptedTxn { raw, key: rtp_db_key(), txn: PhantomData })
+    }
+
+    fn begin_read(&self) -> Result<RatchetEncryptedRead<'_>, RatchetStoreError> {
+        let raw = self.0.begin_read()?;
+        Ok(RatchetEncryptedTxn { raw, key: rtp_db_key(), txn: PhantomData })
+    }
+}
+
+impl<B: RatchetBackendRead + ?Sized> RatchetEncryptedTxn<'_, B> {
+    fn stored_key(&self, table: &str, record_key: &str) -> Result<String, RatchetStoreError> {
+        match rtp_table_by_name(table) {
+            Some(_) => rtp_index_key(&self.key, table, record_key),
+            None => Ok(record_key.to_string()),
+        }
+    }
+
+    fn get(&mut self, table: &str, record_key: &str) -> Result<Option<Vec<u8>>, RatchetStoreError> {
+        let stored_key = self.stored_key(table, record_key)?;
+        match self.raw.get(table, &stored_key)? {
+            Some(stored) => match rtp_open_indexed_record(&self.key, table, &stored_key, &stored)? {
+                (own_key, pt) if own_key == record_key => Ok(Some(pt)),
+                (own_key, _) => Err(RatchetStoreError::Format(format!("{}/{} is sealed as {}", table, record_key, own_key))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&mut self, table: &str) -> Result<Vec<RatchetEncryptedRow>, RatchetStoreError> {
+        Ok(self.raw.scan(table)?
+               .into_iter()
+               .map(|(stored_key, stored)| RatchetEncryptedRow::open(&self.key, table, stored_key, stored))
+               .collect())
+    }
+
+    /// Up to `limit` entries of a log after `after`, in order.
+    fn log_scan(&mut self, log: &str, after: u64, limit: usize) -> Result<Vec<Vec<u8>>, RatchetStoreError> {
+        self.raw.log_scan(log, after, limit)?
+            .into_iter()
+            .map(|(seq, stored)| rtp_open_record(&self.key, log, &seq.to_string(), &stored))
+            .collect()
+    }
+}
+
+impl RatchetEncryptedTxn<'_> {
+    fn put(&mut self, table: &str, record_key: &str, pt: &[u8]) -> Result<(), RatchetStoreError> {
+        match rtp_table_by_name(table) {
+            Some(_) => {
+                let (index_key, bytes) = rtp_seal_indexed_record(&self.key, table, record_key, pt)?;
+                self.raw.put(table, &index_key, bytes)
+            },
+            None => {
+                let bytes = rtp_seal_record(&self.key, table, record_key, pt)?;
+                self.raw.put(table, record_key, bytes)
+            },
+        }
+    }
+
+    fn delete(&mut self, table: &str, record_key: &str) -> Result<bool, RatchetStoreError> {
+        let stored_key = self.stored_key(table, record_key)?;
+        self.raw.delete(table, &stored_key)
+    }
+
+    /// Appends after the last entry of a log, sealed under its position.
+    fn log_append(&mut self, log: &str, entry: impl FnOnce(u64) -> Result<Vec<u8>, RatchetStoreError>) -> Result<u64, RatchetStoreError> {
+        let seq = self.raw.log_last(log)? + 1;
+        let pt = entry(seq)?;
+        let bytes = rtp_seal_record(&self.key, log, &seq.to_string(), &pt)?;
+        self.raw.log_put(log, seq, bytes)?;
+        Ok(seq)
+    }
+
+    fn commit(self) -> Result<(), RatchetStoreError> {
+        self.raw.commit()
+    }
+}
+
+lazy_static! {
+    static ref RATCHET_MEMORY_STORAGE: bool = match rtp_env_key("RATCHET_PAWL_STORAGE").as_str() {
+        "" | "redb" => false,
+        "memory" => true,
+        other => {
+            eprintln!("Ratchet-Pawl unknown storage {}, use redb or memory.", other);
+            std::process::exit(2);
+        },
+    };
+    /// Everything the routes read and write goes through here.
+    static ref RATCHET_STORE: RatchetEncrypted = match *RATCHET_MEMORY_STORAGE {
+        true => RatchetEncrypted(Box::new(RatchetMemBackend::default())),
+        false => RatchetEncrypted(Box::new(RatchetRedbBackend)),
+    };
+}
+
+/// Store work from a route, off the async workers: redb waits on the
+/// disk, and on whoever has the write transaction. A panic is the route's.
+async fn rtp_blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    match rocket::tokio::task::spawn_blocking(work).await {
+        Ok(t) => t,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// With nothing on disk there's nothing to unlock or migrate: a random
+/// key, and an empty store at the current schema version.
+fn rtp_init_memory_store() -> Result<(), RatchetStoreError> {
+    rtp_take_key(rand::random::<[u8; 32]>());
+    let mut txn = RATCHET_STORE.begin()?;
+    let ser = serde_json::to_vec(&RATCHET_SCHEMA_VERSION).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+    txn.raw.put(RATCHET_META_TABLE.name(), RATCHET_META_SCHEMA_VERSION, ser)?;
+    txn.raw.put(RATCHET_META_TABLE.name(), RATCHET_META_ENVELOPE_ONLY, serde_json::to_vec(&true).map_err(|e| RatchetStoreError::Format(e.to_string()))?)?;
+    txn.commit()
+}
+
+trait RatchetKeyed {
+    fn into_key<'k>(&self) -> &str;
+}
+
+/// Who made a row and who last changed it, and when, unix seconds; on
+/// every row from schema version 7. Rows the journal doesn't go back far
+/// enough for have it empty.
+#[derive(Clone, Debug, Default, PartialEq, FromForm, Serialize, Deserialize)]
+#[serde(default)]
+struct RatchetStamp {
+    created_by: String,
+    created_at: u64,
+    updated_by: String,
+    updated_at: u64,
+}
+
+impl RatchetStamp {
+    fn new(actor: &str) -> RatchetStamp {
+        let now = rtp_unix_now();
+        RatchetStamp {
+            created_by: actor.to_string(),
+            created_at: now,
+            updated_by: actor.to_string(),
+            updated_at: now,
+        }
+    }
+
+    fn updated(&self, actor: &str) -> RatchetStamp {
+        RatchetStamp {
+            updated_by: actor.to_string(),
+            updated_at: rtp_unix_now(),
+            ..self.clone()
+        }
+    }
+}
+
+// Changing a stored format means bumping RATCHET_SCHEMA_VERSION, and adding a migration.
+const RATCHET_USERS_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetUserEntry> = 
+    ReadWriteTable::<&str, Vec<u8>, RatchetUserEntry>(TableDefinition::new("ratchet_users"), PhantomData);
+const RATCHET_DEVS_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetDevEntry> = 
+    ReadWriteTable::<&str, Vec<u8>, RatchetDevEntry>(TableDefinition::new("ratchet_devs"), PhantomData);
+const RATCHET_USER_CMD_POLICY_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetUserCmdPolicy> = 
+    ReadWriteTable::<&str, Vec<u8>, RatchetUserCmdPolicy>(TableDefinition::new("ratchet_user_cmd_policy"), PhantomData);
+const RATCHET_APIKEY_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetApiKey> =
+    ReadWriteTable::<&str, Vec<u8>, RatchetApiKey>(TableDefinition::new("ratchet_api_keys"), PhantomData);
+const RATCHET_ADMINS_TABLE: ReadWriteTable<&str, Vec<u8>, RatchetAdminEntry> =
+    ReadWriteTable::<&str, Vec<u8>, RatchetAdminEntry>(TableDefinition::new("ratchet_admins"), PhantomData);
+
+lazy_static! {
+    static ref RATCHET_APIKEYS: Mutex<HashMap<String, RatchetApiKey>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+    static ref RATCHET_USERS: Mutex<HashMap<String, RatchetUserEntry>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+    static ref RATCHET_ADMINS: Mutex<HashMap<String, RatchetAdminEntry>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+    static ref RATCHET_DEVICES: Mutex<HashMap<String, RatchetDevEntry>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+    static ref RATCHET_USER_CMD_POLICY: Mutex<RatchetUserCmdPolicy> = {
+        let s = String::new();
+        Mutex::new(RatchetUserCmdPolicy::new(s, RatchetStamp::default()))
+    };
+    // TODO: It's pretty risky to leave these as separate mutex
+    static ref RATCHET_COOKIES: Mutex<HashMap<String, RatchetSession>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+    static ref RATCHET_USER_COOKIES: Mutex<HashMap<String, HashSet<String>>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+    static ref RATCHET_POLL_PINS: Mutex<Vec<Sender<bool>>> = {
+        let p = Vec::new();
+        Mutex::new(p)
+    };
+    // Swapped wholesale on rotation, see rtp_rotate_key
+    static ref PERM_DB_KEY: std::sync::RwLock<Arc<[u8; 32]>> = std::sync::RwLock::new(Arc::new([0; 32]));
+    // Recommend polling upon attach to subscribers.
+    static ref LONG_POLL_EPOCH: AtomicU64 = AtomicU64::new(1);
+    // What API keys are hashed under, loaded with the database, see rtp_api_key_secret
+    static ref RATCHET_API_KEY_HMAC: std::sync::RwLock<[u8; 32]> = std::sync::RwLock::new([0; 32]);
+}
+
+/// Installs the database key, before the database is imported.
+fn rtp_take_key(key: [u8; 32]) {
+    let mut k = PERM_DB_KEY.write().unwrap_or_else(|e| e.into_inner());
+    *k = Arc::new(key);
+}
+
+/// Current database key; cheap to clone, and stays valid for whoever
+/// holds it even if a rotation swaps it out underneath.
+fn rtp_db_key() -> Arc<[u8; 32]> {
+    PERM_DB_KEY.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Plaintext bookkeeping about the database itself, nothing secret goes here.
+const RATCHET_META_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("ratchet_meta");
+const RATCHET_META_KDF: &str = "kdf";
+const RATCHET_META_WRAPPED_KEY: &str = "wrapped_key";
+const RATCHET_META_SCHEMA_VERSION: &str = "schema_version";
+/// A known value sealed under the database key, to tell a wrong key from bad rows.
+const RATCHET_META_KEY_CHECK: &str = "key_check";
+const RATCHET_KEY_CHECK_VALUE: &[u8] = b"ratchet-pawl key check";
+const RATCHET_META_BACKUP_SIGNING_KEY: &str = "backup_signing_key";
+const RATCHET_META_API_KEY_HMAC: &str = "api_key_hmac";
+/// The last audit entry's position and hash, see RatchetAuditHead.
+const RATCHET_META_AUDIT_HEAD: &str = "audit_head";
+/// Written by the migration that leaves every row sealed; from then on a row
+/// without the envelope is refused rather than opened as FF1, which anyone
+/// could put back in place of a newer row.
+const RATCHET_META_ENVELOPE_ONLY: &str = "envelope_only";
+/// Meta entries sealed under the database key, resealed along with the tables.
+const RATCHET_META_SEALED: &[&str] = &[RATCHET_META_BACKUP_SIGNING_KEY, RATCHET_META_API_KEY_HMAC, RATCHET_META_AUDIT_HEAD];
+
+/// How the database key is derived from what the operator supplies.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RatchetKdf {
+    /// For passphrases, the default.
+    Argon2id,
+    /// For keys that are already high-entropy, e.g. generated.
+    HkdfSha256,
+}
+
+/// Stored under RATCHET_META_KDF, the salt isn't secret.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetKdfParams {
+    kdf: RatchetKdf,
+    salt: Vec<u8>,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    /// Set when the key was split with `split-key`, pawl then starts sealed
+    /// until this many shares are submitted.
+    #[serde(default)]
+    shamir_threshold: Option<u8>,
+    /// Share number to its share_check, so the unseal page can turn away
+    /// anything that isn't one of the shares split-key handed out.
+    #[serde(default)]
+    share_checks: BTreeMap<u8, String>,
+}
+
+impl RatchetKdfParams {
+    /// Fresh salt, with the KDF from RATCHET_PAWL_KDF ("argon2id" or "hkdf").
+    fn generate() -> RatchetKdfParams {
+        match rtp_env_key("RATCHET_PAWL_KDF").as_str() {
+            "hkdf" => RatchetKdfParams::with_kdf(RatchetKdf::HkdfSha256),
+            _ => RatchetKdfParams::with_kdf(RatchetKdf::Argon2id),
+        }
+    }
+
+    fn with_kdf(kdf: RatchetKdf) -> RatchetKdfParams {
+        RatchetKdfParams {
+            kdf,
+            salt: rand::random::<[u8; 16]>().to_vec(),
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            shamir_threshold: None,
+            share_checks: BTreeMap::new(),
+        }
+    }
+
+    /// Hash of a whole share under the salt. Shares are as long as the
+    /// random secret, so this gives nothing away about them.
+    fn share_check(&self, share: &[u8]) -> String {
+        let mut h = Sha256::new();
+        h.update(b"ratchet-pawl key share");
+        h.update(&self.salt);
+        h.update(share);
+        hex::encode(h.finalize())
+    }
+
+    fn derive(&self, secret: &[u8]) -> Result<[u8; 32], RatchetStoreError> {
+        let mut k = [0; 32];
+        match self.kdf {
+            RatchetKdf::Argon2id => {
+                let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(k.len()))
+                                    .map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(secret, &self.salt, &mut k)
+                    .map_err(|_| RatchetStoreError::Crypto)?;
+            },
+            RatchetKdf::HkdfSha256 => {
+                Hkdf::<Sha256>::new(Some(&self.salt), secret)
+                    .expand(b"ratchet-pawl database key", &mut k)
+                    .map_err(|_| RatchetStoreError::Crypto)?;
+            },
+        }
+        Ok(k)
+    }
+}
+
+fn rtp_read_meta(db: &Database, meta_key: &str) -> Result<Option<Vec<u8>>, RatchetStoreError> {
+    let read_txn = db.begin_read()?;
+    let table = match read_txn.open_table(RATCHET_META_TABLE) {
+        Ok(t) => t,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(table.get(meta_key)?.map(|v| v.value()))
+}
+
+/// Whether the database that was just opened may still have FF1 rows, see
+/// RATCHET_META_ENVELOPE_ONLY.
+static RATCHET_LEGACY_ROWS: AtomicBool = AtomicBool::new(false);
+
+fn rtp_note_legacy_rows(db: &Database) -> Result<(), RatchetStoreError> {
+    let legacy = rtp_read_meta(db, RATCHET_META_ENVELOPE_ONLY)?.is_none();
+    RATCHET_LEGACY_ROWS.store(legacy, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Reads the KDF parameters, `None` means the database predates them,
+/// or its key is wrapped instead.
+fn rtp_read_kdf(db: &Database) -> Result<Option<RatchetKdfParams>, RatchetStoreError> {
+    match rtp_read_meta(db, RATCHET_META_KDF)? {
+        Some(v) => serde_json::from_slice(&v)
+                               .map(Some)
+                               .map_err(|e| RatchetStoreError::Format(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// How the next start gets the key back, stored in the same
+/// transaction as the rows sealed under it.
+enum RatchetKeyRecord {
+    /// Derived from a masking key.
+    Kdf(RatchetKdfParams),
+    /// Random, and wrapped by a key provider, e.g. a PKCS#11 token.
+    #[cfg_attr(not(feature = "pkcs11"), allow(dead_code))]
+    Wrapped(Vec<u8>),
+}
+
+/// Before there was a KDF, the key was the passphrase zero-padded,
+/// or truncated, to 32 bytes. Only used to upgrade those databases.
+fn rtp_legacy_key(key: &String) -> [u8; 32] {
+    let mut k = [0; 32];
+    k.iter_mut()
+        .enumerate()
+        .for_each(|(i,b)| *b = *key.as_bytes()
+                                   .get(i)
+                                   .unwrap_or(&0));
+    k
+}
+
+/// Re-seals every row in every table from `old` to `new` in one write
+/// transaction, along with the record of how to get `new` back, so the
+/// database is never left half-rotated. The live key is swapped before any
+/// other writer can get in.
+fn rtp_rotate_key(db: &Database, old: &[u8; 32], new: &[u8; 32], record: &RatchetKeyRecord) -> Result<usize, RatchetStoreError> {
+    let write_txn = db.begin_write()?;
+    let mut n = 0;
+    n += RATCHET_USERS_TABLE.reseal(&write_txn, old, new)?;
+    n += RATCHET_DEVS_TABLE.reseal(&write_txn, old, new)?;
+    n += RATCHET_USER_CMD_POLICY_TABLE.reseal(&write_txn, old, new)?;
+    n += RATCHET_APIKEY_TABLE.reseal(&write_txn, old, new)?;
+    n += RATCHET_ADMINS_TABLE.reseal(&write_txn, old, new)?;
+    n += rtp_reseal_log(&write_txn, RATCHET_JOURNAL_TABLE, old, new)?;
+    n += rtp_reseal_log(&write_txn, RATCHET_AUDIT_TABLE, old, new)?;
+    n += rtp_reseal_quarantine(&write_txn, old, new)?;
+    {
+        let mut meta = write_txn.open_table(RATCHET_META_TABLE)?;
+        match record {
+            RatchetKeyRecord::Kdf(kdf) => {
+                let ser = serde_json::to_vec(kdf).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+                meta.insert(RATCHET_META_KDF, ser)?;
+                meta.remove(RATCHET_META_WRAPPED_KEY)?;
+            },
+            RatchetKeyRecord::Wrapped(wrapped) => {
+                meta.insert(RATCHET_META_WRAPPED_KEY, wrapped.clone())?;
+                meta.remove(RATCHET_META_KDF)?;
+            },
+        }
+        meta.insert(RATCHET_META_KEY_CHECK, rtp_seal_record(new, RATCHET_META_TABLE.name(), RATCHET_META_KEY_CHECK, RATCHET_KEY_CHECK_VALUE)?)?;
+        for name in RATCHET_META_SEALED.iter() {
+            let sealed = meta.get(*name)?.map(|v| v.value());
+            if let Some(sealed) = sealed {
+                let pt = rtp_open_record(old, RATCHET_META_TABLE.name(), name, &sealed)?;
+                meta.insert(*name, rtp_seal_record(new, RATCHET_META_TABLE.name(), name, &pt)?)?;
+                n += 1;
+            }
+        }
+    }
+
+    // writers take the key after begin_write, hold them here until it's swapped.
+    let mut live_key = PERM_DB_KEY.write().unwrap_or_else(|e| e.into_inner());
+    write_txn.commit()?;
+    *live_key = Arc::new(*new);
+    Ok(n)
+}
+
+/// Quarantined rows that still open under `old` are sealed again under
+/// `new`, and move with their index key, so they can still be looked at
+/// after a rotation; the ones that don't open never will.
+fn rtp_reseal_quarantine(write_txn: &WriteTransaction, old: &[u8; 32], new: &[u8; 32]) -> Result<usize, RatchetStoreError> {
+    let mut table = write_txn.open_table(RATCHET_QUARANTINE_TABLE)?;
+    let mut rows = vec![];
+    for tup in table.iter()? {
+        let (k, v) = tup?;
+        rows.push((k.value().to_string(), v.value()));
+    }
+    let mut n = 0;
+    for (id, ser) in rows {
+        let mut q: RatchetQuarantined = serde_json::from_slice(&ser).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        let (own_key, pt) = match rtp_open_indexed_record(old, &q.table, &q.record_key, &q.stored) {
+            Ok(opened) => opened,
+            Err(_) => continue,
+        };
+        (q.record_key, q.stored) = match rtp_table_by_name(&q.table) {
+            Some(_) => rtp_seal_indexed_record(new, &q.table, &own_key, &pt)?,
+            None => (own_key.clone(), rtp_seal_record(new, &q.table, &own_key, &pt)?),
+        };
+        table.remove(id.as_str())?;
+        let ser = serde_json::to_vec(&q).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        table.insert(q.id().as_str(), ser)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Backend data for users, used for authentication
+/// 
+/// Should not be sent over any unsecure channel, since
+/// hashes are subject to attacks.
+/// 
+#[derive(Clone, FromForm, Debug, Serialize, Deserialize)]
+struct RatchetUserEntry {
+    username: String,
+    passhash: String,
+    #[serde(flatten)]
+    #[field(default = <RatchetStamp as Default>::default())]
+    stamp: RatchetStamp,
+}
+
+impl RatchetKeyed for RatchetUserEntry{
+    fn into_key(&self) -> &str {
+        &self.username.as_str()
+    }
+}
+
+/// For long-pollers, each API change results in sending a notification out.
+/// This notificaation is associated with a serial number for the total number of
+/// changes seen so far.
+/// 
+/// Then on re-attach, a poller who may have missed multiple updates is 
+/// immediately notified again, rather than leaving open the possibility of 
+/// missed updates, and a subscriber in a stale state.
+/// 
+/// It's also really really cheap.
+/// 
+/// TODO: Frontend pollers for AJAX / real-time updates.
+/// 
+async fn rtp_notify_pollers() {
+    LONG_POLL_EPOCH.fetch_add(1, Ordering::Relaxed);
+    let mut pins = RATCHET_POLL_PINS.lock().await;
+    while let Some(pin) = pins.pop() {
+        let _ = pin.send(true); // it's pub-sub, so it will leak; 
+                        // we don't know the subscriber is gone until after they fail to reconnect.
+    }
+}
+
+/// Whether two rows are the same as written, `last_used` and the like
+/// aside.
+fn rtp_same_row<V: Serialize>(a: Option<&V>, b: Option<&V>) -> bool {
+    a.map(|v| serde_json::to_vec(v).ok()) == b.map(|v| serde_json::to_vec(v).ok())
+}
+
+/// Undoes a failed write or remove in the map, unless the entry has moved
+/// on from what the handler left there; whoever moved it queued their
+/// write after ours, and putting `previous` back would lose it.
+fn rtp_roll_back<V: Serialize>(map: &mut HashMap<String, V>, key: &str, ours: Option<&V>, previous: Option<V>) {
+    if !rtp_same_row(map.get(key), ours) {
+        return;
+    }
+    match previous {
+        Some(previous) => { map.insert(key.to_string(), previous); },
+        None => { map.remove(key); },
+    }
+}
+
+/// Frontend API for removing a user by username.
+/// 
+/// TODO: Don't remove the bottom dollar
+/// 
+#[post("/rmuser", format = "multipart/form-data", data = "<username>")]
+async fn rm_user(admin: RatchetAdmin<RatchetPermUsers>, username: Form<String>) -> status::Custom<&'static str> {
+    let mut users = RATCHET_USERS.lock().await;
+    match users.remove(&*username) {
+        Some(user) => {
+            let saved = RATCHET_USERS_TABLE.queue_rm(&user, &admin.username);
+            drop(users);
+            if let Err(e) = saved.done().await {
+                eprintln!("Ratchet-Pawl unable to remove user: {:?}", e);
+                rtp_roll_back(&mut *RATCHET_USERS.lock().await, &username, None, Some(user));
+                return status::Custom(Status::InternalServerError, "");
+            }
+            rocket::tokio::spawn(rtp_notify_pollers());
+            status::Custom(Status::Ok, "")
+        },
+        None => status::Custom(Status::Gone, ""),
+    }
+}
+
+/// Frontend API for adding a user.
+/// 
+/// TODO: Input validation, password policy
+/// 
+#[post("/adduser", format = "multipart/form-data", data = "<newuser>")]
+async fn add_user(admin: RatchetAdmin<RatchetPermUsers>, newuser: Form<RatchetUserEntry>) -> status::Custom<&'static str> {
+    let mut users = RATCHET_USERS.lock().await;
+    if !users.contains_key(&newuser.username) {
+        if let Ok(h) = bcrypt::hash(&newuser.passhash) {
+            let new_entry = RatchetUserEntry {
+                username: newuser.username.clone(),
+                passhash: h.clone(),
+                stamp: RatchetStamp::new(&admin.username),
+            };
+
+            let saved = RATCHET_USERS_TABLE.queue_write(&new_entry, &admin.username);
+
+            users.insert(
+                newuser.username.clone(), new_entry.clone()
+            );
+            drop(users);
+            if let Err(e) = saved.done().await {
+                eprintln!("Ratchet-Pawl unable to add user: {:?}", e);
+                rtp_roll_back(&mut *RATCHET_USERS.lock().await, &newuser.username, Some(&new_entry), None);
+                return status::Custom(Status::InternalServerError, "");
+            }
+            rocket::tokio::spawn(rtp_notify_pollers());
+            status::Custom(Status::Ok, "")
+        } else {
+            // TODO: This doesn't exactly mean this anymore
+            status::Custom(Status::Conflict, "")
+        }
+    } else {
+        status::Custom(Status::Conflict, "")
+    }
+}
+
+/// Frontend API for editing a user.
+/// 
+/// TODO: Input validation, password policy
+/// 
+#[post("/edituser", format = "multipart/form-data", data = "<edited>")]
+async fn edit_user(admin: RatchetAdmin<RatchetPermUsers>, edited: Form<RatchetUserEntry>) -> status::Custom<&'static str> {
+    let mut users = RATCHET_USERS.lock().await;
+    if !users.contains_key(&edited.username) {
+        status::Custom(Status::Gone, "")
+    } else {
+        let mut user_update = edited.to_owned();
+        if let Ok(h) = bcrypt::hash(user_update.passhash)  {
+            user_update.passhash = h;
+            user_update.stamp = users.get(&user_update.username).map(|u| u.stamp.updated(&admin.username)).unwrap_or_default();
+            let saved = RATCHET_USERS_TABLE.queue_write(&user_update, &admin.username);
+            let previous = users.insert(user_update.username.clone(), user_update.clone());
+            drop(users);
+            if let Err(e) = saved.done().await {
+                eprintln!("Ratchet-Pawl unable to edit user: {:?}", e);
+                rtp_roll_back(&mut *RATCHET_USERS.lock().await, &user_update.username, Some(&user_update), previous);
+                return status::Custom(Status::InternalServerError, "");
+            }
+            rocket::tokio::spawn(rtp_notify_pollers());
+            status::Custom(Status::Ok, "")
+        } else {
+            // TODO: This doesn't exactly mean this anymore
+            status::Custom(Status::Gone, "")
+        }
     }
 }
 
@@ -309,456 +1572,4215 @@ async fn edit_user(_admin: RatchetUser, edited: Form<RatchetUserEntry>) -> statu
 #[derive(Clone, FromForm, Debug, Serialize)]
 struct RatchetFrontendUserEntry {
     username: String,
+    #[serde(flatten)]
+    #[field(default = <RatchetStamp as Default>::default())]
+    stamp: RatchetStamp,
+}
+
+/// Frontend API for listing users.
+#[get("/getusers")]
+async fn get_users(_admin: RatchetAdmin<RatchetPermView>) -> Json<Vec<RatchetFrontendUserEntry>> {
+    let users = RATCHET_USERS.lock().await;
+    Json(
+        users
+            .values()
+            .map(|u| RatchetFrontendUserEntry {
+                username: u.username.clone(),
+                stamp: u.stamp.clone(),
+            })
+            .collect::<Vec<RatchetFrontendUserEntry>>(),
+    )
+}
+
+/// Who can log into pawl, kept apart from the TACACS+ users ratchet serves.
+/// Schema version 6 added these, before then every user was an admin.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetAdminEntry {
+    username: String,
+    passhash: String,
+    role: RatchetRole,
+    #[serde(flatten)]
+    stamp: RatchetStamp,
+}
+
+impl RatchetKeyed for RatchetAdminEntry {
+    fn into_key(&self) -> &str {
+        self.username.as_str()
+    }
+}
+
+/// What an admin is, see allows for what each can do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "kebab-case")]
+enum RatchetRole {
+    #[field(value = "viewer")]
+    Viewer,
+    #[field(value = "operator")]
+    Operator,
+    #[field(value = "device-admin")]
+    DeviceAdmin,
+    #[field(value = "policy-admin")]
+    PolicyAdmin,
+    #[field(value = "superadmin")]
+    Superadmin,
+}
+
+impl RatchetRole {
+    fn name(&self) -> &'static str {
+        match self {
+            RatchetRole::Viewer => "viewer",
+            RatchetRole::Operator => "operator",
+            RatchetRole::DeviceAdmin => "device-admin",
+            RatchetRole::PolicyAdmin => "policy-admin",
+            RatchetRole::Superadmin => "superadmin",
+        }
+    }
+
+    /// Everyone can look. Operators look after TACACS+ users, device and
+    /// policy admins their own tables, and superadmins everything.
+    fn allows(&self, permission: RatchetPermission) -> bool {
+        matches!((self, permission),
+                 (_, RatchetPermission::View) |
+                 (RatchetRole::Operator, RatchetPermission::Users) |
+                 (RatchetRole::DeviceAdmin, RatchetPermission::Devices) |
+                 (RatchetRole::PolicyAdmin, RatchetPermission::Policy) |
+                 (RatchetRole::Superadmin, _))
+    }
+}
+
+const RATCHET_DEFAULT_ADMIN: &str = "DefaultRatchetAdmin";
+
+/// Whether anyone but `username` is a superadmin.
+fn rtp_other_superadmin(admins: &HashMap<String, RatchetAdminEntry>, username: &str) -> bool {
+    admins.values().any(|a| a.username != username && a.role == RatchetRole::Superadmin)
+}
+
+/// A new admin, or an edit to one leaving out whatever isn't changing.
+#[derive(FromForm)]
+struct RatchetAdminForm {
+    username: String,
+    password: Option<String>,
+    role: Option<RatchetRole>,
+}
+
+/// Frontend API for adding an admin, with a password and a role.
+#[post("/addadmin", format = "multipart/form-data", data = "<newadmin>")]
+async fn add_admin(admin: RatchetAdmin<RatchetPermSuperadmin>, newadmin: Form<RatchetAdminForm>) -> status::Custom<&'static str> {
+    let (password, role) = match (newadmin.password.as_deref().filter(|p| !p.is_empty()), newadmin.role) {
+        (Some(p), Some(r)) if !newadmin.username.is_empty() => (p, r),
+        _ => return status::Custom(Status::BadRequest, "Give a username, a password and a role."),
+    };
+    let mut admins = RATCHET_ADMINS.lock().await;
+    if admins.contains_key(&newadmin.username) {
+        return status::Custom(Status::Conflict, "");
+    }
+    let new_entry = match bcrypt::hash(password) {
+        Ok(h) => RatchetAdminEntry {
+            username: newadmin.username.clone(),
+            passhash: h,
+            role,
+            stamp: RatchetStamp::new(&admin.username),
+        },
+        Err(_) => return status::Custom(Status::InternalServerError, ""),
+    };
+    let saved = RATCHET_ADMINS_TABLE.queue_write(&new_entry, &admin.username);
+    admins.insert(new_entry.username.clone(), new_entry.clone());
+    drop(admins);
+    if let Err(e) = saved.done().await {
+        eprintln!("Ratchet-Pawl unable to add admin: {:?}", e);
+        rtp_roll_back(&mut *RATCHET_ADMINS.lock().await, &newadmin.username, Some(&new_entry), None);
+        return status::Custom(Status::InternalServerError, "");
+    }
+    status::Custom(Status::Ok, "")
+}
+
+/// Frontend API for changing an admin's password, role, or both. They're
+/// logged out either way, and the last superadmin stays one.
+#[post("/editadmin", format = "multipart/form-data", data = "<edited>")]
+async fn edit_admin(admin: RatchetAdmin<RatchetPermSuperadmin>, edited: Form<RatchetAdminForm>) -> status::Custom<&'static str> {
+    let mut admins = RATCHET_ADMINS.lock().await;
+    let mut cookie_store = RATCHET_COOKIES.lock().await;
+    let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
+    let mut admin_update = match admins.get(&edited.username) {
+        Some(a) => a.clone(),
+        None => return status::Custom(Status::Gone, ""),
+    };
+    admin_update.stamp = admin_update.stamp.updated(&admin.username);
+    if let Some(role) = edited.role {
+        admin_update.role = role;
+    }
+    if let Some(password) = edited.password.as_deref().filter(|p| !p.is_empty()) {
+        match bcrypt::hash(password) {
+            Ok(h) => admin_update.passhash = h,
+            Err(_) => return status::Custom(Status::InternalServerError, ""),
+        }
+    }
+    if admin_update.role != RatchetRole::Superadmin && !rtp_other_superadmin(&admins, &admin_update.username) {
+        return status::Custom(Status::Conflict, "");
+    }
+    let saved = RATCHET_ADMINS_TABLE.queue_write(&admin_update, &admin.username);
+    let previous = admins.insert(admin_update.username.clone(), admin_update.clone());
+    let revoked = user_cookies.remove(&admin_update.username).map(|active_cookies| {
+        let n = active_cookies.len();
+        active_cookies.into_iter().for_each(|each_cookie| {cookie_store.remove(&each_cookie);});
+        n
+    });
+    drop((admins, cookie_store, user_cookies));
+    if let Err(e) = saved.done().await {
+        eprintln!("Ratchet-Pawl unable to edit admin: {:?}", e);
+        rtp_roll_back(&mut *RATCHET_ADMINS.lock().await, &admin_update.username, Some(&admin_update), previous);
+        return status::Custom(Status::InternalServerError, "");
+    }
+    if let Some(n) = revoked {
+        rtp_audit("sessions_rm", &admin.username, &admin_update.username, admin.source_ip, format!("{} sessions, admin edited", n)).await;
+    }
+    status::Custom(Status::Ok, "")
+}
+
+/// Frontend API for removing an admin by username, except the last superadmin.
+#[post("/rmadmin", format = "multipart/form-data", data = "<username>")]
+async fn rm_admin(admin: RatchetAdmin<RatchetPermSuperadmin>, username: Form<String>) -> status::Custom<&'static str> {
+    let mut admins = RATCHET_ADMINS.lock().await;
+    let mut cookie_store = RATCHET_COOKIES.lock().await;
+    let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
+    match admins.get(&*username) {
+        None => return status::Custom(Status::Gone, ""),
+        Some(a) if a.role == RatchetRole::Superadmin && !rtp_other_superadmin(&admins, &a.username) => {
+            return status::Custom(Status::Conflict, "");
+        },
+        Some(_) => (),
+    }
+    let removed = match admins.remove(&*username) {
+        Some(a) => a,
+        None => return status::Custom(Status::Gone, ""),
+    };
+    let saved = RATCHET_ADMINS_TABLE.queue_rm(&removed, &admin.username);
+    let revoked = user_cookies.remove(&removed.username).map(|active_cookies| {
+        let n = active_cookies.len();
+        active_cookies.into_iter().for_each(|each_cookie| {cookie_store.remove(&each_cookie);});
+        n
+    });
+    drop((admins, cookie_store, user_cookies));
+    if let Err(e) = saved.done().await {
+        eprintln!("Ratchet-Pawl unable to remove admin: {:?}", e);
+        rtp_roll_back(&mut *RATCHET_ADMINS.lock().await, &username, None, Some(removed));
+        return status::Custom(Status::InternalServerError, "");
+    }
+    if let Some(n) = revoked {
+        rtp_audit("sessions_rm", &admin.username, &removed.username, admin.source_ip, format!("{} sessions, admin removed", n)).await;
+    }
+    status::Custom(Status::Ok, "")
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct RatchetFrontendAdminEntry {
+    username: String,
+    role: RatchetRole,
+    #[serde(flatten)]
+    stamp: RatchetStamp,
+}
+
+/// Frontend API for listing admins.
+#[get("/getadmins")]
+async fn get_admins(_admin: RatchetAdmin<RatchetPermSuperadmin>) -> Json<Vec<RatchetFrontendAdminEntry>> {
+    let admins = RATCHET_ADMINS.lock().await;
+    let mut listed: Vec<RatchetFrontendAdminEntry> = admins
+        .values()
+        .map(|a| RatchetFrontendAdminEntry {
+            username: a.username.clone(),
+            role: a.role,
+            stamp: a.stamp.clone(),
+        })
+        .collect();
+    listed.sort_by(|a, b| a.username.cmp(&b.username));
+    Json(listed)
+}
+
+/// Backend data for TACACS+ clients, used for authentication
+/// 
+/// Should not be sent over any unsecure channel, since
+/// hashes are subject to attacks.
+/// 
+/// Schema version 2 added `description`.
+#[derive(Clone, FromForm, Debug, Serialize, Deserialize)]
+struct RatchetDevEntry {
+    network_id: String,
+    key: String,
+    description: Option<String>,
+    #[serde(flatten)]
+    #[field(default = <RatchetStamp as Default>::default())]
+    stamp: RatchetStamp,
+}
+
+impl RatchetKeyed for RatchetDevEntry{
+    fn into_key(&self) -> &str {
+        self.network_id.as_str()
+    }
+}
+
+/// Frontend API for removing devices.
+#[post("/rmdev", format = "multipart/form-data", data = "<network_id>")]
+async fn rm_dev(admin: RatchetAdmin<RatchetPermDevices>, network_id: Form<String>) -> status::Custom<&'static str> {
+    let mut devs = RATCHET_DEVICES.lock().await;
+    match devs.remove(&*network_id) {
+        Some(dev) => {
+            let saved = RATCHET_DEVS_TABLE.queue_rm(&dev, &admin.username);
+            drop(devs);
+            if let Err(e) = saved.done().await {
+                eprintln!("Ratchet-Pawl unable to remove device: {:?}", e);
+                rtp_roll_back(&mut *RATCHET_DEVICES.lock().await, &network_id, None, Some(dev));
+                return status::Custom(Status::InternalServerError, "");
+            }
+            rocket::tokio::spawn(rtp_notify_pollers());
+            status::Custom(Status::Ok, "")
+        },
+        None => status::Custom(Status::Gone, ""),
+    }
+}
+
+/// Frontend API for adding devices.
+///  
+/// TODO: Input validation, password policy
+/// 
+#[post("/adddev", format = "multipart/form-data", data = "<newdev>")]
+async fn add_dev(admin: RatchetAdmin<RatchetPermDevices>, newdev: Form<RatchetDevEntry>) -> status::Custom<&'static str> {
+    let mut devs = RATCHET_DEVICES.lock().await;
+    // TODO: Replace this with networkier stuff
+    if !devs.contains_key(&newdev.network_id) {
+        let mut new_dev = newdev.to_owned();
+        new_dev.stamp = RatchetStamp::new(&admin.username);
+        let saved = RATCHET_DEVS_TABLE.queue_write(&new_dev, &admin.username);
+        devs.insert(new_dev.network_id.clone(), new_dev.clone());
+        drop(devs);
+        if let Err(e) = saved.done().await {
+            eprintln!("Ratchet-Pawl unable to add device: {:?}", e);
+            rtp_roll_back(&mut *RATCHET_DEVICES.lock().await, &newdev.network_id, Some(&new_dev), None);
+            return status::Custom(Status::InternalServerError, "");
+        }
+        rocket::tokio::spawn(rtp_notify_pollers());
+        status::Custom(Status::Ok, "")
+    } else {
+        status::Custom(Status::Conflict, "")
+    }
+}
+
+/// Frontend API for editing devices.
+///  
+/// TODO: Input validation, password policy
+/// 
+#[post("/editdev", format = "multipart/form-data", data = "<edited>")]
+async fn edit_dev(admin: RatchetAdmin<RatchetPermDevices>, edited: Form<RatchetDevEntry>) -> status::Custom<&'static str> {
+    let mut devs = RATCHET_DEVICES.lock().await;
+    if !devs.contains_key(&edited.network_id) {
+        status::Custom(Status::Gone, "")
+    } else {
+        let mut dev_update = edited.to_owned();
+        dev_update.stamp = devs.get(&dev_update.network_id).map(|d| d.stamp.updated(&admin.username)).unwrap_or_default();
+        let saved = RATCHET_DEVS_TABLE.queue_write(&dev_update, &admin.username);
+        let previous = devs.insert(dev_update.network_id.clone(), dev_update.clone());
+        drop(devs);
+        if let Err(e) = saved.done().await {
+            eprintln!("Ratchet-Pawl unable to edit device: {:?}", e);
+            rtp_roll_back(&mut *RATCHET_DEVICES.lock().await, &dev_update.network_id, Some(&dev_update), previous);
+            return status::Custom(Status::InternalServerError, "");
+        }
+        rocket::tokio::spawn(rtp_notify_pollers());
+        status::Custom(Status::Ok, "")
+    }
+}
+
+/// Special structure to only return safe devdata
+/// back to the Frontend.
+#[derive(Clone, FromForm, Debug, Serialize)]
+struct RatchetFrontendDevEntry {
+    network_id: String,
+    description: Option<String>,
+    #[serde(flatten)]
+    #[field(default = <RatchetStamp as Default>::default())]
+    stamp: RatchetStamp,
+}
+
+/// Frontend API for listing users.
+#[get("/getdevs")]
+async fn get_devs(_admin: RatchetAdmin<RatchetPermView>) -> Json<Vec<RatchetFrontendDevEntry>> {
+    let devs = RATCHET_DEVICES.lock().await;
+    Json(
+        devs.values()
+            .map(|d| RatchetFrontendDevEntry {
+                network_id: d.network_id.clone(),
+                description: d.description.clone(),
+                stamp: d.stamp.clone(),
+            })
+            .collect::<Vec<RatchetFrontendDevEntry>>(),
+    )
+}
+
+/// Frontend API for modifying policy
+///  
+/// TODO: Input validation, password policy
+/// 
+#[post("/pushpolicy", format = "multipart/form-data", data = "<edited>")]
+async fn push_policy(admin: RatchetAdmin<RatchetPermPolicy>, edited: Form<RatchetUserCmdPolicy>) -> status::Custom<&'static str> {
+    let mut policy = RATCHET_USER_CMD_POLICY.lock().await;
+    let mut new_policy = edited.to_owned();
+    new_policy.stamp = policy.stamp.updated(&admin.username);
+
+    if rtp_validate_policy(&new_policy.policy) {
+        let previous = std::mem::replace(&mut *policy, new_policy.clone());
+        let saved = RATCHET_USER_CMD_POLICY_TABLE.queue_write(&new_policy, &admin.username);
+        drop(policy);
+        if let Err(e) = saved.done().await {
+            eprintln!("Ratchet-Pawl unable to save policy: {:?}", e);
+            let mut policy = RATCHET_USER_CMD_POLICY.lock().await;
+            if rtp_same_row(Some(&*policy), Some(&new_policy)) {
+                *policy = previous;
+            }
+            return status::Custom(Status::InternalServerError, "");
+        }
+        rocket::tokio::spawn(rtp_notify_pollers());
+        status::Custom(Status::Ok, "")
+    } else {
+        drop(policy);
+        rtp_audit("policy_rejected", &admin.username, new_policy.into_key(), admin.source_ip, String::from("push")).await;
+        status::Custom(Status::Conflict, "")
+    }
+}
+
+/// Frontend API for checking a policy without pushing it.
+#[post("/validatepolicy", format = "multipart/form-data", data = "<edited>")]
+async fn validate_policy(admin: RatchetAdmin<RatchetPermView>, edited: Form<RatchetUserCmdPolicy>) -> status::Custom<&'static str> {
+    if rtp_validate_policy(&edited.policy) {
+        status::Custom(Status::Ok, "")
+    } else {
+        rtp_audit("policy_rejected", &admin.username, edited.into_key(), admin.source_ip, String::from("validate")).await;
+        status::Custom(Status::Conflict, "")
+    }
+}
+
+#[derive(Clone, FromForm)]
+struct RatchetKeyRotation {
+    old_key: String,
+    new_key: String,
+}
+
+/// How a rotation from the frontend went, see rotate_key.
+enum RatchetRotation {
+    Rotated(usize),
+    WrongKey,
+    Failed(RatchetStoreError),
+}
+
+/// Frontend API for rotating the database key, without a restart.
+/// 
+/// The old key has to match the running one. Whoever manages the
+/// environment needs to start pawl with the new key from here on.
+/// In memory there's nothing to rotate.
+/// 
+/// Both derivations and the reseal are blocking, so they're done off the
+/// async workers; the new key isn't derived unless the old one is right.
+#[post("/rotatekey", format = "multipart/form-data", data = "<rotation>")]
+async fn rotate_key(admin: RatchetAdmin<RatchetPermSuperadmin>, rotation: Form<RatchetKeyRotation>) -> status::Custom<&'static str> {
+    if rotation.new_key.is_empty() || *RATCHET_MEMORY_STORAGE {
+        return status::Custom(Status::Conflict, "");
+    }
+    let rotation = rotation.into_inner();
+    let rotated = rocket::tokio::task::spawn_blocking(move || {
+        let old = match rtp_current_key(&rtp_db(), &rotation.old_key) {
+            Ok(old) if old == *rtp_db_key() => old,
+            Ok(_) => return RatchetRotation::WrongKey,
+            Err(e) => return RatchetRotation::Failed(e),
+        };
+        let new_kdf = RatchetKdfParams::generate();
+        let rotated = new_kdf.derive(rotation.new_key.as_bytes())
+                             .and_then(|new| rtp_rotate_key(&rtp_db(), &old, &new, &RatchetKeyRecord::Kdf(new_kdf)));
+        match rotated {
+            Ok(n) => RatchetRotation::Rotated(n),
+            Err(e) => RatchetRotation::Failed(e),
+        }
+    }).await.unwrap_or_else(|e| RatchetRotation::Failed(RatchetStoreError::Format(e.to_string())));
+    match rotated {
+        RatchetRotation::Rotated(n) => {
+            println!("Ratchet-Pawl rotated the database key, re-encrypted {} records.", n);
+            rtp_audit("key_rotate", &admin.username, "", admin.source_ip, format!("{} records", n)).await;
+            status::Custom(Status::Ok, "")
+        },
+        RatchetRotation::WrongKey => {
+            rtp_audit("key_rotate_rejected", &admin.username, "", admin.source_ip, String::from("wrong old key")).await;
+            status::Custom(Status::Forbidden, "")
+        },
+        RatchetRotation::Failed(e) => {
+            eprintln!("Ratchet-Pawl key rotation failed, nothing was changed: {:?}", e);
+            status::Custom(Status::InternalServerError, "")
+        },
+    }
+}
+
+/// Frontend API for dumping policy.
+#[get("/getpolicy")]
+async fn get_policy(_admin: RatchetAdmin<RatchetPermView>) -> String {
+    RATCHET_USER_CMD_POLICY.lock().await.policy.clone()
+}
+
+/// Backend API for dumping policy.
+#[get("/api/dumppolicy")]
+async fn api_dump_policy(_valid: RatchetApiCaller<RatchetScopeDumpPolicy>) -> String {
+    RATCHET_USER_CMD_POLICY.lock().await.policy.clone()
+}
+
+/// Backend API for getting user creds.
+#[get("/api/dumpusers")]
+async fn api_dump_users(_valid: RatchetApiCaller<RatchetScopeDumpUsers>) -> String {
+    let users = RATCHET_USERS.lock().await;
+    users.iter().fold(
+        String::new(),
+        |mut resp, (user, hash)| {
+            resp.push_str(user);
+            resp.push_str(",");
+            resp.push_str(&hash.passhash);
+            resp.push_str("\n");
+            resp
+        }
+    )
+}
+
+/// Backend API for getting dev keys.
+#[get("/api/dumpdevs")]
+async fn api_dump_devs(_valid: RatchetApiCaller<RatchetScopeDumpDevs>) -> String {
+    let devs = RATCHET_DEVICES.lock().await;
+    devs.iter().fold(
+        String::new(),
+        |mut resp, (network, key)| {
+            resp.push_str(network);
+            resp.push_str(",");
+            resp.push_str(&key.key);
+            resp.push_str("\n");
+            resp
+        }
+    )
+}
+
+/// Backend API for signalling updates.
+/// 
+/// A poller whose key is revoked while it waits is woken with everyone else,
+/// and turned away rather than told to fetch; same once its key expires.
+#[get("/api/longpoll?<serial>")]
+async fn api_long_poll(valid: RatchetApiCaller<RatchetScopePoll>, serial: Option<u64>) -> status::Custom<String> {
+    // Serial number mismatch
+    let latest = LONG_POLL_EPOCH.load(Ordering::Relaxed);
+    if let Some(sn) = serial  { // Option retains compatibility with legacy ratchet
+        if sn != latest { return status::Custom(Status::Ok, format!("Update {}", latest)); } // output
+    }
+
+    // Normal path / waiting
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut pins = RATCHET_POLL_PINS.lock().await;
+        pins.push(tx);
+    } // drop the pins
+
+    let woken = match valid.expires {
+        Some(e) => {
+            let left = std::time::Duration::from_secs(e.saturating_sub(rtp_unix_now()));
+            rocket::tokio::time::timeout(left, rx).await.ok()
+        },
+        None => Some(rx.await),
+    };
+    let kept = match &valid.key {
+        Some((prefix, hash)) => RATCHET_APIKEYS.lock().await.get(prefix).is_some_and(|k| k.hash == *hash),
+        None => true,
+    };
+    match woken {
+        Some(Ok(_v)) if kept => {
+            status::Custom(Status::Ok, format!("Update {}", latest))
+        },
+        Some(Err(_)) => status::Custom(Status::Ok, String::from("")),
+        _ => status::Custom(Status::Forbidden, String::from("Revoked")),
+    }
+}
+
+#[catch(401)]
+fn unauth(_req: &Request) -> String {
+    format!("Unauthorized")
+}
+#[catch(403)]
+fn forbidden(_req: &Request) -> String {
+    String::from("Forbidden")
+}
+#[catch(404)]
+fn not_found(_req: &Request) -> String {
+    format!("Not Found")
+}
+#[catch(409)]
+fn gone(_req: &Request) -> String {
+    format!("Gone")
+}
+#[catch(410)]
+fn conflict(_req: &Request) -> String {
+    format!("Conflict")
+}
+// #[catch(503)]
+// fn svc_unavailable(_req: &Request) -> String {
+//     format!("Service Unavailable")
+// }
+
+fn main() {
+    rtp_take_env_keys();
+    // lock all allocations
+    #[cfg(not(debug_assertions))]
+    {
+        let result = unsafe { mlockall(MCL_CURRENT | MCL_FUTURE | MCL_ONFAULT) };
+        if result != 0 {
+            eprintln!("mlockall failed with error code: {}", result);
+        } else {
+            println!("mlockall succeeded");
+        }
+    }
+    match rtp_command().as_deref() {
+        None => (),
+        Some("test-events") => std::process::exit(rtp_cli_test_events()),
+        Some(_) if *RATCHET_MEMORY_STORAGE => {
+            eprintln!("Ratchet-Pawl commands work on the database file, unset RATCHET_PAWL_STORAGE.");
+            std::process::exit(2);
+        },
+        Some("rotate-key") => std::process::exit(rtp_cli_rotate_key()),
+        Some("split-key") => std::process::exit(rtp_cli_split_key()),
+        Some("check") => std::process::exit(rtp_cli_check()),
+        Some("verify-audit") => std::process::exit(rtp_cli_verify_audit()),
+        Some("backup") => std::process::exit(rtp_cli_backup()),
+        Some("restore") => std::process::exit(rtp_cli_restore()),
+        Some(other) => {
+            eprintln!("Unknown command: {}", other);
+            eprintln!("Usage: ratchet-pawl [--data-dir DIR] [rotate-key | split-key --shares N --threshold K | check [--db FILE] [--repair] | verify-audit [--db FILE] | test-events | backup --recipient age1... --out FILE | restore --in FILE --identity FILE [--dry-run]]");
+            std::process::exit(2);
+        },
+    }
+    if rtp_env_key("RATCHET_PAWL_WEB") == "off" && rtp_env_key("RATCHET_PAWL_API_SOCKET").is_empty() && rtp_env_key("RATCHET_PAWL_CONTROL_SOCKET").is_empty() {
+        eprintln!("Ratchet-Pawl with RATCHET_PAWL_WEB=off needs RATCHET_PAWL_API_SOCKET or RATCHET_PAWL_CONTROL_SOCKET, or there's nothing to serve.");
+        std::process::exit(2);
+    }
+    rtp_lockout_policy();
+    if let Err(e) = rtp_start_events() {
+        eprintln!("Ratchet-Pawl unable to forward events: {}", e);
+        std::process::exit(2);
+    }
+    // https://github.com/rwf2/Rocket/issues/1881 👍👍👍
+    rocket::execute(async move {
+            let unlocked = match *RATCHET_MEMORY_STORAGE {
+                true => rtp_init_memory_store().map(|_| RatchetUnlock::Unlocked),
+                false => rtp_force_db_init().await,
+            };
+            let unlocked = match unlocked {
+                Ok(u) => u,
+                Err(e) => {
+                    eprintln!("Ratchet-Pawl unable to open the database: {:?}", e);
+                    std::process::exit(1);
+                },
+            };
+            if let RatchetUnlock::Sealed(sealed) = unlocked {
+                println!("Ratchet-Pawl is sealed, waiting for {} key shares.", sealed.threshold);
+                let _ = rtp_sealed_rocket(sealed)
+                .launch()
+                .await;
+                if !RATCHET_UNSEALED.load(Ordering::SeqCst) { return; }
+                println!("Ratchet-Pawl unsealed.");
+            }
+            let web = match rocket().await {
+                Ok(web) => web,
+                Err(e) => {
+                    eprintln!("Ratchet-Pawl unable to start: {}", e);
+                    std::process::exit(1);
+                },
+            };
+            let mut sockets = vec![];
+            for serve in [rtp_serve_api_socket().await, rtp_serve_control_socket().await] {
+                match serve {
+                    Ok(socket) => sockets.extend(socket),
+                    Err(e) => {
+                        eprintln!("Ratchet-Pawl unable to serve a socket: {}", e);
+                        std::process::exit(1);
+                    },
+                }
+            }
+            if rtp_env_key("RATCHET_PAWL_WEB") == "off" {
+                println!("Ratchet-Pawl web listener is off.");
+                rtp_wait_for_shutdown().await;
+            } else {
+                let _ = web.launch().await;
+            }
+            for path in sockets {
+                let _ = std::fs::remove_file(path);
+            }
+        });
+}
+
+/// Loads everything and gets the web frontend ready; what stops it from
+/// starting is for the caller to report.
+async fn rocket() -> Result<Rocket<Build>, String> {
+    if !*RATCHET_MEMORY_STORAGE {
+        match rtp_sweep_database(&rtp_db()) {
+            Ok(()) => (),
+            Err(RatchetStoreError::WrongKey) => {
+                eprintln!("Ratchet-Pawl refusing to start: the masking key does not open this database. Nothing was changed.");
+                std::process::exit(1);
+            },
+            Err(e) => return Err(format!("Error checking database: {:?}", e)),
+        }
+        rtp_migrate_database(&rtp_db()).map_err(|e| format!("Error migrating database: {:?}", e))?;
+    }
+    rtp_import_database().await.map_err(|e| format!("Error importing database: {:?}", e))?;
+    
+    initialize_first_admin().await.map_err(|e| format!("Error initializing first admin: {:?}", e))?;
+    initialize_user_cmd_pol().await.map_err(|e| format!("Error initializing user cmd policy: {:?}", e))?;
+    initialize_api_key().await.map_err(|e| format!("Error initializing API key: {:?}", e))?;
+
+    rt_generate_gutter().await;
+    if !*RATCHET_MEMORY_STORAGE {
+        rtp_start_maintenance();
+    }
+    if !RATCHET_AUDIT_REPEAT.is_zero() {
+        rocket::tokio::spawn(rtp_flush_audit_repeats());
+    }
+    Ok(rtp_web_rocket())
+}
+
+/// Everything the web frontend is served, and the API with a key.
+fn rtp_web_rocket() -> Rocket<Build> {
+    rocket::build()
+        .mount("/", rocket::routes![try_login, logged, whoami, hangup])
+        .mount("/", rocket::routes![api_dump_devs, api_dump_users, api_dump_policy, api_long_poll, api_metrics])
+        .mount("/", rocket::routes![rm_user, edit_user, add_user, get_users])
+        .mount("/", rocket::routes![rm_admin, edit_admin, add_admin, get_admins])
+        .mount("/", rocket::routes![rm_dev, edit_dev, add_dev, get_devs])
+        .mount("/", rocket::routes![add_api_key, get_api_keys, rm_api_key])
+        .mount("/",rocket::routes![get_policy, push_policy, validate_policy])
+        .mount("/", rocket::routes![get_sessions, rm_sessions, get_lockouts, unlock_login])
+        .mount("/", rocket::routes![rotate_key, get_quarantine, rm_quarantine])
+        .mount("/", rocket::routes![backup, restore])
+        .mount("/", rocket::routes![get_journal, get_history, rollback])
+        .mount("/", rocket::routes![get_audit])
+        .mount("/", FileServer::from(relative!("pawl-js/build/")))
+        .register("/", catchers![not_found, gone, unauth, forbidden, conflict])
+}
+
+/// Marks the Rocket behind the API socket, whose callers are let in by who
+/// they are rather than by a key, see rtp_serve_api_socket.
+struct RatchetApiSocket;
+
+/// Marks the Rocket behind the control socket, whose callers are root, see
+/// rtp_serve_control_socket.
+struct RatchetControlSocket;
+
+/// The loopback ends of the connections a socket is forwarding to its
+/// Rocket, see rtp_serve_unix. Anyone else who finds the port isn't let in.
+#[derive(Default)]
+struct RatchetSocketPeers(std::sync::Mutex<HashSet<std::net::SocketAddr>>);
+
+impl RatchetSocketPeers {
+    fn forwarded(req: &Request<'_>) -> bool {
+        match (req.rocket().state::<Arc<RatchetSocketPeers>>(), req.remote()) {
+            (Some(peers), Some(remote)) => peers.0.lock().is_ok_and(|p| p.contains(&remote)),
+            _ => false,
+        }
+    }
+}
+
+/// Who may connect to a socket, by SO_PEERCRED: listed uids, or a listed
+/// primary gid. With neither, only the user pawl runs as.
+struct RatchetPeerAllowlist {
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+}
+
+impl RatchetPeerAllowlist {
+    /// RATCHET_PAWL_API_SOCKET_UIDS and RATCHET_PAWL_API_SOCKET_GIDS.
+    fn from_env() -> Result<RatchetPeerAllowlist, String> {
+        let ids = |var: &str| -> Result<Vec<u32>, String> {
+            rtp_env_key(var).split(',')
+                .map(|id| id.trim())
+                .filter(|id| !id.is_empty())
+                .map(|id| id.parse::<u32>().map_err(|_| format!("{} has a bad id: {}", var, id)))
+                .collect()
+        };
+        Ok(RatchetPeerAllowlist {
+            uids: ids("RATCHET_PAWL_API_SOCKET_UIDS")?,
+            gids: ids("RATCHET_PAWL_API_SOCKET_GIDS")?,
+        })
+    }
+
+    fn allows(&self, uid: u32, gid: u32) -> bool {
+        if self.uids.is_empty() && self.gids.is_empty() {
+            return uid == unsafe { libc::getuid() };
+        }
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+/// A socket's Rocket listens on loopback, on whatever port it gets, for
+/// rtp_serve_unix to forward to; nothing about it comes from ROCKET_*.
+fn rtp_socket_config() -> rocket::Config {
+    rocket::Config {
+        address: std::net::Ipv4Addr::LOCALHOST.into(),
+        port: 0,
+        ip_header: None,
+        log_level: rocket::config::LogLevel::Off,
+        ..rocket::Config::default()
+    }
+}
+
+/// Only the `api_*` routes, for the API socket.
+fn rtp_api_socket_rocket() -> Rocket<Build> {
+    rocket::custom(rtp_socket_config())
+        .manage(RatchetApiSocket)
+        .mount("/", rocket::routes![api_dump_devs, api_dump_users, api_dump_policy, api_long_poll, api_metrics])
+        .register("/", catchers![not_found, gone, unauth, forbidden, conflict])
+}
+
+/// The admin routes pawlctl uses, for the control socket.
+fn rtp_control_socket_rocket() -> Rocket<Build> {
+    rocket::custom(rtp_socket_config())
+        .manage(RatchetControlSocket)
+        .mount("/", rocket::routes![rm_user, edit_user, add_user, get_users])
+        .mount("/", rocket::routes![rm_admin, edit_admin, add_admin, get_admins])
+        .mount("/", rocket::routes![rm_dev, edit_dev, add_dev, get_devs])
+        .mount("/", rocket::routes![get_policy, push_policy, validate_policy])
+        .mount("/", rocket::routes![add_api_key, get_api_keys, rm_api_key])
+        .mount("/", rocket::routes![get_sessions, rm_sessions, get_lockouts, unlock_login])
+        .mount("/", rocket::routes![get_audit])
+        .register("/", catchers![not_found, gone, unauth, forbidden, conflict])
+}
+
+/// Serves the `api_*` routes on RATCHET_PAWL_API_SOCKET as well, if it's set,
+/// for a ratchet on the same host. The socket's mode, RATCHET_PAWL_API_SOCKET_MODE
+/// (octal, default 660), is the first check and the peer allowlist the second;
+/// callers that pass both need no API key.
+async fn rtp_serve_api_socket() -> Result<Option<std::path::PathBuf>, String> {
+    let path = match rtp_env_key("RATCHET_PAWL_API_SOCKET") {
+        p if !p.is_empty() => std::path::PathBuf::from(p),
+        _ => return Ok(None),
+    };
+    let allowed = RatchetPeerAllowlist::from_env()?;
+    let mode = match rtp_env_key("RATCHET_PAWL_API_SOCKET_MODE") {
+        m if !m.is_empty() => u32::from_str_radix(&m, 8).map_err(|_| format!("RATCHET_PAWL_API_SOCKET_MODE is not octal: {}", m))?,
+        _ => 0o660,
+    };
+    rtp_serve_unix(&path, mode, allowed, rtp_api_socket_rocket()).await?;
+    println!("Ratchet-Pawl serving the API on {}", path.display());
+    Ok(Some(path))
+}
+
+/// Serves pawlctl on RATCHET_PAWL_CONTROL_SOCKET, if it's set: owner-only,
+/// and only root gets past the peer check. Requests there go through the
+/// same handlers as the web's, as `pawlctl`.
+async fn rtp_serve_control_socket() -> Result<Option<std::path::PathBuf>, String> {
+    let path = match rtp_env_key("RATCHET_PAWL_CONTROL_SOCKET") {
+        p if !p.is_empty() => std::path::PathBuf::from(p),
+        _ => return Ok(None),
+    };
+    let root = RatchetPeerAllowlist { uids: vec![0], gids: vec![] };
+    rtp_serve_unix(&path, 0o600, root, rtp_control_socket_rocket()).await?;
+    println!("Ratchet-Pawl serving pawlctl on {}", path.display());
+    Ok(Some(path))
+}
+
+const RATCHET_ACCEPT_BACKOFF_MIN: std::time::Duration = std::time::Duration::from_millis(50);
+const RATCHET_ACCEPT_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Serves `rocket` on a Unix socket at `path`, to peers `allowed` lets in.
+/// Rocket only listens on TCP, so it's launched on loopback and each
+/// connection let in is forwarded to it, byte for byte.
+async fn rtp_serve_unix(path: &std::path::Path, mode: u32, allowed: RatchetPeerAllowlist, rocket: Rocket<Build>) -> Result<(), String> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let peers = Arc::new(RatchetSocketPeers::default());
+    let (tx, rx) = oneshot::channel();
+    let rocket = rocket
+        .manage(peers.clone())
+        .attach(rocket::fairing::AdHoc::on_liftoff("Socket port", move |rocket| Box::pin(async move {
+            let _ = tx.send(std::net::SocketAddr::new(rocket.config().address, rocket.config().port));
+        })));
+    let name = path.display().to_string();
+    rocket::tokio::spawn(async move {
+        if let Err(e) = rocket.launch().await {
+            eprintln!("Ratchet-Pawl {} stopped: {}", name, e);
+        }
+    });
+    let upstream = rx.await.map_err(|_| format!("Unable to start serving {}", path.display()))?;
+
+    // one left behind by the last run would fail the bind, anything else is left alone
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path).map_err(|e| format!("Unable to remove old socket {}: {}", path.display(), e))?;
+    }
+    // until the mode is set it's whatever the umask gives, but anyone who
+    // connects in between is still checked against allowed before forwarding
+    let listener = rocket::tokio::net::UnixListener::bind(path).map_err(|e| format!("Unable to bind {}: {}", path.display(), e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(|e| format!("Unable to set mode on {}: {}", path.display(), e))?;
+    let name = path.display().to_string();
+
+    rocket::tokio::spawn(async move {
+        // out of file descriptors and the like fail every accept until it
+        // clears, so back off rather than spin
+        let mut backoff = RATCHET_ACCEPT_BACKOFF_MIN;
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => {
+                    backoff = RATCHET_ACCEPT_BACKOFF_MIN;
+                    stream
+                },
+                Err(e) => {
+                    eprintln!("Ratchet-Pawl {} accept failed, trying again in {:?}: {}", name, backoff, e);
+                    rocket::tokio::time::sleep(backoff).await;
+                    backoff = std::cmp::min(backoff * 2, RATCHET_ACCEPT_BACKOFF_MAX);
+                    continue;
+                },
+            };
+            match stream.peer_cred() {
+                Ok(peer) if allowed.allows(peer.uid(), peer.gid()) => (),
+                Ok(peer) => {
+                    eprintln!("Ratchet-Pawl {} refused uid {} gid {}.", name, peer.uid(), peer.gid());
+                    continue; // dropping it hangs up
+                },
+                Err(e) => {
+                    eprintln!("Ratchet-Pawl {} unable to identify a peer: {}", name, e);
+                    continue;
+                },
+            }
+            let peers = peers.clone();
+            let name = name.clone();
+            rocket::tokio::spawn(async move {
+                let mut forward = match rocket::tokio::net::TcpStream::connect(upstream).await {
+                    Ok(forward) => forward,
+                    Err(e) => {
+                        eprintln!("Ratchet-Pawl {} unable to forward a connection: {}", name, e);
+                        return;
+                    },
+                };
+                let Ok(local) = forward.local_addr() else { return };
+                // before a byte is sent, so its first request is already known
+                if let Ok(mut p) = peers.0.lock() {
+                    p.insert(local);
+                }
+                let _ = rocket::tokio::io::copy_bidirectional(&mut stream, &mut forward).await;
+                if let Ok(mut p) = peers.0.lock() {
+                    p.remove(&local);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// For when the web listener is off, and there's no Rocket to wait on.
+async fn rtp_wait_for_shutdown() {
+    use rocket::tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            rocket::tokio::select! {
+                _ = rocket::tokio::signal::ctrl_c() => (),
+                _ = term.recv() => (),
+            }
+        },
+        Err(_) => { let _ = rocket::tokio::signal::ctrl_c().await; },
+    }
+}
+
+static RATCHET_UNSEALED: AtomicBool = AtomicBool::new(false);
+
+/// The shares held while sealed, all of them ones split-key handed out,
+/// and when each source last sent one.
+struct RatchetUnsealShares {
+    shares: Vec<Vec<u8>>,
+    tries: HashMap<Option<std::net::IpAddr>, Instant>,
+}
+
+lazy_static! {
+    static ref RATCHET_UNSEAL_SHARES: Mutex<RatchetUnsealShares> = Mutex::new(RatchetUnsealShares { shares: vec![], tries: HashMap::new() });
+}
+
+/// Anyone who can reach the port can post a share, so one a second from each.
+const RATCHET_UNSEAL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// While sealed, the only thing pawl serves is the unseal page.
+fn rtp_sealed_rocket(sealed: RatchetSealed) -> Rocket<Build> {
+    rocket::build()
+        .manage(sealed)
+        .mount("/", rocket::routes![unseal_page, unseal])
+}
+
+/// How many shares unseal the database, and the salt and share checks
+/// to tell them by, see RatchetKdfParams::share_check.
+struct RatchetSealed {
+    threshold: usize,
+    kdf: RatchetKdfParams,
+}
+
+impl RatchetSealed {
+    /// Whether this is one of the shares split-key handed out.
+    fn holds(&self, share: &[u8]) -> bool {
+        share.first()
+             .and_then(|x| self.kdf.share_checks.get(x))
+             .is_some_and(|check| *check == self.kdf.share_check(share))
+    }
+}
+
+#[get("/")]
+fn unseal_page() -> RawHtml<&'static str> {
+    RawHtml(r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>ratchet-pawl (sealed)</title></head>
+<body>
+<h1>ratchet-pawl is sealed</h1>
+<p>Submit key shares until the threshold is reached.</p>
+<form method="post" action="/unseal" enctype="multipart/form-data">
+<input type="password" name="share" autocomplete="off" size="80">
+<button type="submit">Unseal</button>
+</form>
+</body>
+</html>"#)
+}
+
+/// Collects shares until there are enough, then checks the recovered key
+/// against the database. Only the shares split-key handed out are held, so
+/// nobody else can take a share number or fill up the pool; if the ones held
+/// still don't unseal the database they're all dropped, to start over.
+#[post("/unseal", format = "multipart/form-data", data = "<share>")]
+async fn unseal(share: Form<String>, source_ip: Option<std::net::IpAddr>, sealed: &rocket::State<RatchetSealed>, shutdown: Shutdown) -> status::Custom<String> {
+    let threshold = sealed.threshold;
+    let mut held = RATCHET_UNSEAL_SHARES.lock().await;
+    if RATCHET_UNSEALED.load(Ordering::SeqCst) {
+        return status::Custom(Status::Ok, String::from("Unsealed"));
+    }
+    held.tries.retain(|_, last| last.elapsed() < RATCHET_UNSEAL_INTERVAL);
+    if held.tries.contains_key(&source_ip) {
+        return status::Custom(Status::TooManyRequests, String::from("Too soon, try again in a second"));
+    }
+    held.tries.insert(source_ip, Instant::now());
+    let share = match hex::decode(share.trim()).ok().filter(|b| Share::try_from(b.as_slice()).is_ok()) {
+        Some(b) if sealed.holds(&b) => b,
+        _ => return status::Custom(Status::Conflict, String::from("That isn't one of this database's key shares")),
+    };
+    if held.shares.iter().any(|s| s[0] == share[0]) {
+        return status::Custom(Status::Conflict, format!("Already have share {}", share[0]));
+    }
+    held.shares.push(share);
+    if held.shares.len() < threshold {
+        return status::Custom(Status::Ok, format!("Received {} of {} shares", held.shares.len(), threshold));
+    }
+
+    let shares = std::mem::take(&mut held.shares);
+    let key = rocket::tokio::task::spawn_blocking(move || rtp_unseal_key(&rtp_db(), threshold, &shares)).await;
+    match key {
+        Ok(Ok(k)) => {
+            rtp_take_key(k);
+            RATCHET_UNSEALED.store(true, Ordering::SeqCst);
+            shutdown.notify(); // and come back up with the real routes
+            status::Custom(Status::Ok, String::from("Unsealed"))
+        },
+        Ok(Err(e)) => {
+            eprintln!("Ratchet-Pawl unseal attempt failed: {:?}", e);
+            status::Custom(Status::Conflict, String::from("Those shares don't unseal the database, they were all dropped; start over"))
+        },
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unseal attempt failed: {:?}", e);
+            status::Custom(Status::InternalServerError, String::from("Unable to unseal, the shares were all dropped; start over"))
+        },
+    }
+}
+
+/// The database key from the shares, checked against the database.
+fn rtp_unseal_key(db: &Database, threshold: usize, shares: &[Vec<u8>]) -> Result<[u8; 32], RatchetStoreError> {
+    let shares: Vec<String> = shares.iter().map(hex::encode).collect();
+    let masking_key = rtp_recover_masking_key(threshold as u8, &shares).map_err(RatchetStoreError::Format)?;
+    let key = rtp_current_key(db, &masking_key)?;
+    rtp_check_key(db, &key)?;
+    Ok(key)
+}
+
+async fn rt_generate_gutter() {
+    let mut g = GUTTER.write().await;
+    g.push_str(&bcrypt::hash(rt_generate_gutter_string()).expect("Ratchet Fatal: Unable to generate gutter"));
+}
+
+fn rt_generate_gutter_string() -> String { 
+    (0..72).fold(
+        String::with_capacity(72),
+        |mut s, _| {
+            loop {
+                let c = rand::random::<u8>();
+                if c.is_ascii_alphanumeric() || c.is_ascii_graphic() || c.is_ascii_punctuation() {
+                    s.push(c as char);
+                    break;
+                }
+            }
+            s
+        }
+    )
+}
+
+/// Schema version 7 made this an object, with its stamp; it was the bare
+/// policy text, which is still read, for old journal entries and backups.
+#[derive(Clone, FromForm, Debug, Serialize, Deserialize)]
+#[serde(from = "RatchetUserCmdPolicyRow")]
+struct RatchetUserCmdPolicy {
+    #[field(name = "0")]
+    policy: String,
+    #[serde(flatten)]
+    #[field(default = <RatchetStamp as Default>::default())]
+    stamp: RatchetStamp,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RatchetUserCmdPolicyRow {
+    Bare(String),
+    Stamped {
+        policy: String,
+        #[serde(flatten)]
+        stamp: RatchetStamp,
+    },
+}
+
+impl From<RatchetUserCmdPolicyRow> for RatchetUserCmdPolicy {
+    fn from(row: RatchetUserCmdPolicyRow) -> RatchetUserCmdPolicy {
+        match row {
+            RatchetUserCmdPolicyRow::Bare(policy) => RatchetUserCmdPolicy::new(policy, RatchetStamp::default()),
+            RatchetUserCmdPolicyRow::Stamped { policy, stamp } => RatchetUserCmdPolicy::new(policy, stamp),
+        }
+    }
+}
+
+impl RatchetUserCmdPolicy {
+    fn new(policy: String, stamp: RatchetStamp) -> RatchetUserCmdPolicy {
+        RatchetUserCmdPolicy { policy, stamp }
+    }
+}
+impl RatchetKeyed for RatchetUserCmdPolicy {
+    fn into_key<'k>(&self) -> &str {
+        "singleton"
+    }
+}
+
+/// An invariant that is largely maintained throughout is that
+/// there is at least one user who can administer ratchet in the database.
+async fn initialize_user_cmd_pol() -> Result<(), RatchetStoreError> {
+    let mut user_cmd_policy_init = RATCHET_USER_CMD_POLICY.lock().await;
+    if user_cmd_policy_init.policy.is_empty() {
+        *user_cmd_policy_init = RatchetUserCmdPolicy::new(String::from(RATCHET_EMPTY_POLICY), RatchetStamp::new(RATCHET_SYSTEM_ACTOR));
+        RATCHET_USER_CMD_POLICY_TABLE.write(&user_cmd_policy_init, RATCHET_SYSTEM_ACTOR).await?;
+    }
+    Ok(())
+}
+
+/// An invariant that is largely maintained throughout is that
+/// there is at least one superadmin who can administer ratchet in the database.
+async fn initialize_first_admin() -> Result<(), RatchetStoreError> {
+    let mut admins_init = RATCHET_ADMINS.lock().await;
+    if !admins_init.values().any(|a| a.role == RatchetRole::Superadmin) {
+        let mut pass: String = String::with_capacity(16);
+        while pass.len() < 16 {
+            let c = rand::random::<u8>();
+            if c.is_ascii_alphanumeric() || c.is_ascii_graphic() || c.is_ascii_punctuation() {
+                pass.push(c as char);
+            }
+        }
+        println!("Ratchet-Pawl Initialization creating initial admin with details:");
+        println!("Username: {}", RATCHET_DEFAULT_ADMIN);
+        println!("Password: {}", pass);
+        let init_admin = RatchetAdminEntry {
+            username: String::from(RATCHET_DEFAULT_ADMIN),
+            passhash: bcrypt::hash(pass).expect("unable to initialize password"),
+            role: RatchetRole::Superadmin,
+            stamp: RatchetStamp::new(RATCHET_SYSTEM_ACTOR),
+        };
+        RATCHET_ADMINS_TABLE.write(&init_admin, RATCHET_SYSTEM_ACTOR).await?;
+        admins_init.insert(init_admin.username.clone(), init_admin);
+    }
+    Ok(())
+}
+
+/// redb doesn't write any tables until you open them.
+/// this ensures that the needed tables exist in the
+/// database.
+async fn rtp_force_db_init() -> Result<RatchetUnlock, RatchetStoreError> {
+    let db = &rtp_db();
+
+    let write_txn = db.begin_write()?;
+    {
+        // write initializes tables, tables must be written before they are initialized
+        write_txn.open_table(RATCHET_USERS_TABLE.unwrap())?;
+        write_txn.open_table(RATCHET_DEVS_TABLE.unwrap())?;
+        write_txn.open_table(RATCHET_USER_CMD_POLICY_TABLE.unwrap())?;
+        write_txn.open_table(RATCHET_APIKEY_TABLE.unwrap())?;
+        write_txn.open_table(RATCHET_ADMINS_TABLE.unwrap())?;
+        write_txn.open_table(RATCHET_META_TABLE)?;
+        // reading an empty table is a panic.
+    }
+    write_txn.commit()?;
+
+    rtp_key_provider()?.unlock(db)
+}
+
+/// Gets the database key at startup, moving the database onto
+/// itself first if it was sealed some other way.
+trait RatchetKeyProvider {
+    fn unlock(&self, db: &Database) -> Result<RatchetUnlock, RatchetStoreError>;
+}
+
+/// RATCHET_PAWL_KEY_PROVIDER picks one, the masking key is the default.
+fn rtp_key_provider() -> Result<Box<dyn RatchetKeyProvider>, RatchetStoreError> {
+    match rtp_env_key("RATCHET_PAWL_KEY_PROVIDER").as_str() {
+        "" | "passphrase" => Ok(Box::new(RatchetPassphraseKeys)),
+        #[cfg(feature = "pkcs11")]
+        "pkcs11" => Ok(Box::new(pkcs11::RatchetPkcs11Keys::from_env()?)),
+        other => Err(RatchetStoreError::KeyProvider(format!("unknown key provider {}", other))),
+    }
+}
+
+/// The database key is derived from a masking key, see RATCHET_MASKING_KEY_SOURCE,
+/// or recovered from Shamir shares while sealed.
+struct RatchetPassphraseKeys;
+
+impl RatchetKeyProvider for RatchetPassphraseKeys {
+    fn unlock(&self, db: &Database) -> Result<RatchetUnlock, RatchetStoreError> {
+        if rtp_read_meta(db, RATCHET_META_WRAPPED_KEY)?.is_some() {
+            return Err(RatchetStoreError::KeyProvider(String::from("the database key is wrapped, set RATCHET_PAWL_KEY_PROVIDER")));
+        }
+        if let Some(kdf @ RatchetKdfParams { shamir_threshold: Some(threshold), .. }) = rtp_read_kdf(db)? {
+            // nobody holds the whole key, wait for the shares.
+            return Ok(RatchetUnlock::Sealed(RatchetSealed { threshold: threshold as usize, kdf }));
+        }
+
+        let selected_key = match RATCHET_MASKING_KEY_SOURCE.select() {
+            Ok(k) => k,
+            Err(e) => return Err(RatchetStoreError::KeyProvider(format!("{} Please use a key file (RATCHET_PAWL_MASKING_KEY_FILE), a systemd credential ({}), or the environment var RATCHET_PAWL_MASKING_KEY, to specify a database encryption key.", e, RATCHET_MASKING_KEY_SOURCE.credential))),
+        };
+
+        rtp_masking_key_unlock(db, &selected_key)?;
+        Ok(RatchetUnlock::Unlocked)
+    }
+}
+
+/// Takes the key derived from the masking key. A database from before the
+/// KDF is moved onto one first, less any rows that won't open, which go to
+/// quarantine instead of stopping the move.
+fn rtp_masking_key_unlock(db: &Database, masking_key: &String) -> Result<(), RatchetStoreError> {
+    match rtp_read_kdf(db)? {
+        Some(kdf) => rtp_take_key(kdf.derive(masking_key.as_bytes())?),
+        None => {
+            // new, or from before the KDF; either way re-seal under a derived key.
+            let legacy = rtp_legacy_key(masking_key);
+            rtp_quarantine_unreadable(db, &legacy)?;
+            let kdf = RatchetKdfParams::generate();
+            let new = kdf.derive(masking_key.as_bytes())?;
+            let n = rtp_rotate_key(db, &legacy, &new, &RatchetKeyRecord::Kdf(kdf.clone()))?;
+            if n > 0 {
+                println!("Ratchet-Pawl upgraded {} records to a {:?} derived key.", n, kdf.kdf);
+            }
+        },
+    }
+    Ok(())
+}
+
+/// The database key is random, and only ever stored wrapped by a secret key
+/// that lives on a PKCS#11 token (RATCHET_PAWL_KEY_PROVIDER=pkcs11).
+/// 
+/// - RATCHET_PAWL_PKCS11_MODULE, path to the module, e.g. libsofthsm2.so
+/// - RATCHET_PAWL_PKCS11_SLOT, slot id, otherwise the first slot with a token
+/// - RATCHET_PAWL_PKCS11_KEY_LABEL, the AES key to wrap with, "ratchet-pawl-kek"
+/// - the user PIN from the same kinds of sources as the masking key
+#[cfg(feature = "pkcs11")]
+mod pkcs11 {
+    use super::*;
+    use cryptoki::context::{CInitializeArgs, Pkcs11};
+    use cryptoki::mechanism::{aead::GcmParams, Mechanism};
+    use cryptoki::object::{Attribute, ObjectClass, ObjectHandle};
+    use cryptoki::session::{Session, UserType};
+    use cryptoki::slot::Slot;
+    use cryptoki::types::AuthPin;
+
+    const RATCHET_PKCS11_PIN_SOURCE: RatchetKeySource = RatchetKeySource {
+        env: "RATCHET_PAWL_PKCS11_PIN",
+        file_env: "RATCHET_PAWL_PKCS11_PIN_FILE",
+        credential: "ratchet-pawl-pkcs11-pin",
+        prompt: "PKCS#11 PIN: ",
+    };
+    const RATCHET_PKCS11_WRAP_AAD: &[u8] = b"ratchet-pawl database key";
+
+    /// Holds the one context on the module, it's finalized when this drops.
+    pub struct RatchetPkcs11Keys {
+        ctx: Pkcs11,
+        slot: Option<u64>,
+        key_label: String,
+    }
+
+    fn rtp_token_error(e: cryptoki::error::Error) -> RatchetStoreError {
+        RatchetStoreError::KeyProvider(e.to_string())
+    }
+
+    impl RatchetPkcs11Keys {
+        pub fn from_env() -> Result<RatchetPkcs11Keys, RatchetStoreError> {
+            let module = rtp_env_key("RATCHET_PAWL_PKCS11_MODULE");
+            if module.is_empty() {
+                return Err(RatchetStoreError::KeyProvider(String::from("RATCHET_PAWL_PKCS11_MODULE is not set")));
+            }
+            let slot = match rtp_env_key("RATCHET_PAWL_PKCS11_SLOT").as_str() {
+                "" => None,
+                s => Some(s.parse::<u64>().map_err(|_| RatchetStoreError::KeyProvider(format!("bad slot id {}", s)))?),
+            };
+            let key_label = match rtp_env_key("RATCHET_PAWL_PKCS11_KEY_LABEL").as_str() {
+                "" => String::from("ratchet-pawl-kek"),
+                l => l.to_string(),
+            };
+            let ctx = Pkcs11::new(&module).map_err(rtp_token_error)?;
+            ctx.initialize(CInitializeArgs::OsThreads).map_err(rtp_token_error)?;
+            Ok(RatchetPkcs11Keys { ctx, slot, key_label })
+        }
+
+        /// Logged-in session, and the wrapping key on the token.
+        fn open(&self) -> Result<(Session, ObjectHandle), RatchetStoreError> {
+            let slot = match self.slot {
+                Some(id) => Slot::try_from(id).map_err(rtp_token_error)?,
+                None => *self.ctx.get_slots_with_token().map_err(rtp_token_error)?
+                            .first()
+                            .ok_or(RatchetStoreError::KeyProvider(String::from("no PKCS#11 token present")))?,
+            };
+            let session = self.ctx.open_ro_session(slot).map_err(rtp_token_error)?;
+            let pin = RATCHET_PKCS11_PIN_SOURCE.select().map_err(RatchetStoreError::KeyProvider)?;
+            session.login(UserType::User, Some(&AuthPin::new(pin))).map_err(rtp_token_error)?;
+
+            let template = [
+                Attribute::Class(ObjectClass::SECRET_KEY),
+                Attribute::Label(self.key_label.as_bytes().to_vec()),
+            ];
+            let kek = *session.find_objects(&template).map_err(rtp_token_error)?
+                              .first()
+                              .ok_or(RatchetStoreError::KeyProvider(format!("no secret key labelled {} on the token", self.key_label)))?;
+            Ok((session, kek))
+        }
+
+        /// Layout is `iv | ciphertext+tag`, AES-GCM on the token.
+        fn wrap(session: &Session, kek: ObjectHandle, key: &[u8; 32]) -> Result<Vec<u8>, RatchetStoreError> {
+            let iv = rand::random::<[u8; 12]>();
+            let mech = Mechanism::AesGcm(GcmParams::new(&iv, RATCHET_PKCS11_WRAP_AAD, 128.into()));
+            let ct = session.encrypt(&mech, kek, key).map_err(rtp_token_error)?;
+            let mut out = iv.to_vec();
+            out.extend_from_slice(&ct);
+            Ok(out)
+        }
+
+        fn unwrap(session: &Session, kek: ObjectHandle, wrapped: &[u8]) -> Result<[u8; 32], RatchetStoreError> {
+            if wrapped.len() < 12 {
+                return Err(RatchetStoreError::Crypto);
+            }
+            let (iv, ct) = wrapped.split_at(12);
+            let mech = Mechanism::AesGcm(GcmParams::new(iv, RATCHET_PKCS11_WRAP_AAD, 128.into()));
+            let pt = session.decrypt(&mech, kek, ct).map_err(|_| RatchetStoreError::Crypto)?;
+            <[u8; 32]>::try_from(pt.as_slice()).map_err(|_| RatchetStoreError::Crypto)
+        }
+    }
+
+    impl RatchetKeyProvider for RatchetPkcs11Keys {
+        fn unlock(&self, db: &Database) -> Result<RatchetUnlock, RatchetStoreError> {
+            let (session, kek) = self.open()?;
+            match rtp_read_meta(db, RATCHET_META_WRAPPED_KEY)? {
+                Some(wrapped) => rtp_take_key(RatchetPkcs11Keys::unwrap(&session, kek, &wrapped)?),
+                None => {
+                    // new, or moving off a masking key, which is needed one last time.
+                    let old = match rtp_ever_sealed(db)? {
+                        false => [0; 32],
+                        true => {
+                            let masking_key = rtp_cli_masking_key(db).map_err(RatchetStoreError::KeyProvider)?;
+                            let old = rtp_current_key(db, &masking_key)?;
+                            rtp_quarantine_unreadable(db, &old)?;
+                            old
+                        },
+                    };
+                    let new = rand::random::<[u8; 32]>();
+                    let wrapped = RatchetPkcs11Keys::wrap(&session, kek, &new)?;
+                    let n = rtp_rotate_key(db, &old, &new, &RatchetKeyRecord::Wrapped(wrapped))?;
+                    if n > 0 {
+                        println!("Ratchet-Pawl moved {} records onto a token-wrapped key.", n);
+                    }
+                },
+            }
+            let _ = session.logout();
+            Ok(RatchetUnlock::Unlocked)
+        }
+    }
+}
+
+enum RatchetUnlock {
+    Unlocked,
+    /// Started sealed, waiting for shares.
+    Sealed(RatchetSealed),
+}
+
+fn rtp_env_key(var: &str) -> String {
+    for (k, v) in env::vars() {
+        // something like this appears to have ok support from systemd
+        if k == var { return v; }
+    }
+    "".to_string()
+}
+
+/// Where a masking key can come from, most preferred first: a key file,
+/// a systemd credential (`LoadCredential=`), the environment, and
+/// finally a prompt if someone is at the terminal.
+struct RatchetKeySource {
+    env: &'static str,
+    file_env: &'static str,
+    credential: &'static str,
+    prompt: &'static str,
+}
+
+const RATCHET_MASKING_KEY_SOURCE: RatchetKeySource = RatchetKeySource {
+    env: "RATCHET_PAWL_MASKING_KEY",
+    file_env: "RATCHET_PAWL_MASKING_KEY_FILE",
+    credential: "ratchet-pawl-masking-key",
+    prompt: "Masking key: ",
+};
+const RATCHET_NEW_MASKING_KEY_SOURCE: RatchetKeySource = RatchetKeySource {
+    env: "RATCHET_PAWL_NEW_MASKING_KEY",
+    file_env: "RATCHET_PAWL_NEW_MASKING_KEY_FILE",
+    credential: "ratchet-pawl-new-masking-key",
+    prompt: "New masking key: ",
+};
+
+impl RatchetKeySource {
+    fn select(&self) -> Result<String, String> {
+        // the env var was scrubbed in main, no matter which source wins
+        let from_env = rtp_taken_env_key(self.env);
+
+        let path = rtp_env_key(self.file_env);
+        if !path.is_empty() {
+            return rtp_read_key_file(std::path::Path::new(&path), true);
+        }
+
+        let creds = rtp_env_key("CREDENTIALS_DIRECTORY");
+        if !creds.is_empty() {
+            let path = std::path::Path::new(&creds).join(self.credential);
+            if path.exists() {
+                // systemd already locks these down to the service
+                return rtp_read_key_file(&path, false);
+            }
+        }
+
+        if !from_env.is_empty() {
+            return Ok(from_env);
+        }
+
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+            return rtp_prompt_key(self.prompt);
+        }
+        Err(String::from("No masking key available."))
+    }
+}
+
+/// Every variable that can carry a key or PIN, see rtp_take_env_keys.
+const RATCHET_SECRET_ENV: [&str; 3] = ["RATCHET_PAWL_MASKING_KEY", "RATCHET_PAWL_NEW_MASKING_KEY", "RATCHET_PAWL_PKCS11_PIN"];
+
+/// What rtp_take_env_keys took out of the environment.
+static RATCHET_TAKEN_ENV: std::sync::OnceLock<HashMap<&'static str, String>> = std::sync::OnceLock::new();
+
+/// Reads the secret variables and removes them from the process, including
+/// from the original environment block that `/proc/<pid>/environ` reads from.
+/// Only call this first thing in main, while pawl is still single-threaded.
+fn rtp_take_env_keys() {
+    let mut taken = HashMap::new();
+    for var in RATCHET_SECRET_ENV {
+        let v = rtp_env_key(var);
+        if v.is_empty() {
+            continue;
+        }
+        if let Ok(name) = std::ffi::CString::new(var) {
+            // SAFETY: getenv points into the environment block, we only overwrite
+            // the value bytes in place, up to its own terminator, then unset it.
+            // main calls this before any other thread or the runtime exists, so
+            // nothing else can be reading the environment.
+            unsafe {
+                let p = libc::getenv(name.as_ptr());
+                if !p.is_null() {
+                    std::ptr::write_bytes(p, 0, libc::strlen(p));
+                }
+            }
+        }
+        env::remove_var(var);
+        taken.insert(var, v);
+    }
+    let _ = RATCHET_TAKEN_ENV.set(taken);
+}
+
+/// The value rtp_take_env_keys took for the variable, if any.
+fn rtp_taken_env_key(var: &str) -> String {
+    RATCHET_TAKEN_ENV.get().and_then(|t| t.get(var)).cloned().unwrap_or_default()
+}
+
+fn rtp_read_key_file(path: &std::path::Path, check_mode: bool) -> Result<String, String> {
+    use std::os::unix::fs::PermissionsExt;
+    let meta = std::fs::metadata(path).map_err(|e| format!("Unable to read key file {}: {}.", path.display(), e))?;
+    if check_mode && meta.permissions().mode() & 0o077 != 0 {
+        return Err(format!("Refusing key file {}, it is accessible to group or others (chmod 600).", path.display()));
+    }
+    let k = std::fs::read_to_string(path).map_err(|e| format!("Unable to read key file {}: {}.", path.display(), e))?;
+    let k = k.strip_suffix('\n').unwrap_or(&k);
+    let k = k.strip_suffix('\r').unwrap_or(k);
+    if k.is_empty() {
+        return Err(format!("Key file {} is empty.", path.display()));
+    }
+    Ok(k.to_string())
+}
+
+/// Reads a line from the terminal with echo turned off.
+fn rtp_prompt_key(prompt: &str) -> Result<String, String> {
+    use std::io::Write;
+    eprint!("{}", prompt);
+    let _ = std::io::stderr().flush();
+
+    // SAFETY: plain termios calls on stdin, which was just checked to be a tty,
+    // and the original settings are put back before returning.
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    let have_term = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut term) } == 0;
+    if have_term {
+        let mut quiet = term;
+        quiet.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet); }
+    }
+    let mut line = String::new();
+    let read = std::io::stdin().read_line(&mut line);
+    if have_term {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term); }
+    }
+    eprintln!();
+
+    read.map_err(|e| format!("Unable to read masking key: {}.", e))?;
+    let k = line.trim_end_matches(['\r', '\n']);
+    if k.is_empty() {
+        return Err(String::from("No masking key entered."));
+    }
+    Ok(k.to_string())
+}
+
+/// The old key under the stored KDF, and the new one under fresh parameters;
+/// the new one isn't derived unless the old one opens the database.
+fn rtp_rotation_keys(db: &Database, old_key: &String, new_key: &String) -> Result<([u8; 32], [u8; 32], RatchetKdfParams), RatchetStoreError> {
+    let old = rtp_current_key(db, old_key)?;
+    rtp_check_key(db, &old)?;
+    let new_kdf = RatchetKdfParams::generate();
+    let new = new_kdf.derive(new_key.as_bytes())?;
+    Ok((old, new, new_kdf))
+}
+
+/// The key the database is sealed under right now, from its masking key.
+fn rtp_current_key(db: &Database, masking_key: &String) -> Result<[u8; 32], RatchetStoreError> {
+    if rtp_read_meta(db, RATCHET_META_WRAPPED_KEY)?.is_some() {
+        return Err(RatchetStoreError::KeyProvider(String::from("the database key is wrapped, not derived from a masking key")));
+    }
+    match rtp_read_kdf(db)? {
+        Some(kdf) => kdf.derive(masking_key.as_bytes()),
+        None => Ok(rtp_legacy_key(masking_key)),
+    }
+}
+
+/// For offline commands, the current masking key: rebuilt from shares
+/// on stdin if the database is sealed, or from the usual key sources.
+fn rtp_cli_masking_key(db: &Database) -> Result<String, String> {
+    match rtp_read_kdf(db) {
+        Ok(Some(RatchetKdfParams { shamir_threshold: Some(threshold), .. })) => {
+            eprintln!("The database is sealed, enter {} key shares, one per line.", threshold);
+            let mut shares = vec![];
+            while shares.len() < threshold as usize {
+                shares.push(rtp_prompt_key("Share: ")?);
+            }
+            rtp_recover_masking_key(threshold, &shares)
+        },
+        Ok(_) => RATCHET_MASKING_KEY_SOURCE.select(),
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
+
+/// Shares are hex, the recovered secret is the masking key, also in hex.
+fn rtp_recover_masking_key(threshold: u8, shares: &[String]) -> Result<String, String> {
+    let shares = shares.iter()
+                       .map(|h| hex::decode(h.trim())
+                                    .map_err(|_| String::from("Share is not hex."))
+                                    .and_then(|b| Share::try_from(b.as_slice()).map_err(String::from)))
+                       .collect::<Result<Vec<Share>, String>>()?;
+    Sharks(threshold).recover(shares.iter())
+                     .map(hex::encode)
+                     .map_err(String::from)
+}
+
+/// Checks a key against the database, since e.g. the wrong shares still
+/// produce *a* key. Against the key check if there is one, otherwise a key
+/// that opens none of the records is wrong, rather than the records bad.
+fn rtp_check_key(db: &Database, key: &[u8; 32]) -> Result<(), RatchetStoreError> {
+    if let Some(check) = rtp_read_meta(db, RATCHET_META_KEY_CHECK)? {
+        return match rtp_open_record(key, RATCHET_META_TABLE.name(), RATCHET_META_KEY_CHECK, &check) {
+            Ok(v) if v == RATCHET_KEY_CHECK_VALUE => Ok(()),
+            _ => Err(RatchetStoreError::WrongKey),
+        };
+    }
+    let (good, bad) = rtp_sweep_rows(db, key)?;
+    if good == 0 && !bad.is_empty() {
+        return Err(RatchetStoreError::WrongKey);
+    }
+    Ok(())
+}
+
+/// Opens every row as plain JSON, how many did, and the ones that didn't.
+fn rtp_sweep_rows(db: &Database, key: &[u8; 32]) -> Result<(usize, Vec<RatchetQuarantined>), RatchetStoreError> {
+    let read_txn = db.begin_read()?;
+    let mut good = 0;
+    let mut bad = vec![];
+    let (g, b) = RATCHET_USERS_TABLE.read_sorted::<serde_json::Value>(&read_txn, key)?;
+    good += g.len(); bad.extend(b);
+    let (g, b) = RATCHET_DEVS_TABLE.read_sorted::<serde_json::Value>(&read_txn, key)?;
+    good += g.len(); bad.extend(b);
+    let (g, b) = RATCHET_USER_CMD_POLICY_TABLE.read_sorted::<serde_json::Value>(&read_txn, key)?;
+    good += g.len(); bad.extend(b);
+    let (g, b) = RATCHET_APIKEY_TABLE.read_sorted::<serde_json::Value>(&read_txn, key)?;
+    good += g.len(); bad.extend(b);
+    let (g, b) = RATCHET_ADMINS_TABLE.read_sorted::<serde_json::Value>(&read_txn, key)?;
+    good += g.len(); bad.extend(b);
+    Ok((good, bad))
+}
+
+/// Run before anything else touches the rows: refuses a wrong key outright,
+/// and moves any row that doesn't open into quarantine, so that the rest
+/// of pawl can start without it.
+fn rtp_sweep_database(db: &Database) -> Result<(), RatchetStoreError> {
+    let key = rtp_db_key();
+    rtp_quarantine_unreadable(db, &key)?;
+
+    // databases from before the key check get one now that the key is known good.
+    if rtp_read_meta(db, RATCHET_META_KEY_CHECK)?.is_none() {
+        rtp_write_key_check(db, &key)?;
+    }
+    Ok(())
+}
+
+/// Refuses a wrong key, then quarantines the rows that don't open under it.
+/// Also run before moving a database onto a new key, so one damaged row
+/// doesn't stop the move, and with it the start.
+fn rtp_quarantine_unreadable(db: &Database, key: &[u8; 32]) -> Result<(), RatchetStoreError> {
+    rtp_check_key(db, key)?;
+    let (_, bad) = rtp_sweep_rows(db, key)?;
+    if bad.is_empty() {
+        return Ok(());
+    }
+    let mut txn = RatchetRedbTxn::begin(db)?;
+    rtp_quarantine(txn.as_mut(), &bad)?;
+    txn.commit()
+}
+
+fn rtp_write_key_check(db: &Database, key: &[u8; 32]) -> Result<(), RatchetStoreError> {
+    let write_txn = db.begin_write()?;
+    {
+        let mut meta = write_txn.open_table(RATCHET_META_TABLE)?;
+        meta.insert(RATCHET_META_KEY_CHECK, rtp_seal_record(key, RATCHET_META_TABLE.name(), RATCHET_META_KEY_CHECK, RATCHET_KEY_CHECK_VALUE)?)?;
+    }
+    write_txn.commit()?;
+    Ok(())
+}
+
+/// Whether anything in the database was ever sealed under a key. Rows can
+/// all be in quarantine, but the key check stays.
+fn rtp_ever_sealed(db: &Database) -> Result<bool, RatchetStoreError> {
+    Ok(rtp_count_records(db)? > 0 || rtp_read_meta(db, RATCHET_META_KEY_CHECK)?.is_some())
+}
+
+fn rtp_count_records(db: &Database) -> Result<u64, RatchetStoreError> {
+    let read_txn = db.begin_read()?;
+    Ok(RATCHET_USERS_TABLE.count(&read_txn)? + RATCHET_DEVS_TABLE.count(&read_txn)? +
+       RATCHET_USER_CMD_POLICY_TABLE.count(&read_txn)? + RATCHET_APIKEY_TABLE.count(&read_txn)? +
+       RATCHET_ADMINS_TABLE.count(&read_txn)?)
+}
+
+/// The subcommand, if any, past `--data-dir DIR`.
+fn rtp_command() -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(a) = args.next() {
+        if a == "--data-dir" {
+            args.next();
+            continue;
+        }
+        return Some(a);
+    }
+    None
+}
+
+fn rtp_arg(flag: &str) -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1).cloned())
+}
+
+/// `ratchet-pawl split-key --shares N --threshold K`, run while pawl is stopped.
+/// 
+/// Re-seals the database under a fresh random key, and prints that key split
+/// into N Shamir shares, any K of which unseal it. Nobody ever sees the whole
+/// key; from then on pawl starts sealed and waits for K shares.
+fn rtp_cli_split_key() -> i32 {
+    let shares = rtp_arg("--shares").and_then(|n| n.parse::<u8>().ok());
+    let threshold = rtp_arg("--threshold").and_then(|n| n.parse::<u8>().ok());
+    let (shares, threshold) = match (shares, threshold) {
+        (Some(n), Some(k)) if 2 <= k && k <= n => (n, k),
+        _ => {
+            eprintln!("Usage: ratchet-pawl split-key --shares N --threshold K, with 2 <= K <= N <= 255");
+            return 2;
+        },
+    };
+
+    let old = match rtp_count_records(&rtp_db()) {
+        // nothing to decrypt yet
+        Ok(0) => Ok([0; 32]),
+        Ok(_) => rtp_cli_masking_key(&rtp_db()).and_then(|k| rtp_current_key(&rtp_db(), &k).map_err(|e| format!("{:?}", e))),
+        Err(e) => Err(format!("{:?}", e)),
+    };
+    let old = match old {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("{} Unable to read the current database key, nothing was changed.", e);
+            return 1;
+        },
+    };
+
+    let secret = rand::random::<[u8; 32]>();
+    let mut kdf = RatchetKdfParams::with_kdf(RatchetKdf::HkdfSha256);
+    kdf.shamir_threshold = Some(threshold);
+    let split: Vec<Vec<u8>> = Sharks(threshold).dealer(&secret)
+                                                .take(shares as usize)
+                                                .map(|share| Vec::from(&share))
+                                                .collect();
+    kdf.share_checks = split.iter().map(|share| (share[0], kdf.share_check(share))).collect();
+    let rotated = kdf.derive(hex::encode(secret).as_bytes())
+                     .and_then(|new| rtp_rotate_key(&rtp_db(), &old, &new, &RatchetKeyRecord::Kdf(kdf.clone())));
+    match rotated {
+        Ok(n) => {
+            println!("Ratchet-Pawl re-encrypted {} records, and will start sealed.", n);
+            println!("Give each key holder one share, any {} of them unseal pawl:", threshold);
+            split.iter().for_each(|share| println!("Share: {}", hex::encode(share)));
+            0
+        },
+        Err(e) => {
+            eprintln!("Ratchet-Pawl key split failed, nothing was changed: {:?}", e);
+            1
+        },
+    }
+}
+
+/// `ratchet-pawl rotate-key`, run while pawl is stopped.
+/// 
+/// The current key comes from the usual sources, and the replacement from
+/// the same with NEW_ in the name (RATCHET_PAWL_NEW_MASKING_KEY, etc.), keys
+/// on the command line end up in shell history. Restart pawl with the new
+/// key afterwards.
+fn rtp_cli_rotate_key() -> i32 {
+    let keys = rtp_cli_masking_key(&rtp_db())
+                   .and_then(|old| Ok((old, RATCHET_NEW_MASKING_KEY_SOURCE.select()?)));
+    let (old_key, new_key) = match keys {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("{} Please supply both the current and the new masking key to rotate the database key.", e);
+            return 2;
+        },
+    };
+    let rotated = rtp_rotation_keys(&rtp_db(), &old_key, &new_key)
+                     .and_then(|(old, new, new_kdf)| rtp_rotate_key(&rtp_db(), &old, &new, &RatchetKeyRecord::Kdf(new_kdf)));
+    match rotated {
+        Ok(n) => {
+            println!("Ratchet-Pawl re-encrypted {} records, use the new key from now on.", n);
+            0
+        },
+        Err(e) => {
+            eprintln!("Ratchet-Pawl key rotation failed, nothing was changed: {:?}", e);
+            1
+        },
+    }
+}
+
+/// `ratchet-pawl backup --recipient age1... --out FILE`, run while pawl is stopped.
+fn rtp_cli_backup() -> i32 {
+    let (recipient, out) = match (rtp_arg("--recipient").or(Some(rtp_env_key("RATCHET_PAWL_BACKUP_RECIPIENT")).filter(|r| !r.is_empty())), rtp_arg("--out")) {
+        (Some(r), Some(o)) => (r, o),
+        _ => {
+            eprintln!("Usage: ratchet-pawl backup --recipient age1... --out FILE");
+            return 2;
+        },
+    };
+    if let Err(e) = rtp_cli_db_key(&rtp_db()) {
+        eprintln!("{} Unable to read the database key.", e);
+        return 1;
+    }
+    let written = rtp_create_backup(&recipient)
+                      .and_then(|(archive, manifest)| {
+                          std::fs::write(&out, archive).map_err(|e| format!("Unable to write {}: {}", out, e))?;
+                          Ok(manifest)
+                      });
+    match written {
+        Ok(manifest) => {
+            println!("{}", serde_json::to_string_pretty(&manifest).unwrap_or_default());
+            0
+        },
+        Err(e) => {
+            eprintln!("Ratchet-Pawl backup failed: {}", e);
+            1
+        },
+    }
+}
+
+/// `ratchet-pawl restore --in FILE --identity FILE [--dry-run]`, run while
+/// pawl is stopped. Prints what changes as JSON, and with `--dry-run`
+/// that's all it does.
+fn rtp_cli_restore() -> i32 {
+    let (archive, identity) = match (rtp_arg("--in"), rtp_arg("--identity")) {
+        (Some(a), Some(i)) => (a, i),
+        _ => {
+            eprintln!("Usage: ratchet-pawl restore --in FILE --identity FILE [--dry-run]");
+            return 2;
+        },
+    };
+    let dry_run = env::args().any(|a| a == "--dry-run");
+    if let Err(e) = rtp_cli_db_key(&rtp_db()) {
+        eprintln!("{} Unable to read the database key.", e);
+        return 1;
+    }
+    let restored = std::fs::read(&archive).map_err(|e| format!("Unable to read {}: {}", archive, e))
+        .and_then(|a| Ok((a, std::fs::read_to_string(&identity).map_err(|e| format!("Unable to read {}: {}", identity, e))?)))
+        .and_then(|(a, i)| rtp_open_backup(&a, &i))
+        .and_then(|(manifest, restored)| {
+            let current = rtp_snapshot().map_err(|e| format!("{:?}", e))?;
+            let mut summary = rtp_restore_summary(manifest, &current, &restored);
+            if !dry_run {
+                let changes = rtp_restore_changes(&current, &restored, RATCHET_SYSTEM_ACTOR).map_err(|e| format!("{:?}", e))?;
+                RatchetTables::of(&current).changed(&changes)?;
+                rtp_apply_changes(&changes).map_err(|e| format!("{:?}", e))?;
+                summary.applied = true;
+            }
+            Ok(summary)
+        });
+    match restored {
+        Ok(summary) => {
+            println!("{}", serde_json::to_string_pretty(&summary).unwrap_or_default());
+            0
+        },
+        Err(e) => {
+            eprintln!("Ratchet-Pawl restore failed, nothing was changed: {}", e);
+            1
+        },
+    }
+}
+
+/// One thing `check` found, and whether `--repair` fixed it.
+#[derive(Debug, Serialize)]
+struct RatchetCheckProblem {
+    check: &'static str,
+    table: Option<String>,
+    record_key: Option<String>,
+    detail: String,
+    repaired: bool,
+}
+
+impl RatchetCheckProblem {
+    fn new(check: &'static str, detail: String) -> RatchetCheckProblem {
+        RatchetCheckProblem { check, table: None, record_key: None, detail, repaired: false }
+    }
+
+    fn row(check: &'static str, table: &str, record_key: &str, detail: String) -> RatchetCheckProblem {
+        RatchetCheckProblem { check, table: Some(table.to_string()), record_key: Some(record_key.to_string()), detail, repaired: false }
+    }
+}
+
+/// What `check` prints, `ok` is false while anything is left unrepaired.
+#[derive(Debug, Serialize)]
+struct RatchetCheckReport {
+    database: String,
+    ok: bool,
+    repair: bool,
+    schema_version: Option<u32>,
+    records: u64,
+    problems: Vec<RatchetCheckProblem>,
+}
+
+impl RatchetCheckReport {
+    /// Prints the report, and is the exit code.
+    fn finish(mut self) -> i32 {
+        self.ok = self.problems.iter().all(|p| p.repaired);
+        match serde_json::to_string_pretty(&self) {
+            Ok(out) => println!("{}", out),
+            Err(e) => eprintln!("Ratchet-Pawl unable to print the check report: {}", e),
+        }
+        if self.ok { 0 } else { 1 }
+    }
+}
+
+/// Rows of one table that don't open as `T`, or sit under some other key
+/// than their own into_key.
+fn rtp_check_table<T>(table: &'static ReadWriteTable<'static, &'static str, Vec<u8>, T>,
+                      read_txn: &ReadTransaction,
+                      key: &[u8; 32],
+                      problems: &mut Vec<RatchetCheckProblem>) -> Result<RatchetSortedRows<T>, RatchetStoreError>
+where T: Serialize + DeserializeOwned + RatchetKeyed {
+    let (good, bad) = table.read_sorted::<T>(read_txn, key)?;
+    for q in bad.iter() {
+        problems.push(RatchetCheckProblem::row("unreadable", &q.table, &q.record_key, q.reason.clone()));
+    }
+    for (record_key, item) in good.iter() {
+        if record_key != item.into_key() {
+            problems.push(RatchetCheckProblem::row("key_mismatch", table.0.name(), record_key,
+                                                   format!("stored under the wrong key, belongs under {}", item.into_key())));
+        }
+    }
+    Ok((good, bad))
+}
+
+/// Moves mismatched rows to their own keys, in one transaction, marking
+/// the ones that moved as repaired.
+fn rtp_repair_keys<T>(db: &Database,
+                      table: &'static ReadWriteTable<'static, &'static str, Vec<u8>, T>,
+                      key: &[u8; 32],
+                      rows: &[(String, T)],
+                      problems: &mut [RatchetCheckProblem]) -> Result<(), RatchetStoreError>
+where T: Serialize + DeserializeOwned + RatchetKeyed {
+    let write_txn = db.begin_write()?;
+    let mut moved = vec![];
+    for (record_key, item) in rows.iter().filter(|(k, item)| k != item.into_key()) {
+        if table.rekey(&write_txn, key, record_key, item)? {
+            moved.push(record_key.clone());
+        }
+    }
+    write_txn.commit()?;
+    for p in problems.iter_mut() {
+        if p.check == "key_mismatch" && p.table.as_deref() == Some(table.0.name())
+           && p.record_key.as_ref().is_some_and(|k| moved.contains(k)) {
+            p.repaired = true;
+        }
+    }
+    Ok(())
+}
+
+/// The database key for offline commands, whichever way this database keeps
+/// it, and taken like at startup.
+fn rtp_cli_db_key(db: &Database) -> Result<[u8; 32], String> {
+    match rtp_ever_sealed(db) {
+        // nothing to decrypt
+        Ok(false) => return Ok([0; 32]),
+        Ok(true) => (),
+        Err(e) => return Err(format!("{:?}", e)),
+    }
+    match rtp_read_meta(db, RATCHET_META_WRAPPED_KEY) {
+        Ok(Some(_)) => {
+            rtp_key_provider().and_then(|p| p.unlock(db)).map_err(|e| format!("{:?}", e))?;
+            Ok(*rtp_db_key())
+        },
+        Ok(None) => {
+            let key = rtp_cli_masking_key(db).and_then(|k| rtp_current_key(db, &k).map_err(|e| format!("{:?}", e)))?;
+            rtp_take_key(key);
+            Ok(key)
+        },
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
+
+/// An owner-only copy of a database file, removed when dropped. redb 2.4
+/// can't open a file read-only, so the offline checks open this instead,
+/// and the database itself is only ever read.
+struct RatchetSnapshot(std::path::PathBuf);
+
+impl RatchetSnapshot {
+    fn of(path: &String) -> Result<RatchetSnapshot, String> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let mut from = std::fs::File::open(path).map_err(|e| format!("Unable to open database: {}", e))?;
+        let copy = std::env::temp_dir().join(format!("ratchet-pawl-{}-{}.redb", std::process::id(), hex::encode(rand::random::<[u8; 8]>())));
+        let mut to = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&copy)
+                         .map_err(|e| format!("Unable to copy database to {}: {}", copy.display(), e))?;
+        let snapshot = RatchetSnapshot(copy);
+        std::io::copy(&mut from, &mut to).map_err(|e| format!("Unable to copy database to {}: {}", snapshot.0.display(), e))?;
+        Ok(snapshot)
+    }
+}
+
+impl Drop for RatchetSnapshot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Pawl holds the lock in its data directory for as long as it runs, see
+/// rtp_lock_data_dir, so check takes it too: neither the copy nor a repair
+/// is safe while pawl writes. No lock file, no pawl ever ran there.
+fn rtp_check_lock(path: &String) -> Result<Option<std::fs::File>, String> {
+    let dir = match std::path::Path::new(path).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => std::path::Path::new("."),
+    };
+    let lock_path = dir.join(THE_LOCK_FILE);
+    let lock = match std::fs::File::open(&lock_path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Unable to open lock file {}: {}", lock_path.display(), e)),
+    };
+    rtp_flock(&lock, &lock_path).map_err(|e| format!("The database is in use by pawl ({}), stop it before checking", e))?;
+    Ok(Some(lock))
+}
+
+/// `ratchet-pawl check [--db FILE] [--repair]`, run while pawl is stopped,
+/// e.g. from cron or before an upgrade.
+/// 
+/// Checks every row opens and deserializes, and is stored under its own key,
+/// that there's an admin user, a valid policy and an API key, and prints
+/// what it found as JSON. Without `--repair` nothing is written. With it,
+/// unreadable rows go to quarantine, misplaced rows move to their keys, and
+/// the schema is migrated; missing users, policy and API keys are left for
+/// pawl to create on its next start. Exits 1 if anything is left over.
+fn rtp_cli_check() -> i32 {
+    let path = rtp_arg("--db").unwrap_or(RATCHET_DATA_DIR.join(THE_DATABASE).display().to_string());
+    let repair = env::args().any(|a| a == "--repair");
+    let mut report = RatchetCheckReport {
+        database: path.clone(),
+        ok: false,
+        repair,
+        schema_version: None,
+        records: 0,
+        problems: vec![],
+    };
+    match rtp_check_database(&path, repair, &mut report) {
+        Ok(()) => (),
+        Err(e) => report.problems.push(RatchetCheckProblem::new("error", e)),
+    }
+    report.finish()
+}
+
+fn rtp_check_database(path: &String, repair: bool, report: &mut RatchetCheckReport) -> Result<(), String> {
+    let _lock = rtp_check_lock(path)?;
+    let snapshot = match repair {
+        true => None,
+        false => Some(RatchetSnapshot::of(path)?),
+    };
+    let db = Database::open(snapshot.as_ref().map_or(path.as_ref(), |s| s.0.as_path()))
+                 .map_err(|e| format!("Unable to open database: {}", e))?;
+    rtp_note_legacy_rows(&db).map_err(|e| format!("{:?}", e))?;
+    report.records = rtp_count_records(&db).map_err(|e| format!("{:?}", e))?;
+
+    let key = rtp_cli_db_key(&db)?;
+    match rtp_check_key(&db, &key) {
+        Ok(()) => (),
+        Err(RatchetStoreError::WrongKey) => {
+            report.problems.push(RatchetCheckProblem::new("wrong_key", String::from("the masking key does not open this database")));
+            return Ok(());
+        },
+        Err(e) => return Err(format!("{:?}", e)),
+    }
+    let problems = &mut report.problems;
+
+    if rtp_read_meta(&db, RATCHET_META_KEY_CHECK).map_err(|e| format!("{:?}", e))?.is_none() && report.records > 0 {
+        let mut p = RatchetCheckProblem::new("key_check_missing", String::from("pawl adds it on its next start"));
+        if repair {
+            rtp_write_key_check(&db, &key).map_err(|e| format!("{:?}", e))?;
+            p.repaired = true;
+        }
+        problems.push(p);
+    }
+
+    let version = rtp_read_meta(&db, RATCHET_META_SCHEMA_VERSION)
+                      .and_then(rtp_parse_schema_version)
+                      .map_err(|e| format!("{:?}", e))?;
+    report.schema_version = Some(version);
+    if version > RATCHET_SCHEMA_VERSION {
+        problems.push(RatchetCheckProblem::new("schema_newer", format!("schema version {} is newer than this pawl ({}), rows not checked", version, RATCHET_SCHEMA_VERSION)));
+        return Ok(());
+    }
+    if version < RATCHET_SCHEMA_VERSION {
+        let mut p = RatchetCheckProblem::new("schema_outdated", format!("schema version {}, pawl migrates to {} on its next start", version, RATCHET_SCHEMA_VERSION));
+        if !repair {
+            p.detail.push_str(", rows not checked until then");
+            problems.push(p);
+            return Ok(());
+        }
+        rtp_migrate_database(&db).map_err(|e| format!("{:?}", e))?;
+        p.repaired = true;
+        report.schema_version = Some(RATCHET_SCHEMA_VERSION);
+        problems.push(p);
+    }
+
+    let read_txn = db.begin_read().map_err(|e| format!("{:?}", e))?;
+    let checked = (|| {
+        let users = rtp_check_table(&RATCHET_USERS_TABLE, &read_txn, &key, problems)?;
+        let devs = rtp_check_table(&RATCHET_DEVS_TABLE, &read_txn, &key, problems)?;
+        let policies = rtp_check_table(&RATCHET_USER_CMD_POLICY_TABLE, &read_txn, &key, problems)?;
+        let api_keys = rtp_check_table(&RATCHET_APIKEY_TABLE, &read_txn, &key, problems)?;
+        let admins = rtp_check_table(&RATCHET_ADMINS_TABLE, &read_txn, &key, problems)?;
+        Ok::<_, RatchetStoreError>((users, devs, policies, api_keys, admins))
+    })();
+    let (users, devs, policies, api_keys, admins) = checked.map_err(|e| format!("{:?}", e))?;
+    drop(read_txn);
+
+    if !admins.0.iter().any(|(_, a)| a.role == RatchetRole::Superadmin) {
+        problems.push(RatchetCheckProblem::new("no_superadmin", format!("pawl creates {} on its next start", RATCHET_DEFAULT_ADMIN)));
+    }
+    if policies.0.is_empty() {
+        problems.push(RatchetCheckProblem::new("no_policy", String::from("pawl creates an empty policy on its next start")));
+    }
+    for (record_key, policy) in policies.0.iter() {
+        if !rtp_validate_policy(&policy.policy) {
+            problems.push(RatchetCheckProblem::row("invalid_policy", RATCHET_USER_CMD_POLICY_TABLE.0.name(), record_key,
+                                                   String::from("the stored policy does not pass validation")));
+        }
+    }
+    if api_keys.0.is_empty() {
+        problems.push(RatchetCheckProblem::new("no_api_key", String::from("pawl creates one on its next start")));
+    }
+
+    let quarantined = RatchetRedbRead::begin(&db)
+                          .and_then(|mut txn| rtp_read_quarantine(txn.as_mut()))
+                          .map_err(|e| format!("{:?}", e))?;
+    for q in quarantined.iter() {
+        problems.push(RatchetCheckProblem::row("quarantined", &q.table, &q.record_key,
+                                               format!("already in quarantine: {}", q.reason)));
+    }
+
+    if repair {
+        let bad: Vec<RatchetQuarantined> = [users.1, devs.1, policies.1, api_keys.1, admins.1].concat();
+        RatchetRedbTxn::begin(&db)
+            .and_then(|mut txn| {
+                rtp_quarantine(txn.as_mut(), &bad)?;
+                txn.commit()
+            })
+            .map_err(|e| format!("{:?}", e))?;
+        for p in problems.iter_mut().filter(|p| p.check == "unreadable") {
+            p.repaired = true;
+        }
+        rtp_repair_keys(&db, &RATCHET_USERS_TABLE, &key, &users.0, problems).map_err(|e| format!("{:?}", e))?;
+        rtp_repair_keys(&db, &RATCHET_DEVS_TABLE, &key, &devs.0, problems).map_err(|e| format!("{:?}", e))?;
+        rtp_repair_keys(&db, &RATCHET_USER_CMD_POLICY_TABLE, &key, &policies.0, problems).map_err(|e| format!("{:?}", e))?;
+        rtp_repair_keys(&db, &RATCHET_APIKEY_TABLE, &key, &api_keys.0, problems).map_err(|e| format!("{:?}", e))?;
+        rtp_repair_keys(&db, &RATCHET_ADMINS_TABLE, &key, &admins.0, problems).map_err(|e| format!("{:?}", e))?;
+    }
+    Ok(())
 }
 
-/// Frontend API for listing users.
-#[get("/getusers")]
-async fn get_users(_admin: RatchetUser) -> Json<Vec<RatchetFrontendUserEntry>> {
-    let users = RATCHET_USERS.lock().await;
-    Json(
-        users
-            .values()
-            .map(|u| RatchetFrontendUserEntry {
-                username: u.username.clone(),
+/// What `verify-audit` prints, `ok` is false if anything's wrong with the chain.
+#[derive(Debug, Serialize)]
+struct RatchetAuditReport {
+    database: String,
+    ok: bool,
+    entries: usize,
+    /// The last entry's hash, to note down and compare next time.
+    head: Option<String>,
+    problems: Vec<RatchetCheckProblem>,
+}
+
+/// `ratchet-pawl verify-audit [--db FILE]`, run while pawl is stopped, e.g.
+/// on a copy of the database for an auditor. Nothing is written. Exits 1 if
+/// an entry was removed, edited or moved, see rtp_verify_audit.
+fn rtp_cli_verify_audit() -> i32 {
+    let path = rtp_arg("--db").unwrap_or(RATCHET_DATA_DIR.join(THE_DATABASE).display().to_string());
+    let mut report = RatchetAuditReport { database: path.clone(), ok: false, entries: 0, head: None, problems: vec![] };
+    if let Err(e) = rtp_verify_audit_database(&path, &mut report) {
+        report.problems.push(RatchetCheckProblem::new("error", e));
+    }
+    report.ok = report.problems.is_empty();
+    match serde_json::to_string_pretty(&report) {
+        Ok(out) => println!("{}", out),
+        Err(e) => eprintln!("Ratchet-Pawl unable to print the audit report: {}", e),
+    }
+    if report.ok { 0 } else { 1 }
+}
+
+fn rtp_verify_audit_database(path: &String, report: &mut RatchetAuditReport) -> Result<(), String> {
+    let snapshot = RatchetSnapshot::of(path)?;
+    let db = Database::open(&snapshot.0).map_err(|e| format!("Unable to open database: {}", e))?;
+    let key = rtp_cli_db_key(&db)?;
+    match rtp_check_key(&db, &key) {
+        Ok(()) => (),
+        Err(RatchetStoreError::WrongKey) => return Err(String::from("the masking key does not open this database")),
+        Err(e) => return Err(format!("{:?}", e)),
+    }
+    let rows = RatchetRedbRead::begin(&db)
+                   .and_then(|mut txn| txn.log_scan(RATCHET_AUDIT_TABLE.name(), 0, usize::MAX))
+                   .map_err(|e| format!("{:?}", e))?;
+    let head = match rtp_read_meta(&db, RATCHET_META_AUDIT_HEAD).map_err(|e| format!("{:?}", e))? {
+        Some(sealed) => Some(rtp_open_record(&key, RATCHET_META_TABLE.name(), RATCHET_META_AUDIT_HEAD, &sealed)
+                                 .and_then(|pt| serde_json::from_slice::<RatchetAuditHead>(&pt).map_err(|e| RatchetStoreError::Format(e.to_string())))
+                                 .map_err(|e| format!("Unable to read the audit head: {:?}", e))?),
+        None => None,
+    };
+    report.entries = rows.len();
+    report.head = head.as_ref().map(|h| h.hash.clone());
+    report.problems = rtp_verify_audit(&key, &rows, head);
+    Ok(())
+}
+
+/// The stored formats this build reads and writes.
+const RATCHET_SCHEMA_VERSION: u32 = 7;
+
+/// API keys as schema version 4 kept them, in the clear; backups from
+/// before version 5 have them like this too.
+#[derive(Serialize, Deserialize)]
+struct RatchetApiKeyV4 {
+    name: String,
+    api_key: String,
+    scopes: Vec<String>,
+    created: u64,
+    expires: Option<u64>,
+}
+
+impl RatchetKeyed for RatchetApiKeyV4 {
+    fn into_key(&self) -> &str {
+        self.name.as_str()
+    }
+}
+
+impl RatchetApiKeyV4 {
+    /// The key there was before schema version 4, which could do anything.
+    fn from_v1(api_key: String) -> RatchetApiKeyV4 {
+        RatchetApiKeyV4 {
+            name: String::from(RATCHET_DEFAULT_API_KEY),
+            api_key,
+            scopes: RATCHET_API_SCOPES.iter().map(|s| s.to_string()).collect(),
+            created: rtp_unix_now(),
+            expires: None,
+        }
+    }
+
+    fn hashed(self, hmac: &[u8; 32]) -> Result<RatchetApiKey, RatchetStoreError> {
+        RatchetApiKey::new(hmac, &self.name, &self.api_key, self.scopes, self.created, self.expires)
+    }
+
+    /// A row as schema version 4 or before left it, the oldest were only
+    /// the key, hashed. Anything without a key in it is left alone.
+    fn hash_row(v: serde_json::Value, hmac: &[u8; 32]) -> Result<serde_json::Value, RatchetStoreError> {
+        let old = match (v.get("api_key"), v.get("name")) {
+            (None, _) => return Ok(v),
+            (Some(api_key), None) => RatchetApiKeyV4::from_v1(api_key.as_str().unwrap_or("").to_string()),
+            (Some(_), Some(_)) => serde_json::from_value(v).map_err(|e| RatchetStoreError::Format(e.to_string()))?,
+        };
+        serde_json::to_value(old.hashed(hmac)?).map_err(|e| RatchetStoreError::Format(e.to_string()))
+    }
+}
+
+/// One step forward in the stored formats, `version` is what it leaves behind.
+struct RatchetMigration {
+    version: u32,
+    description: &'static str,
+    step: fn(&WriteTransaction, &[u8; 32]) -> Result<usize, RatchetStoreError>,
+}
+
+/// In order. Keep the old shapes here rather than in the live structs,
+/// a migration only ever needs to know the version before it.
+const RATCHET_MIGRATIONS: &[RatchetMigration] = &[
+    RatchetMigration {
+        version: 2,
+        description: "devices gain a description",
+        step: |write_txn, key| {
+            #[derive(Deserialize)]
+            struct RatchetDevEntryV1 {
+                network_id: String,
+                key: String,
+            }
+            RATCHET_DEVS_TABLE.migrate(write_txn, key, |d: RatchetDevEntryV1| RatchetDevEntry {
+                network_id: d.network_id,
+                key: d.key,
+                description: None,
+                stamp: RatchetStamp::default(),
             })
-            .collect::<Vec<RatchetFrontendUserEntry>>(),
-    )
+        },
+    },
+    RatchetMigration {
+        version: 3,
+        description: "rows are stored under their index keys",
+        step: |write_txn, key| {
+            Ok(RATCHET_USERS_TABLE.migrate(write_txn, key, |v: serde_json::Value| v)? +
+               RATCHET_DEVS_TABLE.migrate(write_txn, key, |v: serde_json::Value| v)? +
+               RATCHET_USER_CMD_POLICY_TABLE.migrate(write_txn, key, |v: serde_json::Value| v)? +
+               RATCHET_APIKEY_TABLE.migrate(write_txn, key, |v: serde_json::Value| v)?)
+        },
+    },
+    RatchetMigration {
+        version: 4,
+        description: "API keys are named, with scopes and expiry",
+        step: |write_txn, key| {
+            #[derive(Deserialize)]
+            struct RatchetApiKeyV1 {
+                api_key: String,
+            }
+            RATCHET_APIKEY_TABLE.migrate_keyed(write_txn, key, |k: RatchetApiKeyV1| RatchetApiKeyV4::from_v1(k.api_key))
+        },
+    },
+    RatchetMigration {
+        version: 5,
+        description: "API keys are kept hashed, in the journal too",
+        step: |write_txn, key| {
+            let hmac = rtp_api_key_secret_in(write_txn, key)?;
+            Ok(RATCHET_APIKEY_TABLE.try_migrate(write_txn, key, |k: RatchetApiKeyV4| k.hashed(&hmac))? +
+               rtp_hash_journal_api_keys(write_txn, key, &hmac)?)
+        },
+    },
+    RatchetMigration {
+        version: 6,
+        description: "web admins are kept apart from TACACS+ users",
+        // nobody is carried over, pawl makes a superadmin on start
+        step: |write_txn, _| {
+            write_txn.open_table(RATCHET_ADMINS_TABLE.unwrap())?;
+            Ok(0)
+        },
+    },
+    RatchetMigration {
+        version: 7,
+        description: "rows say who made and last changed them, as far as the journal knows",
+        step: |write_txn, key| {
+            let stamps = rtp_journal_stamps(write_txn, key)?;
+            let stamped = |table: &'static str, id: &'static str| {
+                let stamps = &stamps;
+                move |v: serde_json::Value| {
+                    let record_key = v.get(id).and_then(|k| k.as_str()).unwrap_or("").to_string();
+                    rtp_stamp_row(v, stamps.get(&(table.to_string(), record_key)))
+                }
+            };
+            let policy = RATCHET_USER_CMD_POLICY_TABLE.name();
+            Ok(RATCHET_USERS_TABLE.migrate(write_txn, key, stamped(RATCHET_USERS_TABLE.name(), "username"))? +
+               RATCHET_DEVS_TABLE.migrate(write_txn, key, stamped(RATCHET_DEVS_TABLE.name(), "network_id"))? +
+               RATCHET_APIKEY_TABLE.migrate(write_txn, key, stamped(RATCHET_APIKEY_TABLE.name(), "name"))? +
+               RATCHET_ADMINS_TABLE.migrate(write_txn, key, stamped(RATCHET_ADMINS_TABLE.name(), "username"))? +
+               RATCHET_USER_CMD_POLICY_TABLE.migrate(write_txn, key, |v: serde_json::Value| {
+                   let v = match v {
+                       serde_json::Value::String(p) => serde_json::json!({ "policy": p }),
+                       v => v,
+                   };
+                   rtp_stamp_row(v, stamps.get(&(policy.to_string(), String::from("singleton"))))
+               })?)
+        },
+    },
+];
+
+/// Hashes the keys in the journal's API key entries, inside the caller's
+/// transaction, like migration 5 does the table. Before schema version 4
+/// the key was its own record key, those entries get the name it got.
+fn rtp_hash_journal_api_keys(write_txn: &WriteTransaction, key: &[u8; 32], hmac: &[u8; 32]) -> Result<usize, RatchetStoreError> {
+    let mut journal = write_txn.open_table(RATCHET_JOURNAL_TABLE)?;
+    let mut rows = vec![];
+    for tup in journal.iter()? {
+        let (seq, stored) = tup?;
+        rows.push((seq.value(), stored.value()));
+    }
+    let mut n = 0;
+    for (seq, stored) in rows {
+        let pt = rtp_open_record(key, RATCHET_JOURNAL_TABLE.name(), &seq.to_string(), &stored)?;
+        let mut e: RatchetJournalEntry = serde_json::from_slice(&pt).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        if e.table != RATCHET_APIKEY_TABLE.name() {
+            continue;
+        }
+        e.previous = e.previous.map(|v| RatchetApiKeyV4::hash_row(v, hmac)).transpose()?;
+        e.new = e.new.map(|v| RatchetApiKeyV4::hash_row(v, hmac)).transpose()?;
+        if let Some(name) = e.previous.as_ref().or(e.new.as_ref()).and_then(|v| v.get("name")).and_then(|n| n.as_str()) {
+            e.record_key = name.to_string();
+        }
+        let pt = serde_json::to_vec(&e).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        journal.insert(seq, rtp_seal_record(key, RATCHET_JOURNAL_TABLE.name(), &seq.to_string(), &pt)?)?;
+        n += 1;
+    }
+    Ok(n)
 }
 
-/// Backend data for TACACS+ clients, used for authentication
-/// 
-/// Should not be sent over any unsecure channel, since
-/// hashes are subject to attacks.
-/// 
-#[derive(Clone, FromForm, Debug, Serialize, Deserialize)]
-struct RatchetDevEntry {
-    network_id: String,
-    key: String,
+/// Who made each row and who last changed it, by the journal, keyed by
+/// table and record key. Rows removed since aren't in it.
+fn rtp_journal_stamps(write_txn: &WriteTransaction, key: &[u8; 32]) -> Result<HashMap<(String, String), RatchetStamp>, RatchetStoreError> {
+    let journal = write_txn.open_table(RATCHET_JOURNAL_TABLE)?;
+    let mut stamps: HashMap<(String, String), RatchetStamp> = HashMap::new();
+    for tup in journal.iter()? {
+        let (seq, stored) = tup?;
+        let pt = rtp_open_record(key, RATCHET_JOURNAL_TABLE.name(), &seq.value().to_string(), &stored.value())?;
+        let e: RatchetJournalEntry = serde_json::from_slice(&pt).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        let row = (e.table, e.record_key);
+        match (e.previous, e.new) {
+            (_, None) => { stamps.remove(&row); },
+            (None, Some(_)) => {
+                stamps.insert(row, RatchetStamp {
+                    created_by: e.actor.clone(),
+                    created_at: e.at,
+                    updated_by: e.actor,
+                    updated_at: e.at,
+                });
+            },
+            (Some(_), Some(_)) => {
+                let stamp = stamps.entry(row).or_default();
+                stamp.updated_by = e.actor;
+                stamp.updated_at = e.at;
+            },
+        }
+    }
+    Ok(stamps)
+}
+
+/// Adds the stamp's fields to a row, if there is a stamp.
+fn rtp_stamp_row(mut v: serde_json::Value, stamp: Option<&RatchetStamp>) -> serde_json::Value {
+    if let (Some(row), Some(serde_json::Value::Object(stamp))) = (v.as_object_mut(), stamp.and_then(|s| serde_json::to_value(s).ok())) {
+        row.extend(stamp);
+    }
+    v
+}
+
+/// Brings the stored formats up to RATCHET_SCHEMA_VERSION, all steps in
+/// one transaction. A database without a version is version 1, and a
+/// database newer than this build is refused rather than guessed at.
+fn rtp_migrate_database(db: &Database) -> Result<(), RatchetStoreError> {
+    let key = rtp_db_key();
+    let write_txn = db.begin_write()?;
+    let (current, envelope_only) = {
+        let meta = write_txn.open_table(RATCHET_META_TABLE)?;
+        let v = meta.get(RATCHET_META_SCHEMA_VERSION)?.map(|v| v.value());
+        let envelope_only = meta.get(RATCHET_META_ENVELOPE_ONLY)?.is_some();
+        (rtp_parse_schema_version(v)?, envelope_only)
+    };
+    if current > RATCHET_SCHEMA_VERSION {
+        return Err(RatchetStoreError::Format(format!("database schema version {} is newer than this pawl ({}), refusing to start", current, RATCHET_SCHEMA_VERSION)));
+    }
+    if current == RATCHET_SCHEMA_VERSION && envelope_only {
+        return Ok(());
+    }
+
+    for m in RATCHET_MIGRATIONS.iter().filter(|m| m.version > current) {
+        let n = (m.step)(&write_txn, &key)?;
+        eprintln!("Ratchet-Pawl schema version {}: {}, {} records.", m.version, m.description, n);
+    }
+    {
+        let mut meta = write_txn.open_table(RATCHET_META_TABLE)?;
+        let ser = serde_json::to_vec(&RATCHET_SCHEMA_VERSION).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        meta.insert(RATCHET_META_SCHEMA_VERSION, ser)?;
+        // version 3 sealed whatever was left
+        meta.insert(RATCHET_META_ENVELOPE_ONLY, serde_json::to_vec(&true).map_err(|e| RatchetStoreError::Format(e.to_string()))?)?;
+    }
+    write_txn.commit()?;
+    RATCHET_LEGACY_ROWS.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Databases from before versioning are version 1.
+fn rtp_parse_schema_version(v: Option<Vec<u8>>) -> Result<u32, RatchetStoreError> {
+    match v {
+        Some(v) => serde_json::from_slice::<u32>(&v).map_err(|e| RatchetStoreError::Format(e.to_string())),
+        None => Ok(1),
+    }
+}
+
+/// Rows that couldn't be opened, or understood, moved aside exactly as they
+/// were found; they're already sealed, or garbage, so they're kept as-is.
+const RATCHET_QUARANTINE_TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("ratchet_quarantine");
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetQuarantined {
+    table: String,
+    record_key: String,
+    reason: String,
+    quarantined_at: u64,
+    stored: Vec<u8>,
+}
+
+impl RatchetQuarantined {
+    fn id(&self) -> String {
+        format!("{}/{}", self.table, self.record_key)
+    }
+}
+
+/// The rows that opened, and the ones that didn't.
+type RatchetSortedRows<R> = (Vec<(String, R)>, Vec<RatchetQuarantined>);
+
+/// Set when anything is in quarantine, pawl runs without those rows.
+static RATCHET_DEGRADED: AtomicBool = AtomicBool::new(false);
+
+fn rtp_unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Moves rows from their tables into quarantine, inside the caller's transaction.
+fn rtp_quarantine(txn: &mut dyn RatchetBackendTxn, bad: &[RatchetQuarantined]) -> Result<(), RatchetStoreError> {
+    for q in bad.iter() {
+        eprintln!("Ratchet-Pawl quarantined {}: {}", q.id(), q.reason);
+        let ser = serde_json::to_vec(q).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+        txn.put(RATCHET_QUARANTINE_TABLE.name(), &q.id(), ser)?;
+        txn.delete(&q.table, &q.record_key)?;
+    }
+    Ok(())
+}
+
+fn rtp_read_quarantine(txn: &mut dyn RatchetBackendRead) -> Result<Vec<RatchetQuarantined>, RatchetStoreError> {
+    txn.scan(RATCHET_QUARANTINE_TABLE.name())?
+        .iter()
+        .map(|(_, v)| serde_json::from_slice(v).map_err(|e| RatchetStoreError::Format(e.to_string())))
+        .collect()
+}
+
+/// Only what's needed to decide what to do about it, not the row itself.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetFrontendQuarantined {
+    id: String,
+    table: String,
+    record_key: String,
+    reason: String,
+    quarantined_at: u64,
+}
+
+/// Frontend API for listing quarantined rows. They can be recreated
+/// through the usual APIs, and then removed from quarantine.
+#[get("/getquarantine")]
+async fn get_quarantine(_admin: RatchetAdmin<RatchetPermView>) -> Result<Json<Vec<RatchetFrontendQuarantined>>, Status> {
+    match rtp_blocking(|| RATCHET_STORE.begin_read().and_then(|mut txn| rtp_read_quarantine(txn.raw.as_mut()))).await {
+        Ok(q) => Ok(Json(q.into_iter()
+                          .map(|q| RatchetFrontendQuarantined {
+                              id: q.id(),
+                              table: q.table,
+                              record_key: q.record_key,
+                              reason: q.reason,
+                              quarantined_at: q.quarantined_at,
+                          })
+                          .collect())),
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unable to read quarantine: {:?}", e);
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+/// Frontend API for deleting a quarantined row for good.
+#[post("/rmquarantine", format = "multipart/form-data", data = "<id>")]
+async fn rm_quarantine(_admin: RatchetAdmin<RatchetPermSuperadmin>, id: Form<String>) -> status::Custom<&'static str> {
+    let id = id.into_inner();
+    let removed = rtp_blocking(move || RATCHET_STORE.begin().and_then(|mut txn| {
+        let removed = txn.raw.delete(RATCHET_QUARANTINE_TABLE.name(), &id)?;
+        let degraded = !txn.raw.scan(RATCHET_QUARANTINE_TABLE.name())?.is_empty();
+        txn.commit()?;
+        RATCHET_DEGRADED.store(degraded, Ordering::SeqCst);
+        Ok(removed)
+    })).await;
+    match removed {
+        Ok(true) => status::Custom(Status::Ok, ""),
+        Ok(false) => status::Custom(Status::Gone, ""),
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unable to remove from quarantine: {:?}", e);
+            status::Custom(Status::InternalServerError, "")
+        },
+    }
+}
+
+/// Everything a backup restores, in the clear only inside the age envelope.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetBackupPayload {
+    users: Vec<RatchetUserEntry>,
+    devs: Vec<RatchetDevEntry>,
+    policy: Vec<RatchetUserCmdPolicy>,
+    api_keys: Vec<RatchetApiKey>,
+    /// What the keys are hashed under, hex; from schema version 5.
+    #[serde(default)]
+    api_key_hmac: Option<String>,
+    /// From schema version 6, older backups leave the admins alone.
+    #[serde(default)]
+    admins: Vec<RatchetAdminEntry>,
+}
+
+const RATCHET_BACKUP_FORMAT: u32 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetBackupManifest {
+    format: u32,
+    pawl_version: String,
+    schema_version: u32,
+    created_at: u64,
+    users: usize,
+    devs: usize,
+    policies: usize,
+    api_keys: usize,
+    #[serde(default)]
+    admins: usize,
+    /// Of the payload, hex.
+    sha256: String,
+    /// The pawl that signed this, an Ed25519 public key, hex.
+    signer: String,
+}
+
+/// The manifest and payload are kept as the exact strings that were
+/// checksummed and signed.
+#[derive(Debug, Serialize, Deserialize)]
+struct RatchetBackupArchive {
+    manifest: String,
+    payload: String,
+    signature: String,
+}
+
+/// This pawl's backup signing key, made the first time it's needed.
+fn rtp_backup_signing_key() -> Result<ed25519_dalek::SigningKey, RatchetStoreError> {
+    let mut txn = RATCHET_STORE.begin()?;
+    if let Some(secret) = txn.get(RATCHET_META_TABLE.name(), RATCHET_META_BACKUP_SIGNING_KEY)? {
+        let secret: [u8; 32] = secret.as_slice().try_into().map_err(|_| RatchetStoreError::Format(String::from("backup signing key is not 32 bytes")))?;
+        return Ok(ed25519_dalek::SigningKey::from_bytes(&secret));
+    }
+    let secret = rand::random::<[u8; 32]>();
+    txn.put(RATCHET_META_TABLE.name(), RATCHET_META_BACKUP_SIGNING_KEY, &secret)?;
+    txn.commit()?;
+    Ok(ed25519_dalek::SigningKey::from_bytes(&secret))
+}
+
+/// All five tables, out of one transaction.
+fn rtp_snapshot() -> Result<RatchetBackupPayload, RatchetStoreError> {
+    let mut txn = RATCHET_STORE.begin_read()?;
+    let (users, mut bad) = RATCHET_USERS_TABLE.scan::<RatchetUserEntry>(&mut txn)?;
+    let (devs, b) = RATCHET_DEVS_TABLE.scan::<RatchetDevEntry>(&mut txn)?;
+    bad.extend(b);
+    let (policy, b) = RATCHET_USER_CMD_POLICY_TABLE.scan::<RatchetUserCmdPolicy>(&mut txn)?;
+    bad.extend(b);
+    let (api_keys, b) = RATCHET_APIKEY_TABLE.scan::<RatchetApiKey>(&mut txn)?;
+    bad.extend(b);
+    let (admins, b) = RATCHET_ADMINS_TABLE.scan::<RatchetAdminEntry>(&mut txn)?;
+    bad.extend(b);
+    let api_key_hmac = txn.get(RATCHET_META_TABLE.name(), RATCHET_META_API_KEY_HMAC)?;
+    if let Some(q) = bad.first() {
+        return Err(RatchetStoreError::Format(format!("{} is unreadable, run check --repair first", q.id())));
+    }
+    Ok(RatchetBackupPayload {
+        users: users.into_iter().map(|(_, v)| v).collect(),
+        devs: devs.into_iter().map(|(_, v)| v).collect(),
+        policy: policy.into_iter().map(|(_, v)| v).collect(),
+        api_keys: api_keys.into_iter().map(|(_, v)| v).collect(),
+        api_key_hmac: api_key_hmac.map(hex::encode),
+        admins: admins.into_iter().map(|(_, v)| v).collect(),
+    })
+}
+
+/// A snapshot of the database, signed and then encrypted to an age
+/// recipient (`age1...`, an X25519 public key).
+fn rtp_create_backup(recipient: &str) -> Result<(Vec<u8>, RatchetBackupManifest), String> {
+    use ed25519_dalek::Signer;
+    let recipient = age::x25519::Recipient::from_str(recipient.trim()).map_err(|e| format!("Bad recipient: {}", e))?;
+    let signing_key = rtp_backup_signing_key().map_err(|e| format!("{:?}", e))?;
+    let snapshot = rtp_snapshot().map_err(|e| format!("{:?}", e))?;
+    let schema_version = RATCHET_STORE.begin_read()
+                             .and_then(|mut txn| txn.raw.get(RATCHET_META_TABLE.name(), RATCHET_META_SCHEMA_VERSION))
+                             .and_then(rtp_parse_schema_version)
+                             .map_err(|e| format!("{:?}", e))?;
+
+    let payload = serde_json::to_string(&snapshot).map_err(|e| e.to_string())?;
+    let manifest = RatchetBackupManifest {
+        format: RATCHET_BACKUP_FORMAT,
+        pawl_version: String::from(env!("CARGO_PKG_VERSION")),
+        schema_version,
+        created_at: rtp_unix_now(),
+        users: snapshot.users.len(),
+        devs: snapshot.devs.len(),
+        policies: snapshot.policy.len(),
+        api_keys: snapshot.api_keys.len(),
+        admins: snapshot.admins.len(),
+        sha256: hex::encode(Sha256::digest(payload.as_bytes())),
+        signer: hex::encode(signing_key.verifying_key().to_bytes()),
+    };
+    let manifest_ser = serde_json::to_string(&manifest).map_err(|e| e.to_string())?;
+    let archive = RatchetBackupArchive {
+        signature: hex::encode(signing_key.sign(manifest_ser.as_bytes()).to_bytes()),
+        manifest: manifest_ser,
+        payload,
+    };
+    let archive = serde_json::to_vec(&archive).map_err(|e| e.to_string())?;
+    let encrypted = age::encrypt(&recipient, &archive).map_err(|e| format!("Unable to encrypt backup: {}", e))?;
+    Ok((encrypted, manifest))
+}
+
+/// Decrypts with an age identity (the contents of an identity file), and
+/// checks everything before anything gets replaced: the signer is this
+/// pawl, or listed in RATCHET_PAWL_BACKUP_SIGNERS, the signature, checksum
+/// and counts match, and the contents would pass `check`.
+fn rtp_open_backup(archive: &[u8], identity: &str) -> Result<(RatchetBackupManifest, RatchetBackupPayload), String> {
+    let identity = identity.lines()
+                           .map(|l| l.trim())
+                           .find(|l| !l.is_empty() && !l.starts_with('#'))
+                           .ok_or(String::from("No identity given."))?;
+    let identity = age::x25519::Identity::from_str(identity).map_err(|e| format!("Bad identity: {}", e))?;
+    let archive = age::decrypt(&identity, archive).map_err(|e| format!("Unable to decrypt backup: {}", e))?;
+    let archive: RatchetBackupArchive = serde_json::from_slice(&archive).map_err(|e| format!("Not a pawl backup: {}", e))?;
+    // not trusted until the signature checks out, just to find the signer
+    let manifest: RatchetBackupManifest = serde_json::from_str(&archive.manifest).map_err(|e| format!("Bad manifest: {}", e))?;
+
+    let own = rtp_backup_signing_key().map_err(|e| format!("{:?}", e))?;
+    let own = hex::encode(own.verifying_key().to_bytes());
+    let trusted = rtp_env_key("RATCHET_PAWL_BACKUP_SIGNERS");
+    if manifest.signer != own && !trusted.split(',').any(|s| s.trim() == manifest.signer) {
+        return Err(format!("Backup was signed by {}, which is not this pawl nor in RATCHET_PAWL_BACKUP_SIGNERS.", manifest.signer));
+    }
+    let signer = hex::decode(&manifest.signer).ok()
+                     .and_then(|b| <[u8; 32]>::try_from(b.as_slice()).ok())
+                     .and_then(|b| ed25519_dalek::VerifyingKey::from_bytes(&b).ok())
+                     .ok_or(String::from("Bad signer key."))?;
+    let signature = hex::decode(&archive.signature).ok()
+                        .and_then(|b| ed25519_dalek::Signature::from_slice(&b).ok())
+                        .ok_or(String::from("Bad signature."))?;
+    signer.verify_strict(archive.manifest.as_bytes(), &signature).map_err(|_| String::from("Backup signature does not verify."))?;
+
+    if manifest.format != RATCHET_BACKUP_FORMAT {
+        return Err(format!("Unknown backup format {}.", manifest.format));
+    }
+    if manifest.schema_version > RATCHET_SCHEMA_VERSION {
+        return Err(format!("Backup schema version {} is newer than this pawl ({}).", manifest.schema_version, RATCHET_SCHEMA_VERSION));
+    }
+    if hex::encode(Sha256::digest(archive.payload.as_bytes())) != manifest.sha256 {
+        return Err(String::from("Backup checksum does not match."));
+    }
+    let mut payload: serde_json::Value = serde_json::from_str(&archive.payload).map_err(|e| format!("Bad payload: {}", e))?;
+    // keys were in the clear before schema version 5, they're hashed here like a migration would
+    if manifest.schema_version < 5 {
+        let hmac = rtp_load_api_key_secret().map_err(|e| format!("{:?}", e))?;
+        if let Some(api_keys) = payload.get_mut("api_keys").and_then(|k| k.as_array_mut()) {
+            for k in api_keys.iter_mut() {
+                let old = if manifest.schema_version < 4 {
+                    RatchetApiKeyV4::from_v1(k.get("api_key").and_then(|a| a.as_str()).unwrap_or("").to_string())
+                } else {
+                    serde_json::from_value(k.take()).map_err(|e| format!("Bad payload: {}", e))?
+                };
+                let new = old.hashed(&hmac).map_err(|e| format!("{:?}", e))?;
+                *k = serde_json::to_value(new).map_err(|e| format!("Bad payload: {}", e))?;
+            }
+        }
+    }
+    let mut payload: RatchetBackupPayload = serde_json::from_value(payload).map_err(|e| format!("Bad payload: {}", e))?;
+    if (payload.users.len(), payload.devs.len(), payload.policy.len(), payload.api_keys.len(), payload.admins.len())
+       != (manifest.users, manifest.devs, manifest.policies, manifest.api_keys, manifest.admins) {
+        return Err(String::from("Backup counts do not match its manifest."));
+    }
+    // every user was an admin before schema version 6, keep the ones there are now
+    if manifest.schema_version < 6 {
+        let mut txn = RATCHET_STORE.begin_read().map_err(|e| format!("{:?}", e))?;
+        let (admins, _) = RATCHET_ADMINS_TABLE.scan::<RatchetAdminEntry>(&mut txn).map_err(|e| format!("{:?}", e))?;
+        payload.admins = admins.into_iter().map(|(_, v)| v).collect();
+    }
+    if !payload.admins.iter().any(|a| a.role == RatchetRole::Superadmin) {
+        return Err(String::from("Backup has no superadmin."));
+    }
+    if payload.api_keys.is_empty() {
+        return Err(String::from("Backup has no API key."));
+    }
+    if payload.policy.len() != 1 || !rtp_validate_policy(&payload.policy[0].policy) {
+        return Err(String::from("Backup policy does not pass validation."));
+    }
+    Ok((manifest, payload))
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct RatchetDiff {
+    added: usize,
+    removed: usize,
+    changed: usize,
+    unchanged: usize,
+}
+
+fn rtp_diff<T: Serialize + RatchetKeyed>(current: &[T], restored: &[T]) -> RatchetDiff {
+    let current: HashMap<&str, serde_json::Value> = current.iter()
+        .map(|v| (v.into_key(), serde_json::to_value(v).unwrap_or_default()))
+        .collect();
+    let mut diff = RatchetDiff::default();
+    for v in restored.iter() {
+        match current.get(v.into_key()) {
+            None => diff.added += 1,
+            Some(c) if *c == serde_json::to_value(v).unwrap_or_default() => diff.unchanged += 1,
+            Some(_) => diff.changed += 1,
+        }
+    }
+    let restored: HashSet<&str> = restored.iter().map(|v| v.into_key()).collect();
+    diff.removed = current.keys().filter(|k| !restored.contains(*k)).count();
+    diff
+}
+
+/// What a restore would change, or did.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetRestoreSummary {
+    manifest: RatchetBackupManifest,
+    applied: bool,
+    users: RatchetDiff,
+    devs: RatchetDiff,
+    policy: RatchetDiff,
+    api_keys: RatchetDiff,
+    admins: RatchetDiff,
+}
+
+fn rtp_restore_summary(manifest: RatchetBackupManifest, current: &RatchetBackupPayload, restored: &RatchetBackupPayload) -> RatchetRestoreSummary {
+    RatchetRestoreSummary {
+        manifest,
+        applied: false,
+        users: rtp_diff(&current.users, &restored.users),
+        devs: rtp_diff(&current.devs, &restored.devs),
+        policy: rtp_diff(&current.policy, &restored.policy),
+        api_keys: rtp_diff(&current.api_keys, &restored.api_keys),
+        admins: rtp_diff(&current.admins, &restored.admins),
+    }
+}
+
+/// What turns the rows in `current` into the ones in `restored`.
+fn rtp_changes<T>(table: &'static ReadWriteTable<'static, &'static str, Vec<u8>, T>, current: &[T], restored: &[T], actor: &str) -> Result<Vec<RatchetChange>, RatchetStoreError>
+where T: Serialize + DeserializeOwned + RatchetKeyed {
+    let mut changes = vec![];
+    let keep: HashSet<&str> = restored.iter().map(|v| v.into_key()).collect();
+    for v in current.iter().filter(|v| !keep.contains(v.into_key())) {
+        changes.push(table.remove(v.into_key(), actor));
+    }
+    let current: HashMap<&str, serde_json::Value> = current.iter()
+        .map(|v| (v.into_key(), serde_json::to_value(v).unwrap_or_default()))
+        .collect();
+    for v in restored.iter() {
+        if current.get(v.into_key()) != Some(&serde_json::to_value(v).unwrap_or_default()) {
+            changes.push(table.put(v, actor)?);
+        }
+    }
+    Ok(changes)
+}
+
+fn rtp_restore_changes(current: &RatchetBackupPayload, restored: &RatchetBackupPayload, actor: &str) -> Result<Vec<RatchetChange>, RatchetStoreError> {
+    Ok([rtp_api_key_secret_change(restored, actor)?.into_iter().collect(),
+        rtp_changes(&RATCHET_USERS_TABLE, &current.users, &restored.users, actor)?,
+        rtp_changes(&RATCHET_DEVS_TABLE, &current.devs, &restored.devs, actor)?,
+        rtp_changes(&RATCHET_USER_CMD_POLICY_TABLE, &current.policy, &restored.policy, actor)?,
+        rtp_changes(&RATCHET_APIKEY_TABLE, &current.api_keys, &restored.api_keys, actor)?,
+        rtp_changes(&RATCHET_ADMINS_TABLE, &current.admins, &restored.admins, actor)?].into_iter().flatten().collect())
+}
+
+#[derive(FromForm)]
+struct RatchetBackupRequest {
+    recipient: String,
+}
+
+/// Frontend API for taking a backup, encrypted to the given age recipient.
+#[post("/backup", format = "multipart/form-data", data = "<req>")]
+async fn backup(admin: RatchetAdmin<RatchetPermSuperadmin>, req: Form<RatchetBackupRequest>) -> Result<(ContentType, Vec<u8>), status::Custom<String>> {
+    // whatever's been accepted should be in it
+    let _ = rtp_persist_flush().done().await;
+    let recipient = req.recipient.clone();
+    match rtp_blocking(move || rtp_create_backup(&recipient)).await {
+        Ok((archive, manifest)) => {
+            println!("Ratchet-Pawl backup taken, {:?}", manifest);
+            rtp_audit("backup", &admin.username, &req.recipient, admin.source_ip, format!("sha256 {}", manifest.sha256)).await;
+            Ok((ContentType::Binary, archive))
+        },
+        Err(e) => Err(status::Custom(Status::Conflict, e)),
+    }
+}
+
+#[derive(FromForm)]
+struct RatchetRestoreRequest<'r> {
+    archive: TempFile<'r>,
+    identity: String,
+    dry_run: bool,
+}
+
+/// Frontend API for restoring a backup. Always answers with what changes,
+/// and with `dry_run` that's all it does.
+#[post("/restore", format = "multipart/form-data", data = "<req>")]
+async fn restore(admin: RatchetAdmin<RatchetPermSuperadmin>, req: Form<RatchetRestoreRequest<'_>>) -> Result<Json<RatchetRestoreSummary>, status::Custom<String>> {
+    let mut archive = vec![];
+    match req.archive.open().await {
+        Ok(mut f) => { let _ = rocket::tokio::io::AsyncReadExt::read_to_end(&mut f, &mut archive).await; },
+        Err(e) => return Err(status::Custom(Status::BadRequest, format!("Unable to read archive: {}", e))),
+    }
+    let identity = req.identity.clone();
+    let (manifest, restored) = rtp_blocking(move || rtp_open_backup(&archive, &identity)).await
+                                   .map_err(|e| status::Custom(Status::Conflict, e))?;
+
+    // nothing else changes until this is done
+    let mut maps = RatchetMaps::lock().await;
+    let current = rtp_blocking(rtp_snapshot).await.map_err(|e| status::Custom(Status::InternalServerError, format!("{:?}", e)))?;
+    let mut summary = rtp_restore_summary(manifest, &current, &restored);
+    if req.dry_run {
+        return Ok(Json(summary));
+    }
+
+    let actor = admin.username.clone();
+    let changes = rtp_blocking(move || rtp_restore_changes(&current, &restored, &actor)).await
+                      .map_err(|e| status::Custom(Status::InternalServerError, format!("{:?}", e)))?;
+    maps.apply(changes).await.map_err(|e| status::Custom(Status::Conflict, e))?;
+    drop(maps);
+    summary.applied = true;
+    println!("Ratchet-Pawl restored backup, {:?}", summary);
+    rocket::tokio::spawn(rtp_notify_pollers());
+    Ok(Json(summary))
+}
+
+/// Every change to the five tables, in order, sealed like the rows are.
+/// Keys are positions, counting from 1; only the last RATCHET_JOURNAL_KEEP
+/// are kept, see rtp_trim_journal.
+const RATCHET_JOURNAL_TABLE: TableDefinition<u64, Vec<u8>> = TableDefinition::new("ratchet_journal");
+
+lazy_static! {
+    /// RATCHET_PAWL_JOURNAL_KEEP, default 10000; 0 keeps everything.
+    static ref RATCHET_JOURNAL_KEEP: u64 = rtp_env_key("RATCHET_PAWL_JOURNAL_KEEP").parse::<u64>().unwrap_or(10000);
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetJournalEntry {
+    seq: u64,
+    table: String,
+    record_key: String,
+    /// None when the row didn't exist.
+    previous: Option<serde_json::Value>,
+    /// None when the row was removed.
+    new: Option<serde_json::Value>,
+    actor: String,
+    at: u64,
+}
+
+/// Who changes things when it isn't someone logged in.
+const RATCHET_SYSTEM_ACTOR: &str = "ratchet-pawl";
+const RATCHET_PAWLCTL_ACTOR: &str = "pawlctl";
+
+/// Appends an entry inside the caller's transaction.
+fn rtp_journal(txn: &mut RatchetEncryptedTxn, c: &RatchetChange, previous: Option<serde_json::Value>, new: Option<serde_json::Value>) -> Result<RatchetJournalEntry, RatchetStoreError> {
+    let mut entry = RatchetJournalEntry {
+        seq: 0,
+        table: c.table.to_string(),
+        record_key: c.record_key.clone(),
+        previous,
+        new,
+        actor: c.actor.clone(),
+        at: rtp_unix_now(),
+    };
+    txn.log_append(RATCHET_JOURNAL_TABLE.name(), |seq| {
+        entry.seq = seq;
+        serde_json::to_vec(&entry).map_err(|e| RatchetStoreError::Format(e.to_string()))
+    })?;
+    Ok(entry)
+}
+
+/// Drops the oldest entries past RATCHET_JOURNAL_KEEP, inside the caller's
+/// transaction. Rollback can't go back further than what's left.
+fn rtp_trim_journal(txn: &mut RatchetEncryptedTxn) -> Result<(), RatchetStoreError> {
+    let keep = *RATCHET_JOURNAL_KEEP;
+    let last = txn.raw.log_last(RATCHET_JOURNAL_TABLE.name())?;
+    if keep > 0 && last > keep {
+        txn.raw.log_trim(RATCHET_JOURNAL_TABLE.name(), last - keep)?;
+    }
+    Ok(())
+}
+
+fn rtp_journal_op(e: &RatchetJournalEntry) -> &'static str {
+    match (&e.previous, &e.new) {
+        (None, Some(_)) => "add",
+        (Some(_), None) => "rm",
+        _ => "edit",
+    }
+}
+
+/// Up to `limit` journal entries after `after`, in order.
+fn rtp_read_journal(after: u64, limit: usize) -> Result<Vec<RatchetJournalEntry>, RatchetStoreError> {
+    RATCHET_STORE.begin_read()?
+        .log_scan(RATCHET_JOURNAL_TABLE.name(), after, limit)?
+        .iter()
+        .map(|pt| serde_json::from_slice(pt).map_err(|e| RatchetStoreError::Format(e.to_string())))
+        .collect()
+}
+
+/// Seals a log again under `new`, inside the caller's transaction.
+fn rtp_reseal_log(write_txn: &WriteTransaction, log: TableDefinition<u64, Vec<u8>>, old: &[u8; 32], new: &[u8; 32]) -> Result<usize, RatchetStoreError> {
+    let mut table = write_txn.open_table(log)?;
+    let mut rows = vec![];
+    for tup in table.iter()? {
+        let (k, v) = tup?;
+        rows.push((k.value(), v.value()));
+    }
+    for (seq, stored) in rows.iter() {
+        let pt = rtp_open_record(old, log.name(), &seq.to_string(), stored)?;
+        table.insert(*seq, rtp_seal_record(new, log.name(), &seq.to_string(), &pt)?)?;
+    }
+    Ok(rows.len())
+}
+
+fn rtp_table_by_name(name: &str) -> Option<&'static str> {
+    [RATCHET_USERS_TABLE.name(), RATCHET_DEVS_TABLE.name(), RATCHET_USER_CMD_POLICY_TABLE.name(), RATCHET_APIKEY_TABLE.name(), RATCHET_ADMINS_TABLE.name()]
+        .into_iter()
+        .find(|t| *t == name)
+}
+
+/// What it takes to put objects back the way they were right after entry
+/// `to`: each object changed since goes back to its `previous` from the
+/// first change after `to`. Only objects matching `only`, if given.
+fn rtp_rollback_changes(entries: &[RatchetJournalEntry], to: u64, only: Option<(&str, &str)>, actor: &str) -> Result<Vec<RatchetChange>, RatchetStoreError> {
+    let mut seen = HashSet::new();
+    let mut changes = vec![];
+    for e in entries.iter().filter(|e| e.seq > to) {
+        if only.is_some_and(|(t, k)| t != e.table || k != e.record_key) {
+            continue;
+        }
+        if !seen.insert((e.table.clone(), e.record_key.clone())) {
+            continue;
+        }
+        let table = rtp_table_by_name(&e.table).ok_or(RatchetStoreError::Format(format!("unknown table {}", e.table)))?;
+        // migration 5 hashes these, but a journal from elsewhere might not be
+        if table == RATCHET_APIKEY_TABLE.name() && e.previous.as_ref().is_some_and(|v| serde_json::from_value::<RatchetApiKey>(v.clone()).is_err()) {
+            return Err(RatchetStoreError::Format(format!("entry {} has API key {} from before keys were kept hashed, it can't be put back; add a new key instead", e.seq, e.record_key)));
+        }
+        let op = match &e.previous {
+            Some(v) => RatchetPersistOp::Put(serde_json::to_vec(v).map_err(|e| RatchetStoreError::Format(e.to_string()))?),
+            None => RatchetPersistOp::Remove,
+        };
+        changes.push(RatchetChange { table, record_key: e.record_key.clone(), op, actor: actor.to_string() });
+    }
+    Ok(changes)
+}
+
+const RATCHET_EMPTY_POLICY: &str = "$\n(\n)";
+
+/// The in-memory maps, all locked, so a batch of changes lands everywhere at once.
+struct RatchetMaps<'m> {
+    users: rocket::tokio::sync::MutexGuard<'m, HashMap<String, RatchetUserEntry>>,
+    devs: rocket::tokio::sync::MutexGuard<'m, HashMap<String, RatchetDevEntry>>,
+    policy: rocket::tokio::sync::MutexGuard<'m, RatchetUserCmdPolicy>,
+    api_keys: rocket::tokio::sync::MutexGuard<'m, HashMap<String, RatchetApiKey>>,
+    admins: rocket::tokio::sync::MutexGuard<'m, HashMap<String, RatchetAdminEntry>>,
+    cookie_store: rocket::tokio::sync::MutexGuard<'m, HashMap<String, RatchetSession>>,
+    user_cookies: rocket::tokio::sync::MutexGuard<'m, HashMap<String, HashSet<String>>>,
+}
+
+impl RatchetMaps<'_> {
+    /// Takes every lock, in the usual order, and waits out queued writes.
+    async fn lock() -> RatchetMaps<'static> {
+        let maps = RatchetMaps {
+            users: RATCHET_USERS.lock().await,
+            devs: RATCHET_DEVICES.lock().await,
+            policy: RATCHET_USER_CMD_POLICY.lock().await,
+            api_keys: RATCHET_APIKEYS.lock().await,
+            admins: RATCHET_ADMINS.lock().await,
+            cookie_store: RATCHET_COOKIES.lock().await,
+            user_cookies: RATCHET_USER_COOKIES.lock().await,
+        };
+        let _ = rtp_persist_flush().done().await;
+        maps
+    }
+
+    /// Commits the changes, journaled, and mirrors them in the maps, but only
+    /// if pawl would still have a superadmin, an API key and a valid policy
+    /// after. Any admin whose login or role changed is logged out.
+    async fn apply(&mut self, changes: Vec<RatchetChange>) -> Result<(), String> {
+        let tables = RatchetTables {
+            users: self.users.clone(),
+            devs: self.devs.clone(),
+            policy: self.policy.clone(),
+            api_keys: self.api_keys.clone(),
+            admins: self.admins.clone(),
+        }.changed(&changes)?;
+
+        rtp_blocking(move || rtp_apply_changes(&changes)).await.map_err(|e| format!("{:?}", e))?;
+
+        for (username, admin) in self.admins.iter() {
+            if tables.admins.get(username).is_none_or(|a| a.passhash != admin.passhash || a.role != admin.role) {
+                if let Some(active_cookies) = self.user_cookies.remove(username) {
+                    active_cookies.into_iter().for_each(|each_cookie| {self.cookie_store.remove(&each_cookie);});
+                }
+            }
+        }
+        *self.users = tables.users;
+        *self.devs = tables.devs;
+        *self.policy = tables.policy;
+        *self.api_keys = tables.api_keys;
+        *self.admins = tables.admins;
+        Ok(())
+    }
+}
+
+/// The five tables as the maps have them, to try changes on.
+struct RatchetTables {
+    users: HashMap<String, RatchetUserEntry>,
+    devs: HashMap<String, RatchetDevEntry>,
+    policy: RatchetUserCmdPolicy,
+    api_keys: HashMap<String, RatchetApiKey>,
+    admins: HashMap<String, RatchetAdminEntry>,
+}
+
+impl RatchetTables {
+    /// From a snapshot, for when pawl isn't running to have the maps.
+    fn of(snapshot: &RatchetBackupPayload) -> RatchetTables {
+        RatchetTables {
+            users: snapshot.users.iter().map(|u| (u.username.clone(), u.clone())).collect(),
+            devs: snapshot.devs.iter().map(|d| (d.network_id.clone(), d.clone())).collect(),
+            policy: snapshot.policy.first().cloned().unwrap_or(RatchetUserCmdPolicy::new(String::from(RATCHET_EMPTY_POLICY), RatchetStamp::default())),
+            api_keys: snapshot.api_keys.iter().map(|k| (k.prefix.clone(), k.clone())).collect(),
+            admins: snapshot.admins.iter().map(|a| (a.username.clone(), a.clone())).collect(),
+        }
+    }
+
+    /// The tables after the changes, but only if pawl would still have a
+    /// superadmin, an API key and a valid policy.
+    fn changed(mut self, changes: &[RatchetChange]) -> Result<RatchetTables, String> {
+        let RatchetTables { users, devs, policy, api_keys, admins } = &mut self;
+        for c in changes.iter() {
+            let ser = match &c.op {
+                RatchetPersistOp::Put(ser) => Some(ser),
+                RatchetPersistOp::ApiKeySecret(_) => continue,
+                _ => None,
+            };
+            let bad = |e: serde_json::Error| format!("{}/{}: {}", c.table, c.record_key, e);
+            match c.table {
+                n if n == RATCHET_USERS_TABLE.0.name() => match ser {
+                    Some(ser) => { users.insert(c.record_key.clone(), serde_json::from_slice(ser).map_err(bad)?); },
+                    None => { users.remove(&c.record_key); },
+                },
+                n if n == RATCHET_DEVS_TABLE.0.name() => match ser {
+                    Some(ser) => { devs.insert(c.record_key.clone(), serde_json::from_slice(ser).map_err(bad)?); },
+                    None => { devs.remove(&c.record_key); },
+                },
+                n if n == RATCHET_USER_CMD_POLICY_TABLE.0.name() => match ser {
+                    Some(ser) => { *policy = serde_json::from_slice(ser).map_err(bad)?; },
+                    // pawl makes a new one on the next start anyway
+                    None => { *policy = RatchetUserCmdPolicy::new(String::from(RATCHET_EMPTY_POLICY), RatchetStamp::new(&c.actor)); },
+                },
+                // rows are by name, the map is by the key itself
+                n if n == RATCHET_APIKEY_TABLE.0.name() => match ser {
+                    Some(ser) => {
+                        let k: RatchetApiKey = serde_json::from_slice(ser).map_err(bad)?;
+                        api_keys.retain(|_, v| v.name != k.name);
+                        api_keys.insert(k.prefix.clone(), k);
+                    },
+                    None => { api_keys.retain(|_, v| v.name != c.record_key); },
+                },
+                n if n == RATCHET_ADMINS_TABLE.0.name() => match ser {
+                    Some(ser) => { admins.insert(c.record_key.clone(), serde_json::from_slice(ser).map_err(bad)?); },
+                    None => { admins.remove(&c.record_key); },
+                },
+                n => return Err(format!("unknown table {}", n)),
+            }
+        }
+        if !admins.values().any(|a| a.role == RatchetRole::Superadmin) {
+            return Err(String::from("that would leave no superadmin"));
+        }
+        if api_keys.is_empty() {
+            return Err(String::from("that would leave no API key"));
+        }
+        if !rtp_validate_policy(&policy.policy) {
+            return Err(String::from("that would leave a policy that does not pass validation"));
+        }
+        Ok(self)
+    }
+}
+
+/// The user, device or policy fields an entry changed. Secrets show only
+/// that they changed.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetFieldDiff {
+    field: String,
+    old: Option<serde_json::Value>,
+    new: Option<serde_json::Value>,
+}
+
+const RATCHET_SECRET_FIELDS: &[&str] = &["passhash", "key", "api_key"];
+const RATCHET_STAMP_FIELDS: &[&str] = &["created_by", "created_at", "updated_by", "updated_at"];
+
+fn rtp_journal_diff(e: &RatchetJournalEntry) -> Vec<RatchetFieldDiff> {
+    // the policy was a bare string before schema version 7, everything else an object
+    let fields = |v: &Option<serde_json::Value>| -> serde_json::Map<String, serde_json::Value> {
+        match v {
+            Some(serde_json::Value::Object(m)) => m.clone(),
+            Some(other) => serde_json::Map::from_iter([(String::from("policy"), other.clone())]),
+            None => serde_json::Map::new(),
+        }
+    };
+    let (mut old, mut new) = (fields(&e.previous), fields(&e.new));
+    // a null field is as good as a missing one, and the stamp is the entry's own actor and time
+    old.retain(|f, v| !v.is_null() && !RATCHET_STAMP_FIELDS.contains(&f.as_str()));
+    new.retain(|f, v| !v.is_null() && !RATCHET_STAMP_FIELDS.contains(&f.as_str()));
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    names.into_iter()
+         .filter(|f| old.get(*f) != new.get(*f))
+         .map(|f| {
+             let show = |v: Option<&serde_json::Value>| match v {
+                 Some(_) if RATCHET_SECRET_FIELDS.contains(&f.as_str()) => Some(serde_json::Value::from("(secret)")),
+                 v => v.cloned(),
+             };
+             RatchetFieldDiff { field: f.clone(), old: show(old.get(f)), new: show(new.get(f)) }
+         })
+         .collect()
+}
+
+/// A journal entry for the frontend, as a diff. API keys are their own
+/// record keys, so those are hidden too.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetFrontendJournalEntry {
+    seq: u64,
+    table: String,
+    record_key: String,
+    op: &'static str,
+    actor: String,
+    at: u64,
+    diff: Vec<RatchetFieldDiff>,
 }
 
-impl RatchetKeyed for RatchetDevEntry{
-    fn into_key(&self) -> &str {
-        self.network_id.as_str()
+impl From<&RatchetJournalEntry> for RatchetFrontendJournalEntry {
+    fn from(e: &RatchetJournalEntry) -> Self {
+        RatchetFrontendJournalEntry {
+            seq: e.seq,
+            table: e.table.clone(),
+            record_key: e.record_key.clone(),
+            op: rtp_journal_op(e),
+            actor: e.actor.clone(),
+            at: e.at,
+            diff: rtp_journal_diff(e),
+        }
     }
 }
 
-/// Frontend API for removing devices.
-#[post("/rmdev", format = "multipart/form-data", data = "<network_id>")]
-async fn rm_dev(_admin: RatchetUser, network_id: Form<String>) -> status::Custom<&'static str> {
-    let mut devs = RATCHET_DEVICES.lock().await;
-    match devs.remove(&*network_id) {
-        Some(dev) => {
-            RATCHET_DEVS_TABLE.rm(&dev).await.expect("Database error");
-            rocket::tokio::spawn(rtp_notify_pollers());
-            status::Custom(Status::Ok, "")
+/// Frontend API for the journal, `limit` (default 100) entries after `after`.
+#[get("/getjournal?<after>&<limit>")]
+async fn get_journal(_admin: RatchetAdmin<RatchetPermView>, after: Option<u64>, limit: Option<usize>) -> Result<Json<Vec<RatchetFrontendJournalEntry>>, Status> {
+    match rtp_blocking(move || rtp_read_journal(after.unwrap_or(0), limit.unwrap_or(100))).await {
+        Ok(entries) => Ok(Json(entries.iter().map(RatchetFrontendJournalEntry::from).collect())),
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unable to read journal: {:?}", e);
+            Err(Status::InternalServerError)
         },
-        None => status::Custom(Status::Gone, ""),
     }
 }
 
-/// Frontend API for adding devices.
-///  
-/// TODO: Input validation, password policy
-/// 
-#[post("/adddev", format = "multipart/form-data", data = "<newdev>")]
-async fn add_dev(_admin: RatchetUser, newdev: Form<RatchetDevEntry>) -> status::Custom<&'static str> {
-    let mut devs = RATCHET_DEVICES.lock().await;
-    // TODO: Replace this with networkier stuff
-    if !devs.contains_key(&newdev.network_id) {
-        let new_dev = newdev.to_owned();
-        RATCHET_DEVS_TABLE.write(&new_dev).await.expect("Database error");
-        devs.insert(new_dev.network_id.clone(), new_dev);
-        rocket::tokio::spawn(rtp_notify_pollers());
-        status::Custom(Status::Ok, "")
-    } else {
-        status::Custom(Status::Conflict, "")
+/// Frontend API for the history of one user, device, or the policy
+/// (`ratchet_user_cmd_policy`, `singleton`).
+#[get("/gethistory?<table>&<key>")]
+async fn get_history(_admin: RatchetAdmin<RatchetPermView>, table: &str, key: &str) -> Result<Json<Vec<RatchetFrontendJournalEntry>>, Status> {
+    match rtp_blocking(|| rtp_read_journal(0, usize::MAX)).await {
+        Ok(entries) => Ok(Json(entries.iter()
+                                      .filter(|e| e.table == table && e.record_key == key)
+                                      .map(RatchetFrontendJournalEntry::from)
+                                      .collect())),
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unable to read journal: {:?}", e);
+            Err(Status::InternalServerError)
+        },
     }
 }
 
-/// Frontend API for editing devices.
-///  
-/// TODO: Input validation, password policy
-/// 
-#[post("/editdev", format = "multipart/form-data", data = "<edited>")]
-async fn edit_dev(_admin: RatchetUser, edited: Form<RatchetDevEntry>) -> status::Custom<&'static str> {
-    let mut devs = RATCHET_DEVICES.lock().await;
-    if !devs.contains_key(&edited.network_id) {
-        status::Custom(Status::Gone, "")
-    } else {
-        let dev_update = edited.to_owned();
-        RATCHET_DEVS_TABLE.write(&dev_update).await.expect("Database error");
-        devs.insert(dev_update.network_id.clone(), dev_update);
-        rocket::tokio::spawn(rtp_notify_pollers());
-        status::Custom(Status::Ok, "")
+#[derive(FromForm)]
+struct RatchetRollback {
+    /// Back to right after this entry, 0 for before the first.
+    to: u64,
+    /// Only the object this entry changed, otherwise everything.
+    object: Option<u64>,
+}
+
+/// Frontend API for rolling back one object, or everything, to a position
+/// in the journal. The rollback is itself journaled, so it can be undone.
+#[post("/rollback", format = "multipart/form-data", data = "<req>")]
+async fn rollback(admin: RatchetAdmin<RatchetPermSuperadmin>, req: Form<RatchetRollback>) -> status::Custom<String> {
+    let mut maps = RatchetMaps::lock().await;
+    let (to, object) = (req.to, req.object);
+    let read = rtp_blocking(move || -> Result<_, RatchetStoreError> {
+        let first = rtp_read_journal(0, 1)?.first().map(|e| e.seq);
+        let object = match object {
+            Some(seq) => rtp_read_journal(seq.saturating_sub(1), 1)?.into_iter().find(|e| e.seq == seq).map(Some),
+            None => Some(None),
+        };
+        Ok((first, object, rtp_read_journal(to, usize::MAX)?))
+    }).await;
+    let (entries, object) = match read {
+        Ok((Some(first), _, _)) if to.saturating_add(1) < first => {
+            return status::Custom(Status::Gone, format!("The journal only goes back to entry {}.", first));
+        },
+        Ok((_, None, _)) => return status::Custom(Status::Gone, String::from("No such journal entry.")),
+        Ok((_, Some(object), entries)) => (entries, object),
+        Err(e) => return status::Custom(Status::InternalServerError, format!("{:?}", e)),
+    };
+    let only = object.as_ref().map(|e| (e.table.as_str(), e.record_key.as_str()));
+    let changes = match rtp_rollback_changes(&entries, req.to, only, &admin.username) {
+        Ok(c) => c,
+        Err(RatchetStoreError::Format(e)) => return status::Custom(Status::Conflict, e),
+        Err(e) => return status::Custom(Status::InternalServerError, format!("{:?}", e)),
+    };
+    let n = changes.len();
+    match maps.apply(changes).await {
+        Ok(()) => {
+            drop(maps);
+            println!("Ratchet-Pawl rolled back {} objects to journal position {}.", n, req.to);
+            rocket::tokio::spawn(rtp_notify_pollers());
+            status::Custom(Status::Ok, format!("{}", n))
+        },
+        Err(e) => status::Custom(Status::Conflict, e),
     }
 }
 
-/// Special structure to only return safe devdata
-/// back to the Frontend.
-#[derive(Clone, FromForm, Debug, Serialize)]
-struct RatchetFrontendDevEntry {
-    network_id: String,
+/// Logins and logouts, every change, API key use and misuse, long poll
+/// subscriptions and policies that fail validation, for auditors. Numbered and sealed like the journal, and
+/// each entry carries the hash of the one before it, so an entry removed
+/// or edited breaks the chain; the last hash is kept in meta too, so the
+/// tail can't quietly go either, see `ratchet-pawl verify-audit`. Never
+/// restored, rolled back or pruned.
+const RATCHET_AUDIT_TABLE: TableDefinition<u64, Vec<u8>> = TableDefinition::new("ratchet_audit");
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetAuditEntry {
+    seq: u64,
+    at: u64,
+    /// e.g. `login`, `login_failed`, `logout`, `sessions_rm`, `user_add`,
+    /// `dev_edit`, `policy_push`, `policy_rejected`, `api_use`,
+    /// `api_key_rejected`, `poll_subscribe`.
+    event: String,
+    actor: String,
+    /// What it was done to: a username, network ID, key name, session, route.
+    object: String,
+    source_ip: Option<String>,
+    detail: String,
+    /// The hash of the entry before, RATCHET_AUDIT_GENESIS for the first.
+    prev: String,
+    /// SHA-256 of this entry, with this left empty.
+    hash: String,
 }
 
-/// Frontend API for listing users.
-#[get("/getdevs")]
-async fn get_devs(_admin: RatchetUser) -> Json<Vec<RatchetFrontendDevEntry>> {
-    let devs = RATCHET_DEVICES.lock().await;
-    Json(
-        devs.values()
-            .map(|d| RatchetFrontendDevEntry {
-                network_id: d.network_id.clone(),
-            })
-            .collect::<Vec<RatchetFrontendDevEntry>>(),
-    )
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetAuditHead {
+    seq: u64,
+    hash: String,
 }
 
-/// Frontend API for modifying policy
-///  
-/// TODO: Input validation, password policy
-/// 
-#[post("/pushpolicy", format = "multipart/form-data", data = "<edited>")]
-async fn push_policy(_admin: RatchetUser, edited: Form<RatchetUserCmdPolicy>) -> status::Custom<&'static str> {
-    let mut policy = RATCHET_USER_CMD_POLICY.lock().await;
-    let new_policy = edited.to_owned();
+const RATCHET_AUDIT_GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
 
-    if rtp_validate_policy(&new_policy.0) {
-        *policy = new_policy.clone();
-        RATCHET_USER_CMD_POLICY_TABLE.write(&new_policy).await.expect("Database error");
-        rocket::tokio::spawn(rtp_notify_pollers());
-        status::Custom(Status::Ok, "")
-    } else {
-        status::Custom(Status::Conflict, "")
+fn rtp_audit_hash(e: &RatchetAuditEntry) -> String {
+    let unhashed = RatchetAuditEntry { hash: String::new(), ..e.clone() };
+    hex::encode(Sha256::digest(serde_json::to_vec(&unhashed).unwrap_or_default()))
+}
+
+/// Appends an entry inside the caller's transaction, and moves the head.
+fn rtp_audit_append(txn: &mut RatchetEncryptedTxn, event: &str, actor: &str, object: &str, source_ip: Option<String>, detail: String) -> Result<RatchetAuditEntry, RatchetStoreError> {
+    let head: Option<RatchetAuditHead> = match txn.get(RATCHET_META_TABLE.name(), RATCHET_META_AUDIT_HEAD)? {
+        Some(pt) => Some(serde_json::from_slice(&pt).map_err(|e| RatchetStoreError::Format(e.to_string()))?),
+        None => None,
+    };
+    let mut entry = RatchetAuditEntry {
+        seq: 0,
+        at: rtp_unix_now(),
+        event: event.to_string(),
+        actor: actor.to_string(),
+        object: object.to_string(),
+        source_ip,
+        detail,
+        prev: head.map(|h| h.hash).unwrap_or(String::from(RATCHET_AUDIT_GENESIS)),
+        hash: String::new(),
+    };
+    txn.log_append(RATCHET_AUDIT_TABLE.name(), |seq| {
+        entry.seq = seq;
+        entry.hash = rtp_audit_hash(&entry);
+        serde_json::to_vec(&entry).map_err(|e| RatchetStoreError::Format(e.to_string()))
+    })?;
+    let head = RatchetAuditHead { seq: entry.seq, hash: entry.hash.clone() };
+    let ser = serde_json::to_vec(&head).map_err(|e| RatchetStoreError::Format(e.to_string()))?;
+    txn.put(RATCHET_META_TABLE.name(), RATCHET_META_AUDIT_HEAD, &ser)?;
+    Ok(entry)
+}
+
+/// The audit entry for a change, next to its journal entry. Which fields
+/// changed, but not to what, that's the journal's.
+fn rtp_audit_journaled(txn: &mut RatchetEncryptedTxn, e: &RatchetJournalEntry) -> Result<RatchetAuditEntry, RatchetStoreError> {
+    let event = match e.table.as_str() {
+        t if t == RATCHET_USER_CMD_POLICY_TABLE.name() => String::from("policy_push"),
+        t if t == RATCHET_USERS_TABLE.name() => format!("user_{}", rtp_journal_op(e)),
+        t if t == RATCHET_DEVS_TABLE.name() => format!("dev_{}", rtp_journal_op(e)),
+        t if t == RATCHET_APIKEY_TABLE.name() => format!("apikey_{}", rtp_journal_op(e)),
+        t if t == RATCHET_ADMINS_TABLE.name() => format!("admin_{}", rtp_journal_op(e)),
+        t => format!("{}_{}", t, rtp_journal_op(e)),
+    };
+    let fields: Vec<String> = rtp_journal_diff(e).into_iter().map(|d| d.field).collect();
+    let detail = match fields.is_empty() {
+        true => format!("journal {}", e.seq),
+        false => format!("journal {}: {}", e.seq, fields.join(", ")),
+    };
+    rtp_audit_append(txn, &event, &e.actor, &e.record_key, None, detail)
+}
+
+/// Audits something that isn't a change, and waits for it to be written.
+/// Failing that, it's only said on stderr; whatever it was still happened.
+async fn rtp_audit(event: &'static str, actor: &str, object: &str, source_ip: Option<std::net::IpAddr>, detail: String) {
+    let pending = rtp_persist(RatchetChange {
+        table: RATCHET_AUDIT_TABLE.name(),
+        record_key: object.to_string(),
+        op: RatchetPersistOp::Audit { event, source_ip: source_ip.map(|ip| ip.to_string()), detail },
+        actor: actor.to_string(),
+    });
+    if let Err(e) = pending.done().await {
+        eprintln!("Ratchet-Pawl unable to audit {} by {}: {:?}", event, actor, e);
     }
 }
 
-/// Frontend API for dumping policy.
-#[get("/getpolicy")]
-async fn get_policy(_admin: RatchetUser) -> String {
-    RATCHET_USER_CMD_POLICY.lock().await.0.clone()
+lazy_static! {
+    /// RATCHET_PAWL_AUDIT_REPEAT_SECS, default 60; 0 audits every one.
+    static ref RATCHET_AUDIT_REPEAT: std::time::Duration = std::time::Duration::from_secs(rtp_env_key("RATCHET_PAWL_AUDIT_REPEAT_SECS").parse::<u64>().unwrap_or(60));
+    static ref RATCHET_AUDIT_REPEATS: Mutex<HashMap<RatchetAuditRepeatKey, RatchetAuditRepeat>> = Mutex::new(HashMap::new());
 }
 
-/// Backend API for dumping policy.
-#[get("/api/dumppolicy")]
-async fn api_dump_policy(_valid: RatchetApiKey) -> String {
-    RATCHET_USER_CMD_POLICY.lock().await.0.clone()
+/// What makes two entries the same, for rtp_audit_repeated.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct RatchetAuditRepeatKey {
+    event: &'static str,
+    actor: String,
+    object: String,
+    source_ip: Option<std::net::IpAddr>,
+    detail: String,
 }
 
-/// Backend API for getting user creds.
-#[get("/api/dumpusers")]
-async fn api_dump_users(_valid: RatchetApiKey) -> String {
-    let users = RATCHET_USERS.lock().await;
-    users.iter().fold(
-        String::new(),
-        |mut resp, (user, hash)| {
-            resp.push_str(user);
-            resp.push_str(",");
-            resp.push_str(&hash.passhash);
-            resp.push_str("\n");
-            resp
+struct RatchetAuditRepeat {
+    since: Instant,
+    more: u64,
+}
+
+/// Audits the first of a kind, then only counts the same again until
+/// RATCHET_AUDIT_REPEAT is up, and audits how many more there were as one
+/// entry; a poller or a client with a bad key would otherwise fill the log.
+/// Counts not yet written are lost on shutdown.
+async fn rtp_audit_repeated(event: &'static str, actor: &str, object: &str, source_ip: Option<std::net::IpAddr>, detail: String) {
+    if RATCHET_AUDIT_REPEAT.is_zero() {
+        return rtp_audit(event, actor, object, source_ip, detail).await;
+    }
+    let key = RatchetAuditRepeatKey { event, actor: actor.to_string(), object: object.to_string(), source_ip, detail };
+    let now = Instant::now();
+    let due = {
+        let mut repeats = RATCHET_AUDIT_REPEATS.lock().await;
+        if let Some(r) = repeats.get_mut(&key).filter(|r| now.saturating_duration_since(r.since) < *RATCHET_AUDIT_REPEAT) {
+            r.more += 1;
+            return;
         }
-    )
+        let due = rtp_due_audit_repeats(&mut repeats, now);
+        repeats.insert(key.clone(), RatchetAuditRepeat { since: now, more: 0 });
+        due
+    };
+    rtp_audit_repeats(due).await;
+    rtp_audit(key.event, &key.actor, &key.object, key.source_ip, key.detail).await;
 }
 
-/// Backend API for getting dev keys.
-#[get("/api/dumpdevs")]
-async fn api_dump_devs(_valid: RatchetApiKey) -> String {
-    let devs = RATCHET_DEVICES.lock().await;
-    devs.iter().fold(
-        String::new(),
-        |mut resp, (network, key)| {
-            resp.push_str(network);
-            resp.push_str(",");
-            resp.push_str(&key.key);
-            resp.push_str("\n");
-            resp
+/// Takes out the counts whose time is up, and says which had any.
+fn rtp_due_audit_repeats(repeats: &mut HashMap<RatchetAuditRepeatKey, RatchetAuditRepeat>, now: Instant) -> Vec<(RatchetAuditRepeatKey, u64)> {
+    let mut due = vec![];
+    repeats.retain(|key, r| {
+        if now.saturating_duration_since(r.since) < *RATCHET_AUDIT_REPEAT {
+            return true;
         }
-    )
+        if r.more > 0 {
+            due.push((key.clone(), r.more));
+        }
+        false
+    });
+    due
 }
 
-/// Backend API for signalling updates.
-#[get("/api/longpoll?<serial>")]
-async fn api_long_poll(_valid: RatchetApiKey, serial: Option<u64>) -> String {
-    // Serial number mismatch
-    let latest = LONG_POLL_EPOCH.load(Ordering::Relaxed);
-    if let Some(sn) = serial  { // Option retains compatibility with legacy ratchet
-        if sn != latest { return String::from(format!("Update {}", latest)); } // output
+async fn rtp_audit_repeats(due: Vec<(RatchetAuditRepeatKey, u64)>) {
+    let secs = RATCHET_AUDIT_REPEAT.as_secs();
+    for (key, more) in due {
+        let detail = match key.detail.is_empty() {
+            true => format!("{} more in {}s", more, secs),
+            false => format!("{}, {} more in {}s", key.detail, more, secs),
+        };
+        rtp_audit(key.event, &key.actor, &key.object, key.source_ip, detail).await;
     }
+}
 
-    // Normal path / waiting
-    let (tx, rx) = oneshot::channel();
-    {
-        let mut pins = RATCHET_POLL_PINS.lock().await;
-        pins.push(tx);
-    } // drop the pins
+/// Writes out what rtp_audit_repeated counted, for when the same doesn't
+/// come round again.
+async fn rtp_flush_audit_repeats() {
+    loop {
+        rocket::tokio::time::sleep(*RATCHET_AUDIT_REPEAT).await;
+        let due = rtp_due_audit_repeats(&mut *RATCHET_AUDIT_REPEATS.lock().await, Instant::now());
+        rtp_audit_repeats(due).await;
+    }
+}
+
+/// The audit log from `after` on, in order.
+fn rtp_read_audit(after: u64) -> Result<Vec<RatchetAuditEntry>, RatchetStoreError> {
+    RATCHET_STORE.begin_read()?
+        .log_scan(RATCHET_AUDIT_TABLE.name(), after, usize::MAX)?
+        .iter()
+        .map(|pt| serde_json::from_slice(pt).map_err(|e| RatchetStoreError::Format(e.to_string())))
+        .collect()
+}
+
+/// Filters for the audit log, all optional; times are Unix seconds, inclusive.
+#[derive(FromForm)]
+struct RatchetAuditQuery {
+    actor: Option<String>,
+    object: Option<String>,
+    event: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    after: Option<u64>,
+    limit: Option<usize>,
+}
 
-    match rx.await {
-        Ok(_v) => String::from(format!("Update {}", latest)),
-        Err(_) => String::from(""),
+impl RatchetAuditQuery {
+    fn matches(&self, e: &RatchetAuditEntry) -> bool {
+        self.actor.as_ref().is_none_or(|a| *a == e.actor) &&
+        self.object.as_ref().is_none_or(|o| *o == e.object) &&
+        self.event.as_ref().is_none_or(|v| *v == e.event) &&
+        self.since.is_none_or(|t| e.at >= t) &&
+        self.until.is_none_or(|t| e.at <= t)
     }
 }
 
-#[catch(401)]
-fn unauth(_req: &Request) -> String {
-    format!("Unauthorized")
+/// Frontend API for the audit log, `limit` (default 100) matching entries
+/// after `after`, oldest first.
+#[get("/getaudit?<query..>")]
+async fn get_audit(_admin: RatchetAdmin<RatchetPermSuperadmin>, query: RatchetAuditQuery) -> Result<Json<Vec<RatchetAuditEntry>>, Status> {
+    let after = query.after.unwrap_or(0);
+    match rtp_blocking(move || rtp_read_audit(after)).await {
+        Ok(entries) => Ok(Json(entries.into_iter().filter(|e| query.matches(e)).take(query.limit.unwrap_or(100)).collect())),
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unable to read the audit log: {:?}", e);
+            Err(Status::InternalServerError)
+        },
+    }
 }
-#[catch(404)]
-fn not_found(_req: &Request) -> String {
-    format!("Not Found")
+
+/// Walks the chain: every position from 1 on, each entry opening, in its
+/// place, hashing to what it says, and pointing at the one before; the
+/// last one matching the head.
+fn rtp_verify_audit(key: &[u8; 32], rows: &[(u64, Vec<u8>)], head: Option<RatchetAuditHead>) -> Vec<RatchetCheckProblem> {
+    let table = RATCHET_AUDIT_TABLE.name();
+    let mut problems = vec![];
+    let mut expected = 1;
+    // None after an entry that doesn't open, there's nothing to follow
+    let mut prev = Some(String::from(RATCHET_AUDIT_GENESIS));
+    for (seq, stored) in rows.iter() {
+        let at = seq.to_string();
+        match seq.cmp(&expected) {
+            std::cmp::Ordering::Equal => (),
+            std::cmp::Ordering::Greater if *seq == expected + 1 => {
+                problems.push(RatchetCheckProblem::row("audit_missing", table, &at, format!("entry {} is gone", expected)))
+            },
+            std::cmp::Ordering::Greater => {
+                problems.push(RatchetCheckProblem::row("audit_missing", table, &at, format!("entries {} to {} are gone", expected, seq - 1)))
+            },
+            std::cmp::Ordering::Less => problems.push(RatchetCheckProblem::row("audit_moved", table, &at, format!("entry {} is out of place", seq))),
+        }
+        expected = expected.max(seq.saturating_add(1));
+        let entry = rtp_open_record(key, table, &at, stored)
+                        .and_then(|pt| serde_json::from_slice::<RatchetAuditEntry>(&pt).map_err(|e| RatchetStoreError::Format(e.to_string())));
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                problems.push(RatchetCheckProblem::row("audit_unreadable", table, &at, format!("{:?}", e)));
+                prev = None;
+                continue;
+            },
+        };
+        if entry.seq != *seq {
+            problems.push(RatchetCheckProblem::row("audit_moved", table, &at, format!("the entry says it's {}", entry.seq)));
+        }
+        if entry.hash != rtp_audit_hash(&entry) {
+            problems.push(RatchetCheckProblem::row("audit_edited", table, &at, String::from("the entry doesn't hash to what it says")));
+        }
+        if prev.as_ref().is_some_and(|p| *p != entry.prev) {
+            problems.push(RatchetCheckProblem::row("audit_chain_broken", table, &at, String::from("the entry doesn't follow the one before it")));
+        }
+        prev = Some(entry.hash);
+    }
+    let last = rows.last().map(|(seq, _)| *seq).unwrap_or(0);
+    match head {
+        Some(h) if h.seq != last => {
+            problems.push(RatchetCheckProblem::new("audit_truncated", format!("the log ends at {}, the head says {}", last, h.seq)));
+        },
+        Some(h) if prev.as_ref().is_some_and(|p| *p != h.hash) => {
+            problems.push(RatchetCheckProblem::new("audit_head_mismatch", format!("entry {} isn't the one the head says", last)));
+        },
+        Some(_) => (),
+        None if last > 0 => {
+            problems.push(RatchetCheckProblem::new("audit_head_missing", String::from("there are entries, but no head")));
+        },
+        None => (),
+    }
+    problems
 }
-#[catch(409)]
-fn gone(_req: &Request) -> String {
-    format!("Gone")
+
+/// How much an event matters, as RFC 5424 severities.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RatchetSeverity {
+    Emergency,
+    Alert,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    Info,
+    Debug,
 }
-#[catch(410)]
-fn conflict(_req: &Request) -> String {
-    format!("Conflict")
+
+impl RatchetSeverity {
+    fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    fn from_name(name: &str) -> Option<RatchetSeverity> {
+        [RatchetSeverity::Emergency, RatchetSeverity::Alert, RatchetSeverity::Critical, RatchetSeverity::Error,
+         RatchetSeverity::Warning, RatchetSeverity::Notice, RatchetSeverity::Info, RatchetSeverity::Debug]
+            .into_iter()
+            .find(|s| serde_json::to_value(s).is_ok_and(|v| v == name))
+    }
 }
-// #[catch(503)]
-// fn svc_unavailable(_req: &Request) -> String {
-//     format!("Service Unavailable")
-// }
 
-fn main() {
-    // lock all allocations
-    #[cfg(not(debug_assertions))]
-    {
-        let result = unsafe { mlockall(MCL_CURRENT | MCL_FUTURE | MCL_ONFAULT) };
-        if result != 0 {
-            eprintln!("mlockall failed with error code: {}", result);
-        } else {
-            println!("mlockall succeeded");
+/// Failures and misuse are warnings, changes and revocations notices,
+/// and the everyday (logins, API use, polling) info.
+fn rtp_event_severity(event: &str) -> RatchetSeverity {
+    match event {
+        "login_failed" | "login_locked" | "api_key_rejected" | "policy_rejected" | "key_rotate_rejected" => RatchetSeverity::Warning,
+        "sessions_rm" | "backup" | "policy_push" | "login_unlocked" | "key_rotate" | "test" => RatchetSeverity::Notice,
+        e if e.ends_with("_add") || e.ends_with("_edit") || e.ends_with("_rm") => RatchetSeverity::Notice,
+        _ => RatchetSeverity::Info,
+    }
+}
+
+/// One forwarded event, as a JSON line; the fields are the audit entry's,
+/// and stay put, `version` says if that ever changes.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetEventLine<'e> {
+    version: u32,
+    time: String,
+    host: &'e str,
+    severity: RatchetSeverity,
+    severity_code: u8,
+    #[serde(flatten)]
+    entry: &'e RatchetAuditEntry,
+}
+
+const RATCHET_EVENT_VERSION: u32 = 1;
+
+/// Unix seconds as an RFC 3339 timestamp, in UTC.
+fn rtp_rfc3339(at: u64) -> String {
+    // days to civil, see Howard Hinnant's chrono-compatible algorithms
+    let (days, secs) = ((at / 86400) as i64, at % 86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+fn rtp_hostname() -> String {
+    let mut buf = [0u8; 256];
+    match unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } {
+        0 => String::from_utf8_lossy(buf.split(|b| *b == 0).next().unwrap_or_default()).to_string(),
+        _ => String::from("-"),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RatchetSyslogTransport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+/// RATCHET_PAWL_SYSLOG, `udp://`, `tcp://` or `tls://` and a host, with a port
+/// or the usual one (514, 601, 6514). TLS checks the server against the CA
+/// certificates in RATCHET_PAWL_SYSLOG_CA.
+struct RatchetSyslogTarget {
+    transport: RatchetSyslogTransport,
+    host: String,
+    port: u16,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    /// RATCHET_PAWL_SYSLOG_FACILITY, default 10 (authpriv).
+    facility: u8,
+}
+
+enum RatchetSyslogConn {
+    Udp(std::net::UdpSocket),
+    Tcp(std::net::TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, std::net::TcpStream>>),
+}
+
+impl RatchetSyslogTarget {
+    fn from_env() -> Result<Option<RatchetSyslogTarget>, String> {
+        let target = match rtp_env_key("RATCHET_PAWL_SYSLOG") {
+            t if !t.is_empty() => t,
+            _ => return Ok(None),
+        };
+        let (transport, rest) = match target.split_once("://") {
+            Some(("udp", rest)) => (RatchetSyslogTransport::Udp, rest),
+            Some(("tcp", rest)) => (RatchetSyslogTransport::Tcp, rest),
+            Some(("tls", rest)) => (RatchetSyslogTransport::Tls, rest),
+            _ => return Err(format!("RATCHET_PAWL_SYSLOG is not udp://, tcp:// or tls://HOST[:PORT]: {}", target)),
+        };
+        let default_port = match transport {
+            RatchetSyslogTransport::Udp => 514,
+            RatchetSyslogTransport::Tcp => 601,
+            RatchetSyslogTransport::Tls => 6514,
+        };
+        // [::1]:514, host:514, or just the host
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((h, p)) if !h.ends_with(':') && (!rest.starts_with('[') || h.ends_with(']')) => {
+                (h, p.parse::<u16>().map_err(|_| format!("RATCHET_PAWL_SYSLOG port is not a number: {}", p))?)
+            },
+            _ => (rest, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+        if host.is_empty() {
+            return Err(String::from("RATCHET_PAWL_SYSLOG has no host"));
+        }
+        let facility = match rtp_env_key("RATCHET_PAWL_SYSLOG_FACILITY") {
+            f if !f.is_empty() => f.parse::<u8>().ok().filter(|f| *f <= 23).ok_or(format!("RATCHET_PAWL_SYSLOG_FACILITY is not 0 to 23: {}", f))?,
+            _ => 10,
+        };
+        let tls = match transport {
+            RatchetSyslogTransport::Tls => Some(Arc::new(rtp_syslog_tls_config()?)),
+            _ => None,
+        };
+        Ok(Some(RatchetSyslogTarget { transport, host, port, tls, facility }))
+    }
+
+    fn connect(&self) -> Result<RatchetSyslogConn, String> {
+        use std::net::ToSocketAddrs;
+        let timeout = std::time::Duration::from_secs(5);
+        let addr = (self.host.as_str(), self.port).to_socket_addrs()
+                       .map_err(|e| format!("unable to resolve {}: {}", self.host, e))?
+                       .next()
+                       .ok_or(format!("{} resolves to nothing", self.host))?;
+        if self.transport == RatchetSyslogTransport::Udp {
+            let bind = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+            let socket = std::net::UdpSocket::bind(bind).map_err(|e| format!("{}", e))?;
+            socket.connect(addr).map_err(|e| format!("{}", e))?;
+            return Ok(RatchetSyslogConn::Udp(socket));
+        }
+        let stream = std::net::TcpStream::connect_timeout(&addr, timeout).map_err(|e| format!("unable to connect to {}: {}", addr, e))?;
+        let _ = stream.set_write_timeout(Some(timeout));
+        let _ = stream.set_read_timeout(Some(timeout));
+        match &self.tls {
+            Some(config) => {
+                let name = rustls::ServerName::try_from(self.host.as_str()).map_err(|e| format!("{}: {}", self.host, e))?;
+                let conn = rustls::ClientConnection::new(config.clone(), name).map_err(|e| format!("{}", e))?;
+                let mut tls = rustls::StreamOwned::new(conn, stream);
+                // handshake now, so a bad certificate shows up as not connecting
+                while tls.conn.is_handshaking() {
+                    tls.conn.complete_io(&mut tls.sock).map_err(|e| format!("TLS with {} failed: {}", addr, e))?;
+                }
+                Ok(RatchetSyslogConn::Tls(Box::new(tls)))
+            },
+            None => Ok(RatchetSyslogConn::Tcp(stream)),
+        }
+    }
+
+    /// RFC 5424, with everything in structured data under the documentation
+    /// enterprise number; octet counted over TCP and TLS (RFC 6587, 5425).
+    fn format(&self, host: &str, severity: RatchetSeverity, e: &RatchetAuditEntry) -> Vec<u8> {
+        let escape = |v: &str| v.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]");
+        let mut sd = format!("[pawl@32473 version=\"{}\" seq=\"{}\" event=\"{}\" actor=\"{}\" object=\"{}\"",
+                             RATCHET_EVENT_VERSION, e.seq, escape(&e.event), escape(&e.actor), escape(&e.object));
+        if let Some(ip) = &e.source_ip {
+            sd.push_str(&format!(" source_ip=\"{}\"", escape(ip)));
+        }
+        sd.push(']');
+        let mut msg = format!("<{}>1 {} {} ratchet-pawl {} {} {} {} by {}",
+                              self.facility as u32 * 8 + severity.code() as u32, rtp_rfc3339(e.at), host,
+                              std::process::id(), e.event, sd, e.event, e.actor);
+        if !e.object.is_empty() {
+            msg.push_str(&format!(" on {}", e.object));
+        }
+        if !e.detail.is_empty() {
+            msg.push_str(&format!(": {}", e.detail));
+        }
+        match self.transport {
+            RatchetSyslogTransport::Udp => msg.into_bytes(),
+            _ => format!("{} {}", msg.len(), msg).into_bytes(),
         }
     }
-    // https://github.com/rwf2/Rocket/issues/1881 👍👍👍
-    rocket::execute(async move {
-            let _ = rocket().await
-            .launch()
-            .await;
-        });
 }
 
-async fn rocket() -> Rocket<Build> {
-    rtp_force_db_init().await.expect("Unable to init database");
-    rtp_import_database().await.expect("Error importing database");
-    
-    initialize_first_user().await.expect("Error initializing first user");
-    initialize_user_cmd_pol().await.expect("Error initializing user cmd policy");
-    initialize_api_key().await.expect("Error initializing API key");
+fn rtp_syslog_tls_config() -> Result<rustls::ClientConfig, String> {
+    let ca = rtp_env_key("RATCHET_PAWL_SYSLOG_CA");
+    if ca.is_empty() {
+        return Err(String::from("RATCHET_PAWL_SYSLOG over tls:// needs RATCHET_PAWL_SYSLOG_CA, the CA certificates to check the server with"));
+    }
+    let pem = std::fs::File::open(&ca).map_err(|e| format!("Unable to read {}: {}", ca, e))?;
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(pem)).map_err(|e| format!("Unable to read {}: {}", ca, e))?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in certs {
+        roots.add(&rustls::Certificate(cert)).map_err(|e| format!("Bad certificate in {}: {}", ca, e))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("No certificates in {}", ca));
+    }
+    Ok(rustls::ClientConfig::builder()
+           .with_safe_defaults()
+           .with_root_certificates(roots)
+           .with_no_client_auth())
+}
 
-    rt_generate_gutter().await;
+impl RatchetSyslogConn {
+    fn send(&mut self, msg: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+        match self {
+            RatchetSyslogConn::Udp(s) => s.send(msg).map(|_| ()),
+            RatchetSyslogConn::Tcp(s) => s.write_all(msg).and_then(|_| s.flush()),
+            RatchetSyslogConn::Tls(s) => s.write_all(msg).and_then(|_| s.flush()),
+        }
+    }
+}
 
-    rocket::build()
-        .mount("/", rocket::routes![try_login, logged, hangup])
-        .mount("/", rocket::routes![api_dump_devs, api_dump_users, api_dump_policy, api_long_poll])
-        .mount("/", rocket::routes![rm_user, edit_user, add_user, get_users])
-        .mount("/", rocket::routes![rm_dev, edit_dev, add_dev, get_devs])
-        .mount("/",rocket::routes![get_policy, push_policy])
-        .mount("/", FileServer::from(relative!("pawl-js/build/")))
-        .register("/", catchers![not_found, gone, unauth, conflict])
+/// RATCHET_PAWL_EVENTS_FILE, one JSON object a line. Past
+/// RATCHET_PAWL_EVENTS_FILE_MAX_BYTES (default 10 MiB) it moves to `.1`,
+/// that to `.2`, and so on, keeping RATCHET_PAWL_EVENTS_FILE_KEEP (default 5).
+struct RatchetEventFile {
+    path: std::path::PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: Option<(std::fs::File, u64)>,
+}
+
+impl RatchetEventFile {
+    fn from_env() -> Result<Option<RatchetEventFile>, String> {
+        let path = match rtp_env_key("RATCHET_PAWL_EVENTS_FILE") {
+            p if !p.is_empty() => std::path::PathBuf::from(p),
+            _ => return Ok(None),
+        };
+        let number = |var: &str, default: u64| match rtp_env_key(var) {
+            v if !v.is_empty() => v.parse::<u64>().map_err(|_| format!("{} is not a number: {}", var, v)),
+            _ => Ok(default),
+        };
+        let mut file = RatchetEventFile {
+            path,
+            max_bytes: number("RATCHET_PAWL_EVENTS_FILE_MAX_BYTES", 10 * 1024 * 1024)?.max(1),
+            keep: number("RATCHET_PAWL_EVENTS_FILE_KEEP", 5)? as usize,
+            file: None,
+        };
+        file.open().map_err(|e| format!("Unable to open {}: {}", file.path.display(), e))?;
+        Ok(Some(file))
+    }
+
+    /// Owner-only, it has usernames and addresses in it.
+    fn open(&mut self) -> std::io::Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let f = std::fs::OpenOptions::new().create(true).append(true).mode(0o600).open(&self.path)?;
+        let size = f.metadata()?.len();
+        self.file = Some((f, size));
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> std::path::PathBuf {
+        let mut p = self.path.clone().into_os_string();
+        p.push(format!(".{}", n));
+        std::path::PathBuf::from(p)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file = None;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if self.rotated(n).exists() {
+                    std::fs::rename(self.rotated(n), self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.open()
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        use std::io::Write;
+        if self.file.is_none() {
+            self.open()?;
+        }
+        if self.file.as_ref().is_some_and(|(_, size)| *size > 0 && size + line.len() as u64 + 1 > self.max_bytes) {
+            self.rotate()?;
+        }
+        match self.file.as_mut() {
+            Some((f, size)) => {
+                f.write_all(format!("{}\n", line).as_bytes())?;
+                *size += line.len() as u64 + 1;
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
 }
 
-async fn rt_generate_gutter() {
-    let mut g = GUTTER.write().await;
-    g.push_str(&bcrypt::hash(rt_generate_gutter_string()).expect("Ratchet Fatal: Unable to generate gutter"));
+/// Where events go, and at what severity and worse, RATCHET_PAWL_EVENTS_SEVERITY
+/// (default notice).
+struct RatchetEventSinks {
+    host: String,
+    severity: RatchetSeverity,
+    syslog: Option<(RatchetSyslogTarget, Option<RatchetSyslogConn>)>,
+    file: Option<RatchetEventFile>,
+    /// So a syslog that's down is said once, not for every event.
+    failing: bool,
 }
 
-fn rt_generate_gutter_string() -> String { 
-    (0..72).fold(
-        String::with_capacity(72),
-        |mut s, _| {
-            loop {
-                let c = rand::random::<u8>();
-                if c.is_ascii_alphanumeric() || c.is_ascii_graphic() || c.is_ascii_punctuation() {
-                    s.push(c as char);
+impl RatchetEventSinks {
+    fn from_env() -> Result<Option<RatchetEventSinks>, String> {
+        let syslog = RatchetSyslogTarget::from_env()?;
+        let file = RatchetEventFile::from_env()?;
+        if syslog.is_none() && file.is_none() {
+            return Ok(None);
+        }
+        let severity = match rtp_env_key("RATCHET_PAWL_EVENTS_SEVERITY") {
+            s if !s.is_empty() => RatchetSeverity::from_name(&s).ok_or(format!("RATCHET_PAWL_EVENTS_SEVERITY is not a syslog severity: {}", s))?,
+            _ => RatchetSeverity::Notice,
+        };
+        Ok(Some(RatchetEventSinks {
+            host: rtp_hostname(),
+            severity,
+            syslog: syslog.map(|t| (t, None)),
+            file,
+            failing: false,
+        }))
+    }
+
+    /// Sends one event everywhere, if it's severe enough; a dropped
+    /// syslog connection gets one more try.
+    fn forward(&mut self, e: &RatchetAuditEntry) -> Result<(), String> {
+        let severity = rtp_event_severity(&e.event);
+        if severity > self.severity {
+            return Ok(());
+        }
+        let mut failed = vec![];
+        if let Some(file) = self.file.as_mut() {
+            let line = RatchetEventLine {
+                version: RATCHET_EVENT_VERSION,
+                time: rtp_rfc3339(e.at),
+                host: &self.host,
+                severity,
+                severity_code: severity.code(),
+                entry: e,
+            };
+            let written = serde_json::to_string(&line).map_err(|e| e.to_string())
+                              .and_then(|l| file.write(&l).map_err(|e| e.to_string()));
+            if let Err(err) = written {
+                failed.push(format!("{}: {}", file.path.display(), err));
+            }
+        }
+        if let Some((target, conn)) = self.syslog.as_mut() {
+            let msg = target.format(&self.host, severity, e);
+            let mut sent = Err(String::new());
+            for _ in 0..2 {
+                if conn.is_none() {
+                    match target.connect() {
+                        Ok(c) => *conn = Some(c),
+                        Err(err) => {
+                            sent = Err(err);
+                            continue;
+                        },
+                    }
+                }
+                sent = match conn.as_mut().map(|c| c.send(&msg)) {
+                    Some(Ok(())) => Ok(()),
+                    Some(Err(err)) => Err(err.to_string()),
+                    None => continue,
+                };
+                if sent.is_ok() {
                     break;
                 }
+                *conn = None;
+            }
+            if let Err(err) = sent {
+                failed.push(format!("syslog {}:{}: {}", target.host, target.port, err));
             }
-            s
         }
-    )
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(failed.join(", ")),
+        }
+    }
 }
 
-#[derive(Clone, FromForm, Debug, Serialize, Deserialize)]
-struct RatchetUserCmdPolicy(String);
-impl RatchetKeyed for RatchetUserCmdPolicy {
-    fn into_key<'k>(&self) -> &str {
-        "singleton"
-    }
+/// Committed audit entries, on their way out, see rtp_start_events.
+static RATCHET_EVENTS: std::sync::OnceLock<std::sync::mpsc::Sender<RatchetAuditEntry>> = std::sync::OnceLock::new();
+
+/// Starts forwarding events, if RATCHET_PAWL_SYSLOG or RATCHET_PAWL_EVENTS_FILE
+/// says where, on a thread of its own so nobody waits on syslog. Events
+/// that can't be delivered are dropped, the audit log still has them.
+fn rtp_start_events() -> Result<(), String> {
+    let mut sinks = match RatchetEventSinks::from_env()? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    let (tx, rx) = std::sync::mpsc::channel::<RatchetAuditEntry>();
+    std::thread::spawn(move || {
+        while let Ok(e) = rx.recv() {
+            match sinks.forward(&e) {
+                Ok(()) if sinks.failing => {
+                    println!("Ratchet-Pawl forwarding events again.");
+                    sinks.failing = false;
+                },
+                Ok(()) => (),
+                Err(err) if !sinks.failing => {
+                    eprintln!("Ratchet-Pawl unable to forward events, dropping them until it can: {}", err);
+                    sinks.failing = true;
+                },
+                Err(_) => (),
+            }
+        }
+    });
+    let _ = RATCHET_EVENTS.set(tx);
+    Ok(())
 }
 
-/// An invariant that is largely maintained throughout is that
-/// there is at least one user who can administer ratchet in the database.
-async fn initialize_user_cmd_pol() -> Result<(), redb::Error> {
-    let mut user_cmd_policy_init = RATCHET_USER_CMD_POLICY.lock().await;
-    if user_cmd_policy_init.0.len() == 0 {
-        *user_cmd_policy_init = RatchetUserCmdPolicy(String::from("$\n(\n)"));
-        RATCHET_USER_CMD_POLICY_TABLE.write(&user_cmd_policy_init).await;
+fn rtp_forward_events(entries: Vec<RatchetAuditEntry>) {
+    if let Some(tx) = RATCHET_EVENTS.get() {
+        entries.into_iter().for_each(|e| { let _ = tx.send(e); });
     }
-    Ok(())
 }
 
-/// An invariant that is largely maintained throughout is that
-/// there is at least one user who can administer ratchet in the database.
-async fn initialize_first_user() -> Result<(), redb::Error> {
-    let mut users_init = RATCHET_USERS.lock().await;
-    if users_init.len() == 0 {
-        let mut pass: String = String::with_capacity(16);
-        while pass.len() < 16 {
-            let c = rand::random::<u8>();
-            if c.is_ascii_alphanumeric() || c.is_ascii_graphic() || c.is_ascii_punctuation() {
-                pass.push(c as char);
-            }
-        }
-        println!("Ratchet-Pawl Initialization creating initial user with details:");
-        println!("Username: DefaultRatchetUser");
-        println!("Password: {}", pass);
-        let username = String::from("DefaultRatchetUser");
-        let init_user = RatchetUserEntry {
-            username: username,
-            passhash: bcrypt::hash(pass).expect("unable to initialize password"),
-        };
-        RATCHET_USERS_TABLE.write(&init_user).await?;
-        users_init.insert(init_user.username.clone(), init_user);
+/// `ratchet-pawl test-events`, sends a `test` event wherever events are
+/// configured to go, and says how that went.
+fn rtp_cli_test_events() -> i32 {
+    let mut sinks = match RatchetEventSinks::from_env() {
+        Ok(Some(s)) => s,
+        Ok(None) => {
+            eprintln!("Ratchet-Pawl has nowhere to send events, set RATCHET_PAWL_SYSLOG or RATCHET_PAWL_EVENTS_FILE.");
+            return 2;
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        },
+    };
+    sinks.severity = RatchetSeverity::Debug;
+    let entry = RatchetAuditEntry {
+        seq: 0,
+        at: rtp_unix_now(),
+        event: String::from("test"),
+        actor: String::from(RATCHET_SYSTEM_ACTOR),
+        object: String::new(),
+        source_ip: None,
+        detail: String::from("test event from ratchet-pawl test-events"),
+        prev: String::new(),
+        hash: String::new(),
+    };
+    match sinks.forward(&entry) {
+        Ok(()) => {
+            println!("Ratchet-Pawl sent a test event.");
+            0
+        },
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unable to send a test event: {}", e);
+            1
+        },
     }
-    Ok(())
 }
 
-/// redb doesn't write any tables until you open them.
-/// this ensures that the needed tables exist in the
-/// database.
-async fn rtp_force_db_init() -> Result<(), redb::Error> {
-    let db = &DB;
+/// The last compaction, and how many so far, for /api/metrics.
+static RATCHET_COMPACTIONS: AtomicU64 = AtomicU64::new(0);
+static RATCHET_COMPACT_FAILURES: AtomicU64 = AtomicU64::new(0);
+static RATCHET_COMPACT_BEFORE_BYTES: AtomicU64 = AtomicU64::new(0);
+static RATCHET_COMPACT_AFTER_BYTES: AtomicU64 = AtomicU64::new(0);
+static RATCHET_COMPACT_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+fn rtp_db_size() -> u64 {
+    std::fs::metadata(RATCHET_DATA_DIR.join(THE_DATABASE)).map(|m| m.len()).unwrap_or(0)
+}
 
+/// How much of the database is free pages and fragmentation, 0 to 1.
+fn rtp_free_ratio(db: &Database) -> Result<f64, RatchetStoreError> {
     let write_txn = db.begin_write()?;
-    {
-        // write initializes tables, tables must be written before they are initialized
-        write_txn.open_table(RATCHET_USERS_TABLE.unwrap())?;
-        write_txn.open_table(RATCHET_DEVS_TABLE.unwrap())?;
-        write_txn.open_table(RATCHET_USER_CMD_POLICY_TABLE.unwrap())?;
-        write_txn.open_table(RATCHET_APIKEY_TABLE.unwrap())?;
-        // reading an empty table is a panic.
+    let stats = write_txn.stats()?;
+    write_txn.abort()?;
+    let total = stats.stored_bytes() + stats.metadata_bytes() + stats.fragmented_bytes();
+    if total == 0 {
+        return Ok(0.0);
     }
-    write_txn.commit()?;
-    
-    let selected_key = {|| {for (k, v) in env::vars() {
-        // something like this appears to have ok support from systemd
-        if k == "RATCHET_PAWL_MASKING_KEY" { return v; }
-    } return "".to_string() }}();
-
-    if selected_key == "" { panic!("Please use the environment var, RATCHET_PAWL_MASKING_KEY, to specify a database encryption key."); }
-    // if selected_key == "" {
-    //     // ZERG RUSH!!
-    //     let ke = File::open(THE_DATABASE)?;
-    //     let ke = ke.metadata()?;
-    //     let ke = ke.created()?;
-    //     let ke = ke.duration_since(SystemTime::UNIX_EPOCH);
-    //     selected_key = match ke {
-    //         Ok(k) => k.as_secs().to_string(),
-    //         Err(_) => panic!("Need a shadowing key."),
-    //     };
-    // }
+    Ok(stats.fragmented_bytes() as f64 / total as f64)
+}
 
+/// How long compaction waits out transactions that were already open.
+const RATCHET_COMPACT_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
 
-    unsafe { rtp_take_key(&selected_key); }
+/// Compacts the file. Takes the database to itself for the duration, so
+/// anything that writes, the persistence thread included, waits its turn;
+/// routes only get at it from blocking threads, see rtp_blocking, so the
+/// async workers don't. Transactions don't hold the lock once they're
+/// begun, so any still open are waited out, up to RATCHET_COMPACT_WAIT.
+fn rtp_compact(reason: &str) -> Result<(), RatchetStoreError> {
+    let started = Instant::now();
+    let mut db = DB.write().unwrap_or_else(|e| e.into_inner());
+    let before = rtp_db_size();
+    loop {
+        match db.compact() {
+            Ok(_) => break,
+            Err(redb::CompactionError::TransactionInProgress) if started.elapsed() < RATCHET_COMPACT_WAIT => {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+    drop(db);
+    let after = rtp_db_size();
+    let millis = started.elapsed().as_millis() as u64;
 
+    RATCHET_COMPACTIONS.fetch_add(1, Ordering::SeqCst);
+    RATCHET_COMPACT_BEFORE_BYTES.store(before, Ordering::SeqCst);
+    RATCHET_COMPACT_AFTER_BYTES.store(after, Ordering::SeqCst);
+    RATCHET_COMPACT_MILLIS.store(millis, Ordering::SeqCst);
+    println!("Ratchet-Pawl compacted the database ({}), {} -> {} bytes in {} ms.", reason, before, after, millis);
     Ok(())
 }
 
+/// Every RATCHET_PAWL_COMPACT_CHECK_SECS (default 600), compacts if it's
+/// been RATCHET_PAWL_COMPACT_INTERVAL_SECS (default a day, 0 never) since
+/// the last time, or if free pages are at least RATCHET_PAWL_COMPACT_FREE_RATIO
+/// (default 0.5) of the file.
+fn rtp_start_maintenance() {
+    let check = rtp_env_key("RATCHET_PAWL_COMPACT_CHECK_SECS").parse::<u64>().unwrap_or(600).max(1);
+    let interval = rtp_env_key("RATCHET_PAWL_COMPACT_INTERVAL_SECS").parse::<u64>().unwrap_or(86400);
+    let threshold = rtp_env_key("RATCHET_PAWL_COMPACT_FREE_RATIO").parse::<f64>().unwrap_or(0.5);
+    std::thread::spawn(move || {
+        let mut last = Instant::now();
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(check));
+            let reason = if interval > 0 && last.elapsed().as_secs() >= interval {
+                Some(String::from("scheduled"))
+            } else if rtp_db_size() <= RATCHET_COMPACT_AFTER_BYTES.load(Ordering::SeqCst) {
+                // hasn't grown since last time, compacting again won't help
+                None
+            } else {
+                match rtp_free_ratio(&rtp_db()) {
+                    Ok(ratio) if ratio >= threshold => Some(format!("{:.0}% fragmented", ratio * 100.0)),
+                    Ok(_) => None,
+                    Err(e) => {
+                        eprintln!("Ratchet-Pawl unable to check free pages: {:?}", e);
+                        None
+                    },
+                }
+            };
+            if let Some(reason) = reason {
+                // e.g. a long read in progress, there's always the next check
+                match rtp_compact(&reason) {
+                    Ok(()) => last = Instant::now(),
+                    Err(e) => {
+                        RATCHET_COMPACT_FAILURES.fetch_add(1, Ordering::SeqCst);
+                        eprintln!("Ratchet-Pawl compaction ({}) failed, will retry: {:?}", reason, e);
+                    },
+                }
+            }
+        }
+    });
+}
+
+/// Prometheus text format, for whoever holds an API key.
+#[get("/api/metrics")]
+async fn api_metrics(_valid: RatchetApiCaller<RatchetScopeMetrics>) -> String {
+    let metrics = [
+        ("ratchet_pawl_db_bytes", "gauge", "Size of the database file.", rtp_db_size() as f64),
+        ("ratchet_pawl_compactions_total", "counter", "Compactions since start.", RATCHET_COMPACTIONS.load(Ordering::SeqCst) as f64),
+        ("ratchet_pawl_compaction_failures_total", "counter", "Compactions that failed since start.", RATCHET_COMPACT_FAILURES.load(Ordering::SeqCst) as f64),
+        ("ratchet_pawl_compaction_before_bytes", "gauge", "Database size before the last compaction.", RATCHET_COMPACT_BEFORE_BYTES.load(Ordering::SeqCst) as f64),
+        ("ratchet_pawl_compaction_after_bytes", "gauge", "Database size after the last compaction.", RATCHET_COMPACT_AFTER_BYTES.load(Ordering::SeqCst) as f64),
+        ("ratchet_pawl_compaction_seconds", "gauge", "How long the last compaction took.", RATCHET_COMPACT_MILLIS.load(Ordering::SeqCst) as f64 / 1000.0),
+        ("ratchet_pawl_degraded", "gauge", "Whether records are in quarantine.", if RATCHET_DEGRADED.load(Ordering::SeqCst) { 1.0 } else { 0.0 }),
+    ];
+    metrics.iter().fold(String::new(), |mut out, (name, kind, help, value)| {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        out
+    })
+}
+
 /// This is the mechanism that puts the database in memory.
 /// 
 /// pawl ensures that its hash tables always exactly match
 /// the contents of the database, and that all clients are
 /// eventually consistent with the status.
-async fn rtp_import_database() -> Result<(), redb::Error> {
-    let db = &DB;
+async fn rtp_import_database() -> Result<(), RatchetStoreError> {
     let mut users_init = RATCHET_USERS.lock().await;
     let mut devs_init: rocket::tokio::sync::MutexGuard<'_, HashMap<String, RatchetDevEntry>> = RATCHET_DEVICES.lock().await;
     let mut user_cmd_policy_init = RATCHET_USER_CMD_POLICY.lock().await;
     let mut api_init = RATCHET_APIKEYS.lock().await;
-    let write_txn = db.begin_write()?;
-    {
-        // write initializes tables, tables must be written before they are initialized
-        write_txn.open_table(RATCHET_USERS_TABLE.unwrap())?;
-        write_txn.open_table(RATCHET_DEVS_TABLE.unwrap())?;
-        write_txn.open_table(RATCHET_USER_CMD_POLICY_TABLE.unwrap())?;
-        write_txn.open_table(RATCHET_APIKEY_TABLE.unwrap())?;
-        // reading an empty table is a panic.
-    }
-    write_txn.commit()?;
+    let mut admins_init = RATCHET_ADMINS.lock().await;
 
-    let key: &[u8; 32]  = *PERM_DB_KEY.clone();
-    let ff = FF1::<Aes256>::new(key, 2).unwrap();
+    // rows that open but still don't fit are quarantined too, see rtp_sweep_database
+    let mut txn = RATCHET_STORE.begin()?;
+    let mut bad = vec![];
+    let (users, b) = RATCHET_USERS_TABLE.scan::<RatchetUserEntry>(&mut txn)?;
+    bad.extend(b);
+    let (devs, b) = RATCHET_DEVS_TABLE.scan::<RatchetDevEntry>(&mut txn)?;
+    bad.extend(b);
+    let (policies, b) = RATCHET_USER_CMD_POLICY_TABLE.scan::<RatchetUserCmdPolicy>(&mut txn)?;
+    bad.extend(b);
+    let (api_keys, b) = RATCHET_APIKEY_TABLE.scan::<RatchetApiKey>(&mut txn)?;
+    bad.extend(b);
+    let (admins, b) = RATCHET_ADMINS_TABLE.scan::<RatchetAdminEntry>(&mut txn)?;
+    bad.extend(b);
+    rtp_quarantine(txn.raw.as_mut(), &bad)?;
+    let quarantined = rtp_read_quarantine(txn.raw.as_mut())?.len();
+    txn.commit()?;
+    rtp_load_api_key_secret()?;
 
-    let read_txn = db.begin_read()?;
-    let table = read_txn.open_table(RATCHET_USERS_TABLE.unwrap())?;
-
-    let table_iter = table.iter()?;
-    table_iter.for_each(|tup| {
-        let v = tup.expect("dont get this interface");
-        let key = v.0.value();
-        let val = v.1.value(); // these are shadowed already and not directly recoverable. but we're just gonna encrypt it anyway.
-        let val_pt = ff.decrypt(&[], &BinaryNumeralString::from_bytes_le(&val)).unwrap().to_bytes_le();
-        let val_pt = str::from_utf8(&val_pt).unwrap();
-        //println!("Processing {:#?}", key);
-        let new_user: RatchetUserEntry = serde_json::from_str(val_pt).unwrap();
-        //println!("Got {:#?}", new_user);
-        users_init.insert(key.to_string(), new_user);
-    });
+    for (username, new_user) in users {
+        users_init.insert(username, new_user);
+    }
 
-    let key: &[u8; 32]  = *PERM_DB_KEY.clone();
-    let ff = FF1::<Aes256>::new(key, 2).unwrap();
+    for (record_key, new_dev) in devs {
+        devs_init.insert(record_key, new_dev);
+    }
 
-    let read_txn = db.begin_read()?;
-    let table = read_txn.open_table(RATCHET_DEVS_TABLE.unwrap())?;
- 
-    let table_iter = table.iter()?;
-    table_iter.for_each(|tup| {
-        let v = tup.expect("dont get this interface");
-        let record_key = v.0.value();
-        let val = v.1.value();
-        let val_pt = ff.decrypt(&[], &BinaryNumeralString::from_bytes_le(&val)).unwrap().to_bytes_le();
-        let val_pt = str::from_utf8(&val_pt).unwrap();
-        //println!("Processing {:#?}", key);
-        let new_dev: RatchetDevEntry = serde_json::from_str(&val_pt).unwrap();
-        //println!("Got {:#?}", new_dev);
-        devs_init.insert(record_key.to_string(), new_dev);
-    });
+    for (_, policy) in policies {
+        *user_cmd_policy_init = policy;
+    }
 
-    let read_txn = db.begin_read()?;
-    let table = read_txn.open_table(RATCHET_USER_CMD_POLICY_TABLE.unwrap())?;
-    let table_iter = table.iter()?;
-    table_iter.for_each(|tup| {
-        let v = tup.expect("dont get this interface");
-        let val = v.1.value();
-        let val_pt = ff.decrypt(&[], &BinaryNumeralString::from_bytes_le(&val)).unwrap().to_bytes_le();
-        let val_pt = str::from_utf8(&val_pt).unwrap();
+    for (_, new_key) in api_keys {
+        api_init.insert(new_key.prefix.clone(), new_key); // this awkward bit is because write is genuinely key-value
+    }
 
-        *user_cmd_policy_init = serde_json::from_str(&val_pt).unwrap();
-    });    
+    for (username, admin) in admins {
+        admins_init.insert(username, admin);
+    }
 
-    let read_txn = db.begin_read()?;
-    let table = read_txn.open_table(RATCHET_APIKEY_TABLE.unwrap())?;
-
-    let table_iter = table.iter()?;
-    table_iter.for_each(|tup| {
-        let v = tup.expect("dont get this interface");
-        //let key = v.0.value();
-        let val = v.1.value();
-        let val_pt = ff.decrypt(&[], &BinaryNumeralString::from_bytes_le(&val)).unwrap().to_bytes_le();
-        let val_pt = str::from_utf8(&val_pt).unwrap();
-        //println!("Processing {:#?}", key);
-        let new_key: RatchetApiKey = serde_json::from_str(val_pt).unwrap();
-        //println!("Got {:#?}", new_key);
-        // CONTRACT: ratchet-cycle intermediates pawl and ratchet to deliver this
-        println!("Api-Key: {}", new_key.api_key.clone());
-        api_init.insert(new_key.api_key.clone(), new_key); // this awkward bit is because write is genuinely key-value
-    });
+    if quarantined > 0 {
+        RATCHET_DEGRADED.store(true, Ordering::SeqCst);
+        eprintln!("Ratchet-Pawl is running degraded, {} records are in quarantine, see /getquarantine.", quarantined);
+    }
 
     Ok(())
 }
 
-struct RatchetUser;
+/// What an admin can do, see RatchetRole::allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RatchetPermission {
+    /// Look, but not touch.
+    View,
+    /// TACACS+ users.
+    Users,
+    Devices,
+    Policy,
+    /// Admins, API keys, sessions, keys, backups and everything else.
+    Superadmin,
+}
+
+/// The permission a route asks for, each admin route names one.
+trait RatchetPermitted: Send {
+    const PERMISSION: RatchetPermission;
+}
+struct RatchetPermView;
+impl RatchetPermitted for RatchetPermView { const PERMISSION: RatchetPermission = RatchetPermission::View; }
+struct RatchetPermUsers;
+impl RatchetPermitted for RatchetPermUsers { const PERMISSION: RatchetPermission = RatchetPermission::Users; }
+struct RatchetPermDevices;
+impl RatchetPermitted for RatchetPermDevices { const PERMISSION: RatchetPermission = RatchetPermission::Devices; }
+struct RatchetPermPolicy;
+impl RatchetPermitted for RatchetPermPolicy { const PERMISSION: RatchetPermission = RatchetPermission::Policy; }
+struct RatchetPermSuperadmin;
+impl RatchetPermitted for RatchetPermSuperadmin { const PERMISSION: RatchetPermission = RatchetPermission::Superadmin; }
+
+/// A logged in admin whose role allows `P`: who, on which session, from where.
+struct RatchetAdmin<P: RatchetPermitted> {
+    username: String,
+    role: RatchetRole,
+    /// A name for the session, not the cookie, which is the credential.
+    session: String,
+    source_ip: Option<std::net::IpAddr>,
+    /// When the session was issued and when it times out, unix seconds;
+    /// pawlctl's last as long as the request.
+    issued: u64,
+    expires: Option<u64>,
+    permission: PhantomData<P>,
+}
+
+/// What a cookie stands for, in RATCHET_COOKIES.
+#[derive(Clone, Debug)]
+struct RatchetSession {
+    timeout: Instant,
+    username: String,
+    id: String,
+    issued: u64,
+}
 enum RatchetAuthError {
     NotAuthenticated
 }
@@ -770,36 +5792,71 @@ impl std::fmt::Debug for RatchetAuthError {
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for RatchetUser {
+impl<'r, P: RatchetPermitted> FromRequest<'r> for RatchetAdmin<P> {
     type Error = RatchetAuthError;
     /// Mechanism to identify whether someone who posesses
-    /// a cookies has an authorized cookie or not.
+    /// a cookies has an authorized cookie or not, and then whether
+    /// their role lets them do `P`, as of now.
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let mut cookie_store = RATCHET_COOKIES.lock().await;
-        let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
-        if let Some(cookie) = req.cookies().get("X-Ratchet-Auth-Token") {
-            let cookie_name = cookie.value();
-
-            match cookie_store.get_key_value(cookie_name) {
-                Some((_, max_age)) if Instant::now() < max_age.0 => request::Outcome::Success(RatchetUser),
-                Some((_, _)) => {
-                    if let Some((_, associated_user)) = cookie_store.remove(cookie_name){ // toss the cookie.
-                        match user_cookies.get_mut(&associated_user) {
-                            Some(cs) => {
-                                cs.remove(cookie_name);
-                                if cs.len() == 0 { user_cookies.remove(&associated_user); }
-                            },
-                            None => (),
-                        }
-                    }
-                    request::Outcome::Error((Status::Unauthorized, RatchetAuthError::NotAuthenticated))
-                 },
-                _ => request::Outcome::Error((Status::Unauthorized, RatchetAuthError::NotAuthenticated))
+        // root on the control socket, see rtp_serve_control_socket
+        if req.rocket().state::<RatchetControlSocket>().is_some() {
+            if !RatchetSocketPeers::forwarded(req) {
+                return request::Outcome::Error((Status::Forbidden, RatchetAuthError::NotAuthenticated));
             }
-        } else {
-            // bugger off
-            request::Outcome::Error((Status::Unauthorized, RatchetAuthError::NotAuthenticated))
+            return request::Outcome::Success(RatchetAdmin {
+                username: String::from(RATCHET_PAWLCTL_ACTOR),
+                role: RatchetRole::Superadmin,
+                session: String::from(RATCHET_PAWLCTL_ACTOR),
+                source_ip: None,
+                issued: rtp_unix_now(),
+                expires: None,
+                permission: PhantomData,
+            });
+        }
+        let session = match rtp_session(req).await {
+            request::Outcome::Success(session) => session,
+            request::Outcome::Error(e) => return request::Outcome::Error(e),
+            request::Outcome::Forward(f) => return request::Outcome::Forward(f),
+        };
+        match RATCHET_ADMINS.lock().await.get(&session.username) {
+            Some(a) if a.role.allows(P::PERMISSION) => request::Outcome::Success(RatchetAdmin {
+                role: a.role,
+                session: session.id,
+                source_ip: req.client_ip(),
+                issued: session.issued,
+                expires: Some(session.issued + AUTH_TIMEOUT_MINUTES*60),
+                username: session.username,
+                permission: PhantomData,
+            }),
+            Some(_) => request::Outcome::Error((Status::Forbidden, RatchetAuthError::NotAuthenticated)),
+            None => request::Outcome::Error((Status::Unauthorized, RatchetAuthError::NotAuthenticated)),
+        }
+    }
+}
+
+/// The session behind the cookie, if it's still good.
+async fn rtp_session(req: &Request<'_>) -> request::Outcome<RatchetSession, RatchetAuthError> {
+    let mut cookie_store = RATCHET_COOKIES.lock().await;
+    let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
+    if let Some(cookie) = req.cookies().get("X-Ratchet-Auth-Token") {
+        let cookie_name = cookie.value();
+
+        match cookie_store.get_key_value(cookie_name) {
+            Some((_, session)) if Instant::now() < session.timeout => request::Outcome::Success(session.clone()),
+            Some((_, _)) => {
+                if let Some(RatchetSession { username: associated_user, .. }) = cookie_store.remove(cookie_name){ // toss the cookie.
+                    if let Some(cs) = user_cookies.get_mut(&associated_user) {
+                        cs.remove(cookie_name);
+                        if cs.is_empty() { user_cookies.remove(&associated_user); }
+                    }
+                }
+                request::Outcome::Error((Status::Unauthorized, RatchetAuthError::NotAuthenticated))
+             },
+            _ => request::Outcome::Error((Status::Unauthorized, RatchetAuthError::NotAuthenticated))
         }
+    } else {
+        // bugger off
+        request::Outcome::Error((Status::Unauthorized, RatchetAuthError::NotAuthenticated))
     }
 }
 
@@ -809,14 +5866,163 @@ struct RatchetLoginCreds {
     password: String,
 }
 
+/// Failed logins are counted by the username tried, whether or not it's an
+/// admin, and by where they came from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum RatchetLoginSubject {
+    Username(String),
+    SourceIp(std::net::IpAddr),
+}
+
+impl RatchetLoginSubject {
+    fn of(username: &str, source_ip: Option<std::net::IpAddr>) -> Vec<RatchetLoginSubject> {
+        let mut subjects = vec![RatchetLoginSubject::Username(username.to_string())];
+        subjects.extend(source_ip.map(RatchetLoginSubject::SourceIp));
+        subjects
+    }
+
+    fn value(&self) -> String {
+        match self {
+            RatchetLoginSubject::Username(u) => u.clone(),
+            RatchetLoginSubject::SourceIp(ip) => ip.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RatchetLoginFailures {
+    count: u32,
+    last: Instant,
+    /// Attempts before this aren't even checked.
+    until: Instant,
+    /// Past the threshold, rather than backing off.
+    locked: bool,
+}
+
+/// After each failed login the username and the source IP back off,
+/// RATCHET_PAWL_LOGIN_BACKOFF_MS doubling with every failure up to
+/// RATCHET_PAWL_LOGIN_BACKOFF_MAX_MS; at RATCHET_PAWL_LOGIN_LOCKOUT_THRESHOLD
+/// failures for a username, or RATCHET_PAWL_LOGIN_LOCKOUT_IP_THRESHOLD for a
+/// source IP, they're locked out for RATCHET_PAWL_LOGIN_LOCKOUT_SECS. Failures
+/// are forgotten that long after the last one, too.
+#[derive(Clone, Debug)]
+struct RatchetLockoutPolicy {
+    backoff: std::time::Duration,
+    backoff_max: std::time::Duration,
+    threshold: u32,
+    ip_threshold: u32,
+    lockout: std::time::Duration,
+}
+
+impl RatchetLockoutPolicy {
+    fn from_env() -> Result<RatchetLockoutPolicy, String> {
+        let number = |var: &str, default: u64| match rtp_env_key(var) {
+            v if !v.is_empty() => v.parse::<u64>().map_err(|_| format!("{} is not a number: {}", var, v)),
+            _ => Ok(default),
+        };
+        Ok(RatchetLockoutPolicy {
+            backoff: std::time::Duration::from_millis(number("RATCHET_PAWL_LOGIN_BACKOFF_MS", 1000)?),
+            backoff_max: std::time::Duration::from_millis(number("RATCHET_PAWL_LOGIN_BACKOFF_MAX_MS", 30000)?),
+            threshold: number("RATCHET_PAWL_LOGIN_LOCKOUT_THRESHOLD", 5)?.clamp(1, u32::MAX as u64) as u32,
+            ip_threshold: number("RATCHET_PAWL_LOGIN_LOCKOUT_IP_THRESHOLD", 20)?.clamp(1, u32::MAX as u64) as u32,
+            lockout: std::time::Duration::from_secs(number("RATCHET_PAWL_LOGIN_LOCKOUT_SECS", 900)?),
+        })
+    }
+
+    fn threshold(&self, subject: &RatchetLoginSubject) -> u32 {
+        match subject {
+            RatchetLoginSubject::Username(_) => self.threshold,
+            RatchetLoginSubject::SourceIp(_) => self.ip_threshold,
+        }
+    }
+
+    /// How long to wait after the `count`th failure.
+    fn backoff(&self, count: u32) -> std::time::Duration {
+        self.backoff.saturating_mul(2u32.saturating_pow(count.saturating_sub(1))).min(self.backoff_max)
+    }
+}
+
+static RATCHET_LOCKOUT: std::sync::OnceLock<RatchetLockoutPolicy> = std::sync::OnceLock::new();
+
+lazy_static! {
+    // Only kept in memory, a restart forgives everyone.
+    static ref RATCHET_LOGIN_FAILURES: Mutex<HashMap<RatchetLoginSubject, RatchetLoginFailures>> = Mutex::new(HashMap::new());
+}
+
+fn rtp_lockout_policy() -> &'static RatchetLockoutPolicy {
+    RATCHET_LOCKOUT.get_or_init(|| RatchetLockoutPolicy::from_env().unwrap_or_else(|e| {
+        eprintln!("Ratchet-Pawl {}", e);
+        std::process::exit(2);
+    }))
+}
+
+/// Lockouts that have run out, and failures long enough ago, are dropped.
+fn rtp_forget_login_failures(failures: &mut HashMap<RatchetLoginSubject, RatchetLoginFailures>, now: Instant) {
+    let lockout = rtp_lockout_policy().lockout;
+    failures.retain(|_, f| now < f.until || (!f.locked && now.saturating_duration_since(f.last) < lockout));
+}
+
+/// Counts the attempt as a failure before the password is even checked, so
+/// attempts racing each other all see it, and says who it would lock out; or
+/// why the attempt shouldn't be checked at all. See rtp_login_succeeded.
+async fn rtp_login_attempt(username: &str, source_ip: Option<std::net::IpAddr>) -> Result<Vec<(RatchetLoginSubject, u32)>, String> {
+    let policy = rtp_lockout_policy();
+    let now = Instant::now();
+    let mut failures = RATCHET_LOGIN_FAILURES.lock().await;
+    rtp_forget_login_failures(&mut failures, now);
+    let subjects = RatchetLoginSubject::of(username, source_ip);
+    if let Some(why) = subjects.iter().find_map(|subject| {
+        match failures.get(subject) {
+            Some(f) if now < f.until && f.locked => Some(format!("{} locked out", subject.value())),
+            Some(f) if now < f.until => Some(format!("{} backing off", subject.value())),
+            _ => None,
+        }
+    }) {
+        return Err(why);
+    }
+    let mut locked = vec![];
+    for subject in subjects {
+        let threshold = policy.threshold(&subject);
+        let f = failures.entry(subject.clone()).or_insert(RatchetLoginFailures { count: 0, last: now, until: now, locked: false });
+        f.count += 1;
+        f.last = now;
+        if f.count >= threshold {
+            f.locked = true;
+            f.until = now + policy.lockout;
+            locked.push((subject, f.count));
+        } else {
+            f.until = now + policy.backoff(f.count);
+        }
+    }
+    Ok(locked)
+}
+
+/// The attempt wasn't a failure after all, the username and source IP are
+/// forgiven.
+async fn rtp_login_succeeded(username: &str, source_ip: Option<std::net::IpAddr>) {
+    let mut failures = RATCHET_LOGIN_FAILURES.lock().await;
+    for subject in RatchetLoginSubject::of(username, source_ip) {
+        failures.remove(&subject);
+    }
+}
+
+/// Only admins log in here, TACACS+ users are for ratchet.
+/// 
 /// TODO: Move out west and do something with JWT
 #[post("/trylogin", format = "multipart/form-data", data = "<creds>")]
-async fn try_login(cookies: &CookieJar<'_>, creds: Form<RatchetLoginCreds>) -> status::Custom<&'static str> {
-    let users = RATCHET_USERS.lock().await;
+async fn try_login(cookies: &CookieJar<'_>, creds: Form<RatchetLoginCreds>, source_ip: Option<std::net::IpAddr>) -> status::Custom<&'static str> {
+    let attempt = rtp_login_attempt(&creds.username, source_ip).await;
+    let users = RATCHET_ADMINS.lock().await;
     let mut cookie_store = RATCHET_COOKIES.lock().await;
     let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
     let cred = users.get(&creds.username);
-    if users.contains_key(&creds.username) && bcrypt::verify(&creds.password, &cred.unwrap().passhash) {
+    if let Err(why) = attempt {
+        // Same answer, same time, as a wrong password.
+        bcrypt::verify(&creds.password, &GUTTER.read().await);
+        drop((users, cookie_store, user_cookies));
+        rtp_audit("login_failed", &creds.username, "", source_ip, why).await;
+        status::Custom(Status::Unauthorized, "")
+    } else if users.contains_key(&creds.username) && bcrypt::verify(&creds.password, &cred.unwrap().passhash) {
         let new_uuid = Uuid::new_v4();
         let cookie = Cookie::build(("X-Ratchet-Auth-Token", new_uuid.to_string()))
                             .path("/")
@@ -826,7 +6032,14 @@ async fn try_login(cookies: &CookieJar<'_>, creds: Form<RatchetLoginCreds>) -> s
                         
         cookies.add(cookie); 
         let timeout = Instant::now() + std::time::Duration::from_secs(AUTH_TIMEOUT_MINUTES*60);
-        cookie_store.insert(new_uuid.to_string(), (timeout,creds.username.clone()));
+        let session = RatchetSession {
+            timeout,
+            username: creds.username.clone(),
+            id: Uuid::new_v4().to_string(),
+            issued: rtp_unix_now(),
+        };
+        let session_id = session.id.clone();
+        cookie_store.insert(new_uuid.to_string(), session);
         match user_cookies.get_mut(&creds.username) {
             Some(h) => {
                 h.insert(new_uuid.to_string());
@@ -840,15 +6053,32 @@ async fn try_login(cookies: &CookieJar<'_>, creds: Form<RatchetLoginCreds>) -> s
         // Also schedule a task to delete the cookie around the same time as the timeout
         // deauthorizing it.
         rocket::tokio::spawn(wipe_cookie(creds.username.clone(), new_uuid.to_string(), timeout));
+        drop((users, cookie_store, user_cookies));
+        rtp_login_succeeded(&creds.username, source_ip).await;
+        rtp_audit("login", &creds.username, &session_id, source_ip, String::new()).await;
         status::Custom(Status::Ok, "")
     } else if !users.contains_key(&creds.username){ 
         bcrypt::verify(&creds.password, &GUTTER.read().await);
+        drop((users, cookie_store, user_cookies));
+        rtp_audit("login_failed", &creds.username, "", source_ip, String::from("no such admin")).await;
+        rtp_audit_lockouts(&creds.username, source_ip, attempt.unwrap_or_default()).await;
         status::Custom(Status::Unauthorized, "")
     } else {
+        drop((users, cookie_store, user_cookies));
+        rtp_audit("login_failed", &creds.username, "", source_ip, String::from("wrong password")).await;
+        rtp_audit_lockouts(&creds.username, source_ip, attempt.unwrap_or_default()).await;
         status::Custom(Status::Unauthorized, "")
     }
 }
 
+/// Records whoever the failure locked out.
+async fn rtp_audit_lockouts(username: &str, source_ip: Option<std::net::IpAddr>, locked: Vec<(RatchetLoginSubject, u32)>) {
+    for (subject, count) in locked {
+        let detail = format!("{} failures, for {}s", count, rtp_lockout_policy().lockout.as_secs());
+        rtp_audit("login_locked", username, &subject.value(), source_ip, detail).await;
+    }
+}
+
 /// When the timeout has lapsed, the cookie is removed from the table, and no longer authorized
 /// Additionally, if the user has no more cookies
 async fn wipe_cookie(name: String, uuid: String, when: Instant) {
@@ -871,22 +6101,51 @@ async fn wipe_cookie(name: String, uuid: String, when: Instant) {
 }
 
 /// The frontend needs to know if the user is still authenticated so that
-/// data loss isn't encountered, when avoidable.
+/// data loss isn't encountered, when avoidable; and their role, so it
+/// doesn't offer what they can't do.
 #[get("/logged")]
-async fn logged(_admin: RatchetUser) -> status::Custom<&'static str> {
-    status::Custom(Status::Ok, "")
+async fn logged(admin: RatchetAdmin<RatchetPermView>) -> status::Custom<&'static str> {
+    status::Custom(Status::Ok, admin.role.name())
+}
+
+/// Who the frontend is logged in as, see RatchetAdmin.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetFrontendPrincipal {
+    username: String,
+    role: RatchetRole,
+    session: String,
+    source_ip: Option<String>,
+    issued: u64,
+    expires: Option<u64>,
+    /// Seconds until then.
+    expires_in: Option<u64>,
+}
+
+/// Frontend API for who's logged in, and until when.
+#[get("/whoami")]
+async fn whoami(admin: RatchetAdmin<RatchetPermView>) -> Json<RatchetFrontendPrincipal> {
+    let now = rtp_unix_now();
+    Json(RatchetFrontendPrincipal {
+        role: admin.role,
+        session: admin.session,
+        source_ip: admin.source_ip.map(|ip| ip.to_string()),
+        issued: admin.issued,
+        expires: admin.expires,
+        expires_in: admin.expires.map(|e| e.saturating_sub(now)),
+        username: admin.username,
+    })
 }
 
 /// Users may want to log out and log back in to guarantee 30 more minutes of
 /// installing users whose names are all just floating point values as fast
 /// as disk / I/O contention permit.
 #[get("/hangup")]
-async fn hangup(_admin: RatchetUser, cookies: &CookieJar<'_>) -> status::Custom<&'static str> {
+async fn hangup(admin: RatchetAdmin<RatchetPermView>, cookies: &CookieJar<'_>) -> status::Custom<&'static str> {
     if let Some(c) = cookies.get("X-Ratchet-Auth-Token") {
         let mut cookie_store = RATCHET_COOKIES.lock().await;
         let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
         let cookie_name = c.value();
-        if let Some((_, associated_user)) = cookie_store.remove(cookie_name) { // toss the cookie.
+        if let Some(RatchetSession { username: associated_user, .. }) = cookie_store.remove(cookie_name) { // toss the cookie.
             match user_cookies.get_mut(&associated_user) {
                 Some(cs) => {
                     cs.remove(cookie_name);
@@ -898,66 +6157,505 @@ async fn hangup(_admin: RatchetUser, cookies: &CookieJar<'_>) -> status::Custom<
             }
         }
 
-    }
-    status::Custom(Status::Ok, "")
+    }
+    rtp_audit("logout", &admin.username, &admin.session, admin.source_ip, String::new()).await;
+    status::Custom(Status::Ok, "")
+}
+
+/// Who's logged in; sessions are only ever shown by user, the cookies
+/// themselves are the credentials.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetFrontendSessions {
+    username: String,
+    sessions: usize,
+    /// Seconds until the last of them times out.
+    expires_in: u64,
+}
+
+/// Frontend API for listing sessions.
+#[get("/getsessions")]
+async fn get_sessions(_admin: RatchetAdmin<RatchetPermSuperadmin>) -> Json<Vec<RatchetFrontendSessions>> {
+    let cookie_store = RATCHET_COOKIES.lock().await;
+    let user_cookies = RATCHET_USER_COOKIES.lock().await;
+    let now = Instant::now();
+    let mut listed: Vec<RatchetFrontendSessions> = user_cookies
+        .iter()
+        .map(|(username, cookies)| RatchetFrontendSessions {
+            username: username.clone(),
+            sessions: cookies.len(),
+            expires_in: cookies.iter()
+                               .filter_map(|c| cookie_store.get(c))
+                               .map(|session| session.timeout.saturating_duration_since(now).as_secs())
+                               .max()
+                               .unwrap_or(0),
+        })
+        .collect();
+    listed.sort_by(|a, b| a.username.cmp(&b.username));
+    Json(listed)
+}
+
+/// Frontend API for logging a user out everywhere.
+#[post("/rmsessions", format = "multipart/form-data", data = "<username>")]
+async fn rm_sessions(admin: RatchetAdmin<RatchetPermSuperadmin>, username: Form<String>) -> status::Custom<&'static str> {
+    let mut cookie_store = RATCHET_COOKIES.lock().await;
+    let mut user_cookies = RATCHET_USER_COOKIES.lock().await;
+    match user_cookies.remove(&*username) {
+        Some(active_cookies) => {
+            let n = active_cookies.len();
+            active_cookies.into_iter().for_each(|each_cookie| {cookie_store.remove(&each_cookie);});
+            drop((cookie_store, user_cookies));
+            rtp_audit("sessions_rm", &admin.username, &username, admin.source_ip, format!("{} sessions", n)).await;
+            status::Custom(Status::Ok, "")
+        },
+        None => status::Custom(Status::Gone, ""),
+    }
+}
+
+/// A username or source IP with failed logins against it.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetFrontendLockout {
+    username: Option<String>,
+    source_ip: Option<String>,
+    failures: u32,
+    locked: bool,
+    /// Seconds until it can try again.
+    retry_in: u64,
+}
+
+/// Frontend API for listing who's backing off or locked out.
+#[get("/getlockouts")]
+async fn get_lockouts(_admin: RatchetAdmin<RatchetPermSuperadmin>) -> Json<Vec<RatchetFrontendLockout>> {
+    let now = Instant::now();
+    let mut failures = RATCHET_LOGIN_FAILURES.lock().await;
+    rtp_forget_login_failures(&mut failures, now);
+    let mut listed: Vec<(&RatchetLoginSubject, RatchetFrontendLockout)> = failures
+        .iter()
+        .map(|(subject, f)| (subject, RatchetFrontendLockout {
+            username: match subject { RatchetLoginSubject::Username(u) => Some(u.clone()), _ => None },
+            source_ip: match subject { RatchetLoginSubject::SourceIp(ip) => Some(ip.to_string()), _ => None },
+            failures: f.count,
+            locked: f.locked,
+            retry_in: f.until.saturating_duration_since(now).as_secs(),
+        }))
+        .collect();
+    listed.sort_by_key(|(subject, _)| (matches!(subject, RatchetLoginSubject::SourceIp(_)), subject.value()));
+    Json(listed.into_iter().map(|(_, l)| l).collect())
+}
+
+/// Frontend API for letting a username, or a source IP, try again right
+/// away; forgets its failures along with any lockout.
+#[post("/unlocklogin", format = "multipart/form-data", data = "<subject>")]
+async fn unlock_login(admin: RatchetAdmin<RatchetPermSuperadmin>, subject: Form<String>) -> status::Custom<&'static str> {
+    let mut failures = RATCHET_LOGIN_FAILURES.lock().await;
+    let mut subjects = vec![RatchetLoginSubject::Username(subject.to_string())];
+    subjects.extend(subject.parse::<std::net::IpAddr>().ok().map(RatchetLoginSubject::SourceIp));
+    let forgotten: Vec<(RatchetLoginSubject, RatchetLoginFailures)> = subjects
+        .into_iter()
+        .filter_map(|s| failures.remove(&s).map(|f| (s, f)))
+        .collect();
+    drop(failures);
+    if forgotten.is_empty() {
+        return status::Custom(Status::Gone, "");
+    }
+    for (s, f) in forgotten {
+        let detail = format!("{} failures{}", f.count, if f.locked { ", was locked out" } else { "" });
+        rtp_audit("login_unlocked", &admin.username, &s.value(), admin.source_ip, detail).await;
+    }
+    status::Custom(Status::Ok, "")
+}
+
+/// The API Key is for the backend / ratchet-proper to fetch details about
+/// the authentication / authorization database.
+/// 
+/// Keys are named, and only good for their scopes until they expire or
+/// are revoked. Schema version 4 added everything but the key, and schema
+/// version 5 traded the key for its keyed hash, found by its prefix.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RatchetApiKey {
+    name: String,
+    /// The first few characters, to find it by; not enough to guess the rest.
+    prefix: String,
+    /// See rtp_api_key_hash.
+    hash: String,
+    scopes: Vec<String>,
+    created: u64,
+    expires: Option<u64>,
+    /// Since this pawl started, it isn't saved.
+    #[serde(skip)]
+    last_used: Option<u64>,
+    #[serde(flatten)]
+    stamp: RatchetStamp,
+}
+
+impl RatchetKeyed for RatchetApiKey{
+    fn into_key(&self) -> &str {
+        self.name.as_str()
+    }
+}
+
+impl RatchetApiKey {
+    /// A key with the given secret, hashed under `hmac`.
+    fn new(hmac: &[u8; 32], name: &str, api_key: &str, scopes: Vec<String>, created: u64, expires: Option<u64>) -> Result<RatchetApiKey, RatchetStoreError> {
+        Ok(RatchetApiKey {
+            name: name.to_string(),
+            prefix: rtp_api_key_prefix(api_key),
+            hash: rtp_api_key_hash(hmac, api_key)?,
+            scopes,
+            created,
+            expires,
+            last_used: None,
+            stamp: RatchetStamp::default(),
+        })
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|e| e <= now)
+    }
+}
+
+/// The one pawl makes for itself, and the one ratchet-cycle is handed.
+const RATCHET_DEFAULT_API_KEY: &str = "default";
+
+const RATCHET_API_SCOPES: &[&str] = &["dump:users", "dump:devs", "dump:policy", "poll", "metrics"];
+
+const RATCHET_API_KEY_PREFIX_LEN: usize = 8;
+
+fn rtp_api_key_prefix(api_key: &str) -> String {
+    api_key.chars().take(RATCHET_API_KEY_PREFIX_LEN).collect()
+}
+
+/// HMAC-SHA256 of the key under this pawl's API key secret, hex. Only this
+/// is kept, so neither the database nor a backup gives a key away.
+fn rtp_api_key_hash(hmac: &[u8; 32], api_key: &str) -> Result<String, RatchetStoreError> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(hmac).map_err(|_| RatchetStoreError::Crypto)?;
+    mac.update(api_key.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Whether `api_key` hashes to `hash`, in constant time.
+fn rtp_api_key_verify(hmac: &[u8; 32], api_key: &str, hash: &str) -> bool {
+    let expected = match hex::decode(hash) {
+        Ok(h) => h,
+        Err(_) => return false,
+    };
+    let mut mac = match <Hmac<Sha256> as Mac>::new_from_slice(hmac) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(api_key.as_bytes());
+    mac.verify_slice(&expected).is_ok()
+}
+
+/// The secret API keys are hashed under, made the first time it's needed,
+/// and put in use. It's sealed like the backup signing key, and travels
+/// with backups.
+fn rtp_load_api_key_secret() -> Result<[u8; 32], RatchetStoreError> {
+    let mut txn = RATCHET_STORE.begin()?;
+    let secret: [u8; 32] = match txn.get(RATCHET_META_TABLE.name(), RATCHET_META_API_KEY_HMAC)? {
+        Some(secret) => secret.as_slice().try_into().map_err(|_| RatchetStoreError::Format(String::from("API key secret is not 32 bytes")))?,
+        None => {
+            let secret = rand::random::<[u8; 32]>();
+            txn.put(RATCHET_META_TABLE.name(), RATCHET_META_API_KEY_HMAC, &secret)?;
+            txn.commit()?;
+            secret
+        },
+    };
+    *RATCHET_API_KEY_HMAC.write().unwrap_or_else(|e| e.into_inner()) = secret;
+    Ok(secret)
 }
 
-/// The API Key is for the backend / ratchet-proper to fetch details about
-/// the authentication / authorization database.
-#[derive(Clone, FromForm, Debug, Serialize, Deserialize)]
-struct RatchetApiKey {
-    api_key: String,
+/// Same, straight on the redb file inside a migration, not put in use.
+fn rtp_api_key_secret_in(write_txn: &WriteTransaction, key: &[u8; 32]) -> Result<[u8; 32], RatchetStoreError> {
+    let mut meta = write_txn.open_table(RATCHET_META_TABLE)?;
+    let sealed = meta.get(RATCHET_META_API_KEY_HMAC)?.map(|v| v.value());
+    let secret = match sealed {
+        Some(sealed) => rtp_open_record(key, RATCHET_META_TABLE.name(), RATCHET_META_API_KEY_HMAC, &sealed)?,
+        None => {
+            let secret = rand::random::<[u8; 32]>().to_vec();
+            meta.insert(RATCHET_META_API_KEY_HMAC, rtp_seal_record(key, RATCHET_META_TABLE.name(), RATCHET_META_API_KEY_HMAC, &secret)?)?;
+            secret
+        },
+    };
+    secret.as_slice().try_into().map_err(|_| RatchetStoreError::Format(String::from("API key secret is not 32 bytes")))
 }
 
-impl RatchetKeyed for RatchetApiKey{
-    fn into_key(&self) -> &str {
-        "-" // don't write the key in the clear
+/// Puts a restored backup's API key secret in use, if it has one and it
+/// isn't already, so the keys it brings verify. It goes in the same
+/// transaction as the keys, see rtp_apply_changes.
+fn rtp_api_key_secret_change(restored: &RatchetBackupPayload, actor: &str) -> Result<Option<RatchetChange>, RatchetStoreError> {
+    let theirs: [u8; 32] = match restored.api_key_hmac.as_ref().and_then(|h| hex::decode(h).ok()).and_then(|h| h.try_into().ok()) {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    if rtp_load_api_key_secret()? == theirs {
+        return Ok(None);
     }
+    Ok(Some(RatchetChange {
+        table: RATCHET_META_TABLE.name(),
+        record_key: RATCHET_META_API_KEY_HMAC.to_string(),
+        op: RatchetPersistOp::ApiKeySecret(theirs),
+        actor: actor.to_string(),
+    }))
+}
+
+fn rtp_api_key_hmac() -> [u8; 32] {
+    *RATCHET_API_KEY_HMAC.read().unwrap_or_else(|e| e.into_inner())
 }
 
-/// Choose a pretty hard-to-guess API key
-async fn initialize_api_key() -> Result<(), redb::Error> { 
+/// A pretty hard-to-guess API key
+fn rtp_new_api_key() -> String {
     let mut api_key: String = String::with_capacity(128);
+    while api_key.len() < 128 {
+        let c = rand::random::<u8>();
+        if c.is_ascii_alphanumeric() || c.is_ascii_graphic() || c.is_ascii_punctuation() {
+            api_key.push(c as char);
+        }
+    }
+    api_key
+}
+
+/// Where a new `default` key is left for ratchet-cycle, which reads it and
+/// deletes it: RATCHET_PAWL_API_KEY_FILE, or next to the database.
+fn rtp_api_key_handoff_path() -> std::path::PathBuf {
+    match rtp_env_key("RATCHET_PAWL_API_KEY_FILE") {
+        p if !p.is_empty() => std::path::PathBuf::from(p),
+        _ => RATCHET_DATA_DIR.join("ratchet-api-key"),
+    }
+}
+
+/// Writes the key owner-only, in the "Api-Key: " line ratchet-cycle has
+/// always matched on.
+fn rtp_hand_off_api_key(api_key: &str) -> std::io::Result<std::path::PathBuf> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let path = rtp_api_key_handoff_path();
+    // one that was never picked up could have anyone's permissions, start over
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let mut f = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+    writeln!(f, "Api-Key: {}", api_key)?;
+    f.sync_all()?;
+    Ok(path)
+}
+
+/// Choose a pretty hard-to-guess API key, if there's none at all, and hand
+/// it off once it's stored; it can't be shown again after this.
+async fn initialize_api_key() -> Result<(), RatchetStoreError> { 
     let mut api_init = RATCHET_APIKEYS.lock().await;
     if api_init.len() == 0 {
-        while api_key.len() < 128 {
-            let c = rand::random::<u8>();
-            if c.is_ascii_alphanumeric() || c.is_ascii_graphic() || c.is_ascii_punctuation() {
-                api_key.push(c as char);
-            }
+        let api_key = rtp_new_api_key();
+        let scopes = RATCHET_API_SCOPES.iter().map(|s| s.to_string()).collect();
+        let mut new_key = RatchetApiKey::new(&rtp_api_key_hmac(), RATCHET_DEFAULT_API_KEY, &api_key, scopes, rtp_unix_now(), None)?;
+        new_key.stamp = RatchetStamp::new(RATCHET_SYSTEM_ACTOR);
+        RATCHET_APIKEY_TABLE.write(&new_key, RATCHET_SYSTEM_ACTOR).await?;
+        // CONTRACT: ratchet-cycle picks this up from the handoff file, see rtp_hand_off_api_key
+        match rtp_hand_off_api_key(&api_key) {
+            Ok(path) => println!("Ratchet-Pawl Initialization created an API key, handed off in {}", path.display()),
+            Err(e) => {
+                // nobody could ever use it, the next start makes another
+                RATCHET_APIKEY_TABLE.queue_rm(&new_key, RATCHET_SYSTEM_ACTOR).done().await?;
+                return Err(RatchetStoreError::Format(format!("unable to hand off the API key: {}", e)));
+            },
         }
-        println!("Ratchet-Pawl Initialization creating API-Key details:");
-        // CONTRACT: ratchet-cycle expects the api-key to be dumped in the first 10 or so 
-        // lines for pawl's execution, make sure to maintain this; it matches on "Api-Key: " pattern
-        println!("Api-Key: {}", api_key);
-        api_init.insert(api_key.clone(), RatchetApiKey {
-                api_key: api_key.clone()
-            });
-        RATCHET_APIKEY_TABLE.write(&RatchetApiKey {
-            api_key: api_key
-        }).await?
+        api_init.insert(new_key.prefix.clone(), new_key);
     }
     Ok(())
 }
 
+/// What an API key may be used for, each `api_*` route asks for one.
+trait RatchetApiScope: Send {
+    const SCOPE: &'static str;
+}
+struct RatchetScopeDumpUsers;
+impl RatchetApiScope for RatchetScopeDumpUsers { const SCOPE: &'static str = "dump:users"; }
+struct RatchetScopeDumpDevs;
+impl RatchetApiScope for RatchetScopeDumpDevs { const SCOPE: &'static str = "dump:devs"; }
+struct RatchetScopeDumpPolicy;
+impl RatchetApiScope for RatchetScopeDumpPolicy { const SCOPE: &'static str = "dump:policy"; }
+struct RatchetScopePoll;
+impl RatchetApiScope for RatchetScopePoll { const SCOPE: &'static str = "poll"; }
+struct RatchetScopeMetrics;
+impl RatchetApiScope for RatchetScopeMetrics { const SCOPE: &'static str = "metrics"; }
+
+/// An API user whose key has scope `S`.
+struct RatchetApiCaller<S: RatchetApiScope> {
+    /// Its prefix and hash, there's no key over the API socket.
+    key: Option<(String, String)>,
+    expires: Option<u64>,
+    scope: PhantomData<S>,
+}
+
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for RatchetApiKey {
+impl<'r, S: RatchetApiScope> FromRequest<'r> for RatchetApiCaller<S> {
     type Error = RatchetAuthError;
-    /// Mechanism to identify an API user.
+    /// Mechanism to identify an API user. Unknown and expired keys aren't
+    /// found, known ones without the scope are forbidden.
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let api_key_store = RATCHET_APIKEYS.lock().await;
-        if let Some(api_key) = req.headers().get_one("X-Ratchet-Api-Key") {
-            match api_key_store.get_key_value(api_key) {
-                Some((_, _)) => {
-                    request::Outcome::Success(RatchetApiKey{api_key: "".to_string()})
+        // already let in by its credentials, see rtp_serve_api_socket
+        if req.rocket().state::<RatchetApiSocket>().is_some() {
+            if !RatchetSocketPeers::forwarded(req) {
+                return request::Outcome::Error((Status::Forbidden, RatchetAuthError::NotAuthenticated));
+            }
+            rtp_audit_api_use::<S>(req, RATCHET_API_SOCKET_ACTOR).await;
+            return request::Outcome::Success(RatchetApiCaller { key: None, expires: None, scope: PhantomData });
+        }
+        let mut api_key_store = RATCHET_APIKEYS.lock().await;
+        let now = rtp_unix_now();
+        let hmac = rtp_api_key_hmac();
+        // who it was and why, for the audit log; never the key itself
+        let (status, actor, why) = if let Some(api_key) = req.headers().get_one("X-Ratchet-Api-Key") {
+            match api_key_store.get_mut(&rtp_api_key_prefix(api_key)) {
+                Some(k) if !rtp_api_key_verify(&hmac, api_key, &k.hash) => (Status::NotFound, k.name.clone(), String::from("wrong key")),
+                Some(k) if k.expired(now) => (Status::NotFound, k.name.clone(), String::from("expired")),
+                Some(k) if !k.scopes.iter().any(|s| s == S::SCOPE) => {
+                    (Status::Forbidden, k.name.clone(), format!("no {} scope", S::SCOPE))
+                },
+                Some(k) => {
+                    k.last_used = Some(now);
+                    let name = k.name.clone();
+                    let caller = RatchetApiCaller { key: Some((k.prefix.clone(), k.hash.clone())), expires: k.expires, scope: PhantomData };
+                    drop(api_key_store);
+                    rtp_audit_api_use::<S>(req, &name).await;
+                    return request::Outcome::Success(caller);
                  },
-                _ => request::Outcome::Error((Status::NotFound, RatchetAuthError::NotAuthenticated))
+                _ => (Status::NotFound, String::from(RATCHET_UNKNOWN_ACTOR), String::from("unknown key")),
             }
         } else {
             // bugger off
-            request::Outcome::Error((Status::NotFound, RatchetAuthError::NotAuthenticated))
-        }
+            (Status::NotFound, String::from(RATCHET_UNKNOWN_ACTOR), String::from("no key"))
+        };
+        drop(api_key_store);
+        rtp_audit_repeated("api_key_rejected", &actor, req.uri().path().as_str(), req.client_ip(), why).await;
+        request::Outcome::Error((status, RatchetAuthError::NotAuthenticated))
+    }
+}
+
+/// Who's audited for a key that isn't any key's.
+const RATCHET_UNKNOWN_ACTOR: &str = "unknown";
+
+/// Who's audited for using the API socket, which takes no key.
+const RATCHET_API_SOCKET_ACTOR: &str = "api-socket";
+
+/// A long poll is a subscription, anything else a use of the key.
+async fn rtp_audit_api_use<S: RatchetApiScope>(req: &Request<'_>, actor: &str) {
+    let event = if S::SCOPE == RatchetScopePoll::SCOPE { "poll_subscribe" } else { "api_use" };
+    rtp_audit_repeated(event, actor, req.uri().path().as_str(), req.client_ip(), String::new()).await;
+}
+
+/// A new API key, `scopes` can be repeated or comma separated.
+#[derive(FromForm)]
+struct RatchetNewApiKey {
+    name: String,
+    scopes: Vec<String>,
+    expires_in_days: Option<u64>,
+}
+
+/// Frontend API for creating an API key, the key is in the response and
+/// nowhere else, it can't be shown again.
+#[post("/addapikey", format = "multipart/form-data", data = "<newkey>")]
+async fn add_api_key(admin: RatchetAdmin<RatchetPermSuperadmin>, newkey: Form<RatchetNewApiKey>) -> status::Custom<String> {
+    let scopes: Vec<String> = newkey.scopes.iter()
+                                    .flat_map(|s| s.split(','))
+                                    .map(|s| s.trim().to_string())
+                                    .filter(|s| !s.is_empty())
+                                    .collect();
+    if newkey.name.trim().is_empty() || scopes.is_empty() || !scopes.iter().all(|s| RATCHET_API_SCOPES.contains(&s.as_str())) {
+        return status::Custom(Status::BadRequest, format!("Name a key, and give it some of: {}", RATCHET_API_SCOPES.join(", ")));
+    }
+    let now = rtp_unix_now();
+    let expires = match newkey.expires_in_days.map(|d| d.checked_mul(86400).and_then(|secs| now.checked_add(secs))) {
+        Some(None) => return status::Custom(Status::BadRequest, String::from("That key would never expire, leave the expiry out instead.")),
+        expires => expires.flatten(),
+    };
+    let mut api_keys = RATCHET_APIKEYS.lock().await;
+    if api_keys.values().any(|k| k.name == newkey.name) {
+        return status::Custom(Status::Conflict, String::new());
+    }
+    // prefixes index the keys, so they can't be shared
+    let mut api_key = rtp_new_api_key();
+    while api_keys.contains_key(&rtp_api_key_prefix(&api_key)) {
+        api_key = rtp_new_api_key();
+    }
+    let mut new_key = match RatchetApiKey::new(&rtp_api_key_hmac(), &newkey.name, &api_key, scopes, now, expires) {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("Ratchet-Pawl unable to add API key: {:?}", e);
+            return status::Custom(Status::InternalServerError, String::new());
+        },
+    };
+    new_key.stamp = RatchetStamp::new(&admin.username);
+    let saved = RATCHET_APIKEY_TABLE.queue_write(&new_key, &admin.username);
+    api_keys.insert(new_key.prefix.clone(), new_key.clone());
+    drop(api_keys);
+    if let Err(e) = saved.done().await {
+        eprintln!("Ratchet-Pawl unable to add API key: {:?}", e);
+        rtp_roll_back(&mut *RATCHET_APIKEYS.lock().await, &new_key.prefix, Some(&new_key), None);
+        return status::Custom(Status::InternalServerError, String::new());
+    }
+    status::Custom(Status::Ok, api_key)
+}
+
+/// Special structure to only return what's safe about API keys
+/// back to the Frontend.
+#[derive(Clone, Debug, Serialize)]
+struct RatchetFrontendApiKey {
+    name: String,
+    scopes: Vec<String>,
+    created: u64,
+    expires: Option<u64>,
+    last_used: Option<u64>,
+    #[serde(flatten)]
+    stamp: RatchetStamp,
+}
+
+/// Frontend API for listing API keys.
+#[get("/getapikeys")]
+async fn get_api_keys(_admin: RatchetAdmin<RatchetPermSuperadmin>) -> Json<Vec<RatchetFrontendApiKey>> {
+    let api_keys = RATCHET_APIKEYS.lock().await;
+    let mut listed: Vec<RatchetFrontendApiKey> = api_keys
+        .values()
+        .map(|k| RatchetFrontendApiKey {
+            name: k.name.clone(),
+            scopes: k.scopes.clone(),
+            created: k.created,
+            expires: k.expires,
+            last_used: k.last_used,
+            stamp: k.stamp.clone(),
+        })
+        .collect();
+    listed.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(listed)
+}
+
+/// Frontend API for revoking an API key by name. Pollers holding it are
+/// let go straight away. The last key can't be revoked, pawl would only
+/// make a new one on its next start.
+#[post("/rmapikey", format = "multipart/form-data", data = "<name>")]
+async fn rm_api_key(admin: RatchetAdmin<RatchetPermSuperadmin>, name: Form<String>) -> status::Custom<&'static str> {
+    let mut api_keys = RATCHET_APIKEYS.lock().await;
+    let prefix = match api_keys.values().find(|k| k.name == *name) {
+        Some(k) => k.prefix.clone(),
+        None => return status::Custom(Status::Gone, ""),
+    };
+    if api_keys.len() == 1 {
+        return status::Custom(Status::Conflict, "");
+    }
+    let revoked = match api_keys.remove(&prefix) {
+        Some(k) => k,
+        None => return status::Custom(Status::Gone, ""),
+    };
+    let saved = RATCHET_APIKEY_TABLE.queue_rm(&revoked, &admin.username);
+    drop(api_keys);
+    if let Err(e) = saved.done().await {
+        eprintln!("Ratchet-Pawl unable to revoke API key: {:?}", e);
+        rtp_roll_back(&mut *RATCHET_APIKEYS.lock().await, &prefix, None, Some(revoked));
+        return status::Custom(Status::InternalServerError, "");
     }
+    rocket::tokio::spawn(rtp_notify_pollers());
+    status::Custom(Status::Ok, "")
 }
 
 #[derive(Debug)]
@@ -1132,4 +6830,904 @@ fn rtp_fetch_precis_username(packet_text: &Vec<u8>, i18n: bool) -> Result<String
     } else {
         Ok(raw_username.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use age::secrecy::ExposeSecret;
+    use rocket::local::asynchronous::Client;
+
+    fn entry(event: &str, object: &str, detail: &str) -> RatchetAuditEntry {
+        RatchetAuditEntry {
+            seq: 7,
+            at: 1735689600,
+            event: event.to_string(),
+            actor: String::from("bob"),
+            object: object.to_string(),
+            source_ip: Some(String::from("10.0.0.1")),
+            detail: detail.to_string(),
+            prev: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    fn target(transport: RatchetSyslogTransport, port: u16) -> RatchetSyslogTarget {
+        RatchetSyslogTarget { transport, host: String::from("127.0.0.1"), port, tls: None, facility: 10 }
+    }
+
+    fn sinks(target: RatchetSyslogTarget) -> RatchetEventSinks {
+        RatchetEventSinks {
+            host: String::from("pawl-test"),
+            severity: RatchetSeverity::Notice,
+            syslog: Some((target, None)),
+            file: None,
+            failing: false,
+        }
+    }
+
+    /// Reads one RFC 6587 octet counted frame.
+    fn frame(stream: &mut std::net::TcpStream) -> String {
+        let mut len = vec![];
+        let mut b = [0u8; 1];
+        loop {
+            stream.read_exact(&mut b).unwrap();
+            if b[0] == b' ' {
+                break;
+            }
+            len.push(b[0]);
+        }
+        let mut msg = vec![0u8; str::from_utf8(&len).unwrap().parse().unwrap()];
+        stream.read_exact(&mut msg).unwrap();
+        String::from_utf8(msg).unwrap()
+    }
+
+    #[test]
+    fn rfc3339_dates() {
+        assert_eq!(rtp_rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rtp_rfc3339(68169600), "1972-02-29T00:00:00Z");
+        assert_eq!(rtp_rfc3339(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(rtp_rfc3339(1709251199), "2024-02-29T23:59:59Z");
+        assert_eq!(rtp_rfc3339(1735689600), "2025-01-01T00:00:00Z");
+        assert_eq!(rtp_rfc3339(4107542400), "2100-03-01T00:00:00Z");
+        assert_eq!(rtp_rfc3339(253402300799), "9999-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn rfc3339_every_day_follows_the_last() {
+        let leap = |y: u32| y.is_multiple_of(4) && (!y.is_multiple_of(100) || y.is_multiple_of(400));
+        let (mut y, mut m, mut d) = (1970u32, 1u32, 1u32);
+        // to 2500, through 2000 and 2100 both
+        for day in 0..194_000u64 {
+            assert_eq!(rtp_rfc3339(day * 86400 + 86399), format!("{:04}-{:02}-{:02}T23:59:59Z", y, m, d));
+            let days_in_month = match m {
+                2 if leap(y) => 29,
+                2 => 28,
+                4 | 6 | 9 | 11 => 30,
+                _ => 31,
+            };
+            d += 1;
+            if d > days_in_month {
+                d = 1;
+                m += 1;
+            }
+            if m > 12 {
+                m = 1;
+                y += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn syslog_over_udp_is_one_datagram() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let target = target(RatchetSyslogTransport::Udp, server.local_addr().unwrap().port());
+        let mut conn = target.connect().unwrap();
+        let e = entry("login_failed", "s]1", "wrong \"password\"");
+        conn.send(&target.format("pawl-test", rtp_event_severity(&e.event), &e)).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(str::from_utf8(&buf[..n]).unwrap(), format!(
+            "<84>1 2025-01-01T00:00:00Z pawl-test ratchet-pawl {} login_failed \
+             [pawl@32473 version=\"1\" seq=\"7\" event=\"login_failed\" actor=\"bob\" object=\"s\\]1\" source_ip=\"10.0.0.1\"] \
+             login_failed by bob on s]1: wrong \"password\"", std::process::id()));
+    }
+
+    #[test]
+    fn syslog_over_tcp_is_octet_counted() {
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sinks = sinks(target(RatchetSyslogTransport::Tcp, server.local_addr().unwrap().port()));
+        // counted in bytes, not characters
+        sinks.forward(&entry("user_add", "jürgen", "")).unwrap();
+        sinks.forward(&entry("login", "", "")).unwrap();
+        sinks.forward(&entry("login_locked", "", "5 failures")).unwrap();
+
+        let (mut stream, _) = server.accept().unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let first = frame(&mut stream);
+        assert!(first.starts_with("<85>1 2025-01-01T00:00:00Z pawl-test ratchet-pawl "), "{}", first);
+        assert!(first.ends_with("user_add by bob on jürgen"), "{}", first);
+        // login is info, below the notice default
+        let second = frame(&mut stream);
+        assert!(second.starts_with("<84>1 "), "{}", second);
+        assert!(second.ends_with("login_locked by bob: 5 failures"), "{}", second);
+    }
+
+    #[test]
+    fn syslog_over_tcp_reconnects() {
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sinks = sinks(target(RatchetSyslogTransport::Tcp, server.local_addr().unwrap().port()));
+        sinks.forward(&entry("user_add", "alice", "")).unwrap();
+        let (mut stream, _) = server.accept().unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        assert!(frame(&mut stream).ends_with("user_add by bob on alice"));
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+        drop(stream);
+
+        // the first write after a hang up can still look like it went, keep
+        // sending until one lands on the new connection
+        let accepted = std::thread::spawn(move || server.accept().unwrap().0);
+        for _ in 0..10 {
+            let _ = sinks.forward(&entry("user_rm", "alice", ""));
+            if accepted.is_finished() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        let mut stream = accepted.join().unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        assert!(frame(&mut stream).ends_with("user_rm by bob on alice"));
+    }
+
+    /// Sealed audit rows 1 to n, chained the way rtp_audit_append does it.
+    fn audit_chain(key: &[u8; 32], n: u64) -> (Vec<(u64, Vec<u8>)>, RatchetAuditHead) {
+        let mut prev = String::from(RATCHET_AUDIT_GENESIS);
+        let mut rows = vec![];
+        for seq in 1..=n {
+            let mut e = RatchetAuditEntry { seq, prev: prev.clone(), ..entry("user_add", &format!("user{}", seq), "") };
+            e.hash = rtp_audit_hash(&e);
+            prev = e.hash.clone();
+            rows.push((seq, seal_audit(key, &e)));
+        }
+        (rows, RatchetAuditHead { seq: n, hash: prev })
+    }
+
+    fn seal_audit(key: &[u8; 32], e: &RatchetAuditEntry) -> Vec<u8> {
+        rtp_seal_record(key, RATCHET_AUDIT_TABLE.name(), &e.seq.to_string(), &serde_json::to_vec(e).unwrap()).unwrap()
+    }
+
+    fn open_audit(key: &[u8; 32], row: &(u64, Vec<u8>)) -> RatchetAuditEntry {
+        serde_json::from_slice(&rtp_open_record(key, RATCHET_AUDIT_TABLE.name(), &row.0.to_string(), &row.1).unwrap()).unwrap()
+    }
+
+    fn checks(problems: Vec<RatchetCheckProblem>) -> Vec<&'static str> {
+        problems.iter().map(|p| p.check).collect()
+    }
+
+    #[test]
+    fn verify_audit_finds_edited_entries() {
+        let key = rand::random::<[u8; 32]>();
+        let (rows, head) = audit_chain(&key, 4);
+        assert!(rtp_verify_audit(&key, &rows, Some(head.clone())).is_empty());
+
+        // edited, hash left as it was
+        let mut edited = rows.clone();
+        let mut e = open_audit(&key, &edited[1]);
+        e.detail = String::from("nothing to see");
+        edited[1].1 = seal_audit(&key, &e);
+        assert_eq!(checks(rtp_verify_audit(&key, &edited, Some(head.clone()))), ["audit_edited"]);
+        // and hashed again, the next one no longer follows it
+        e.hash = rtp_audit_hash(&e);
+        edited[1].1 = seal_audit(&key, &e);
+        assert_eq!(checks(rtp_verify_audit(&key, &edited, Some(head.clone()))), ["audit_chain_broken"]);
+        // swapped with another entry, neither opens where it is
+        let mut swapped = rows.clone();
+        (swapped[1].1, swapped[2].1) = (rows[2].1.clone(), rows[1].1.clone());
+        assert_eq!(checks(rtp_verify_audit(&key, &swapped, Some(head))), ["audit_unreadable", "audit_unreadable"]);
+    }
+
+    #[test]
+    fn verify_audit_finds_deleted_entries() {
+        let key = rand::random::<[u8; 32]>();
+        let (rows, head) = audit_chain(&key, 4);
+        let mut deleted = rows.clone();
+        deleted.remove(1);
+        assert_eq!(checks(rtp_verify_audit(&key, &deleted, Some(head.clone()))), ["audit_missing", "audit_chain_broken"]);
+        // the last one, only the head knows it was there
+        assert_eq!(checks(rtp_verify_audit(&key, &rows[..3], Some(head))), ["audit_truncated"]);
+        assert_eq!(checks(rtp_verify_audit(&key, &rows, None)), ["audit_head_missing"]);
+    }
+
+    lazy_static! {
+        /// The routes share pawl's globals, so they're tried one test at a
+        /// time; true once pawl has started, see client.
+        static ref STARTED: Mutex<bool> = Mutex::new(false);
+    }
+
+    /// For tests on pawl's key and the legacy rows flag: keeps the route
+    /// tests out, and puts both back when dropped.
+    struct KeyGlobals {
+        _started: rocket::tokio::sync::MutexGuard<'static, bool>,
+        key: Arc<[u8; 32]>,
+        legacy: bool,
+    }
+
+    impl Drop for KeyGlobals {
+        fn drop(&mut self) {
+            rtp_take_key(*self.key);
+            RATCHET_LEGACY_ROWS.store(self.legacy, Ordering::SeqCst);
+        }
+    }
+
+    impl KeyGlobals {
+        fn keep(started: rocket::tokio::sync::MutexGuard<'static, bool>) -> KeyGlobals {
+            KeyGlobals {
+                _started: started,
+                key: rtp_db_key(),
+                legacy: RATCHET_LEGACY_ROWS.load(Ordering::SeqCst),
+            }
+        }
+    }
+
+    fn key_globals() -> KeyGlobals {
+        KeyGlobals::keep(STARTED.blocking_lock())
+    }
+
+    /// A new redb file with pawl's tables in it.
+    fn scratch_db(name: &str) -> Database {
+        let path = std::env::temp_dir().join(format!("ratchet-pawl-test-{}-{}.redb", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::create(path).unwrap();
+        let write_txn = db.begin_write().unwrap();
+        for table in [&RATCHET_USERS_TABLE.0, &RATCHET_DEVS_TABLE.0, &RATCHET_USER_CMD_POLICY_TABLE.0, &RATCHET_APIKEY_TABLE.0, &RATCHET_ADMINS_TABLE.0] {
+            write_txn.open_table(*table).unwrap();
+        }
+        write_txn.open_table(RATCHET_META_TABLE).unwrap();
+        write_txn.commit().unwrap();
+        db
+    }
+
+    /// A row the way pawl wrote them before the envelope: FF1 under the
+    /// zero-padded masking key, stored under its own key.
+    fn put_legacy_row(db: &Database, table: TableDefinition<&str, Vec<u8>>, masking_key: &str, record_key: &str, row: &[u8]) {
+        let ff = FF1::<Aes256>::new(&rtp_legacy_key(&masking_key.to_string()), 2).unwrap();
+        let ct = ff.encrypt(&[], &BinaryNumeralString::from_bytes_le(row)).unwrap().to_bytes_le();
+        let write_txn = db.begin_write().unwrap();
+        write_txn.open_table(table).unwrap().insert(record_key, ct).unwrap();
+        write_txn.commit().unwrap();
+    }
+
+    #[test]
+    fn envelopes_refuse_tampered_and_moved_rows() {
+        let _globals = key_globals();
+        RATCHET_LEGACY_ROWS.store(false, Ordering::SeqCst);
+        let key = rand::random::<[u8; 32]>();
+        let users = RATCHET_USERS_TABLE.name();
+        let row = br#"{"username":"alice"}"#;
+        let (alice_key, alice) = rtp_seal_indexed_record(&key, users, "alice", row).unwrap();
+        let (bob_key, _) = rtp_seal_indexed_record(&key, users, "bob", row).unwrap();
+        assert_eq!(rtp_open_indexed_record(&key, users, &alice_key, &alice).unwrap(), (String::from("alice"), row.to_vec()));
+
+        for i in 0..alice.len() {
+            let mut tampered = alice.clone();
+            tampered[i] ^= 1;
+            assert!(matches!(rtp_open_indexed_record(&key, users, &alice_key, &tampered), Err(RatchetStoreError::Crypto)), "byte {}", i);
+        }
+        assert!(matches!(rtp_open_indexed_record(&key, users, &alice_key, &alice[..alice.len() - 1]), Err(RatchetStoreError::Crypto)));
+        // put in place of bob's row, or in another table
+        assert!(matches!(rtp_open_indexed_record(&key, users, &bob_key, &alice), Err(RatchetStoreError::Crypto)));
+        assert!(matches!(rtp_open_indexed_record(&key, RATCHET_ADMINS_TABLE.name(), &alice_key, &alice), Err(RatchetStoreError::Crypto)));
+        assert!(matches!(rtp_open_indexed_record(&rand::random::<[u8; 32]>(), users, &alice_key, &alice), Err(RatchetStoreError::Crypto)));
+
+        // V1, as meta is sealed, is bound to its key as well
+        let meta = rtp_seal_record(&key, RATCHET_META_TABLE.name(), "a", row).unwrap();
+        assert_eq!(rtp_open_record(&key, RATCHET_META_TABLE.name(), "a", &meta).unwrap(), row.to_vec());
+        assert!(matches!(rtp_open_record(&key, RATCHET_META_TABLE.name(), "b", &meta), Err(RatchetStoreError::Crypto)));
+        // and a V1 row isn't taken in the five tables once they're all V2
+        let v1 = rtp_seal_record(&key, users, &alice_key, row).unwrap();
+        assert!(matches!(rtp_open_record(&key, users, &alice_key, &v1), Err(RatchetStoreError::Crypto)));
+    }
+
+    #[test]
+    fn kdf_derives_one_key_per_secret_and_salt() {
+        let mut keys = vec![];
+        for kdf in [RatchetKdf::Argon2id, RatchetKdf::HkdfSha256] {
+            let mut params = RatchetKdfParams::with_kdf(kdf);
+            // the defaults are slow without optimizations, and HKDF ignores them
+            params.m_cost = 64;
+            params.t_cost = 1;
+            let key = params.derive(b"correct horse").unwrap();
+            // read back from meta, it's the same key
+            let stored: RatchetKdfParams = serde_json::from_slice(&serde_json::to_vec(&params).unwrap()).unwrap();
+            assert_eq!(stored.derive(b"correct horse").unwrap(), key);
+            assert_ne!(params.derive(b"correct horse!").unwrap(), key);
+            let resalted = RatchetKdfParams { salt: rand::random::<[u8; 16]>().to_vec(), ..params.clone() };
+            assert_ne!(resalted.derive(b"correct horse").unwrap(), key);
+            keys.push(key);
+        }
+        assert_ne!(keys[0], keys[1]);
+    }
+
+    #[test]
+    fn legacy_upgrade_reseals_rows_under_a_derived_key() {
+        let _globals = key_globals();
+        let db = scratch_db("legacy-upgrade");
+        let user = serde_json::json!({ "username": "alice", "passhash": "$2b$04$x" });
+        put_legacy_row(&db, RATCHET_USERS_TABLE.unwrap(), "old masking key", "alice", &serde_json::to_vec(&user).unwrap());
+        rtp_note_legacy_rows(&db).unwrap();
+        assert!(rtp_read_kdf(&db).unwrap().is_none());
+
+        rtp_masking_key_unlock(&db, &String::from("old masking key")).unwrap();
+        let key = rtp_db_key();
+        let kdf = rtp_read_kdf(&db).unwrap().unwrap();
+        assert_eq!(*key, kdf.derive(b"old masking key").unwrap());
+        assert_ne!(*key, rtp_legacy_key(&String::from("old masking key")));
+        let read_txn = db.begin_read().unwrap();
+        let (good, bad) = RATCHET_USERS_TABLE.read_sorted::<serde_json::Value>(&read_txn, &key).unwrap();
+        assert_eq!(good, vec![(String::from("alice"), user)]);
+        assert!(bad.is_empty());
+        drop(read_txn);
+
+        // the next start derives the same key, and there's nothing left to upgrade
+        rtp_take_key([0; 32]);
+        rtp_masking_key_unlock(&db, &String::from("old masking key")).unwrap();
+        assert_eq!(rtp_db_key(), key);
+    }
+
+    /// What split-key does to `db`, but with the key handed back as well.
+    fn split_key(db: &Database, threshold: u8, n: usize) -> (RatchetKdfParams, Vec<Vec<u8>>, [u8; 32]) {
+        let secret = rand::random::<[u8; 32]>();
+        let mut kdf = RatchetKdfParams::with_kdf(RatchetKdf::HkdfSha256);
+        kdf.shamir_threshold = Some(threshold);
+        let split: Vec<Vec<u8>> = Sharks(threshold).dealer(&secret).take(n).map(|share| Vec::from(&share)).collect();
+        kdf.share_checks = split.iter().map(|share| (share[0], kdf.share_check(share))).collect();
+        let key = kdf.derive(hex::encode(secret).as_bytes()).unwrap();
+        rtp_rotate_key(db, &[0; 32], &key, &RatchetKeyRecord::Kdf(kdf.clone())).unwrap();
+        (kdf, split, key)
+    }
+
+    #[test]
+    fn any_threshold_of_the_shares_unseal() {
+        let _globals = key_globals();
+        let db = scratch_db("split-key");
+        let (kdf, split, key) = split_key(&db, 2, 3);
+        for pair in [[0, 1], [0, 2], [2, 1]] {
+            let shares: Vec<Vec<u8>> = pair.iter().map(|i| split[*i].clone()).collect();
+            assert_eq!(rtp_unseal_key(&db, 2, &shares).unwrap(), key);
+        }
+        assert!(rtp_unseal_key(&db, 2, &split[..1]).is_err());
+
+        // another split's shares make a key too, just not this one
+        let (_, other, _) = split_key(&scratch_db("split-key-other"), 2, 3);
+        assert!(matches!(rtp_unseal_key(&db, 2, &other[..2]), Err(RatchetStoreError::WrongKey)));
+        let sealed = RatchetSealed { threshold: 2, kdf };
+        assert!(split.iter().all(|share| sealed.holds(share)));
+        assert!(!other.iter().any(|share| sealed.holds(share)));
+        let mut tampered = split[0].clone();
+        tampered[1] ^= 1;
+        assert!(!sealed.holds(&tampered));
+    }
+
+    #[test]
+    fn legacy_upgrade_quarantines_rows_that_dont_open() {
+        let _globals = key_globals();
+        let db = scratch_db("legacy-damaged");
+        let user = serde_json::json!({ "username": "alice", "passhash": "$2b$04$x" });
+        put_legacy_row(&db, RATCHET_USERS_TABLE.unwrap(), "old masking key", "alice", &serde_json::to_vec(&user).unwrap());
+        put_legacy_row(&db, RATCHET_USERS_TABLE.unwrap(), "old masking key", "bob", &serde_json::to_vec(&user).unwrap());
+        // damaged on disk, FF1 turns it into garbage
+        let write_txn = db.begin_write().unwrap();
+        write_txn.open_table(RATCHET_USERS_TABLE.unwrap()).unwrap().insert("bob", vec![0x5a; 40]).unwrap();
+        write_txn.commit().unwrap();
+        rtp_note_legacy_rows(&db).unwrap();
+
+        rtp_masking_key_unlock(&db, &String::from("old masking key")).unwrap();
+        let read_txn = db.begin_read().unwrap();
+        let (good, bad) = RATCHET_USERS_TABLE.read_sorted::<serde_json::Value>(&read_txn, &rtp_db_key()).unwrap();
+        assert_eq!(good, vec![(String::from("alice"), user)]);
+        assert!(bad.is_empty());
+        // moved along with the rest, under its index key
+        let quarantined: Vec<RatchetQuarantined> = read_txn.open_table(RATCHET_QUARANTINE_TABLE).unwrap()
+                                                           .iter().unwrap()
+                                                           .map(|tup| serde_json::from_slice(&tup.unwrap().1.value()).unwrap())
+                                                           .collect();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].table, RATCHET_USERS_TABLE.name());
+        assert_eq!(quarantined[0].record_key, rtp_index_key(&rtp_db_key(), RATCHET_USERS_TABLE.name(), "bob").unwrap());
+    }
+
+    #[test]
+    fn legacy_upgrade_refuses_the_wrong_key() {
+        let _globals = key_globals();
+        let db = scratch_db("legacy-wrong-key");
+        put_legacy_row(&db, RATCHET_USERS_TABLE.unwrap(), "old masking key", "alice", br#"{"username":"alice","passhash":"$2b$04$x"}"#);
+        rtp_note_legacy_rows(&db).unwrap();
+
+        assert!(matches!(rtp_masking_key_unlock(&db, &String::from("not the key")), Err(RatchetStoreError::WrongKey)));
+        let read_txn = db.begin_read().unwrap();
+        assert_eq!(RATCHET_USERS_TABLE.count(&read_txn).unwrap(), 1);
+        assert!(read_txn.open_table(RATCHET_QUARANTINE_TABLE).is_err());
+    }
+
+    fn stored_keys(db: &Database, table: TableDefinition<&str, Vec<u8>>) -> Vec<String> {
+        let read_txn = db.begin_read().unwrap();
+        let table = read_txn.open_table(table).unwrap();
+        table.iter().unwrap().map(|tup| tup.unwrap().0.value().to_string()).collect()
+    }
+
+    #[test]
+    fn index_keys_hide_row_keys_and_move_with_the_key() {
+        let _globals = key_globals();
+        let db = scratch_db("index-keys");
+        let (old, new) = (rand::random::<[u8; 32]>(), rand::random::<[u8; 32]>());
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(RATCHET_USERS_TABLE.unwrap()).unwrap();
+            for name in ["alice", "bob"] {
+                let row = serde_json::json!({ "username": name });
+                let (index_key, sealed) = rtp_seal_indexed_record(&old, RATCHET_USERS_TABLE.name(), name, &serde_json::to_vec(&row).unwrap()).unwrap();
+                table.insert(index_key.as_str(), sealed).unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+        let mut expected: Vec<String> = ["alice", "bob"].iter().map(|n| rtp_index_key(&old, RATCHET_USERS_TABLE.name(), n).unwrap()).collect();
+        expected.sort();
+        assert_eq!(stored_keys(&db, RATCHET_USERS_TABLE.unwrap()), expected);
+        // per table, the same name elsewhere is stored under another key
+        assert_ne!(rtp_index_key(&old, RATCHET_USERS_TABLE.name(), "alice").unwrap(), rtp_index_key(&old, RATCHET_ADMINS_TABLE.name(), "alice").unwrap());
+
+        let n = rtp_rotate_key(&db, &old, &new, &RatchetKeyRecord::Kdf(RatchetKdfParams::with_kdf(RatchetKdf::HkdfSha256))).unwrap();
+        assert_eq!(n, 2);
+        let mut expected: Vec<String> = ["alice", "bob"].iter().map(|n| rtp_index_key(&new, RATCHET_USERS_TABLE.name(), n).unwrap()).collect();
+        expected.sort();
+        assert_eq!(stored_keys(&db, RATCHET_USERS_TABLE.unwrap()), expected);
+        let read_txn = db.begin_read().unwrap();
+        let (good, bad) = RATCHET_USERS_TABLE.read_sorted::<serde_json::Value>(&read_txn, &new).unwrap();
+        assert!(bad.is_empty());
+        for (own_key, row) in good.iter() {
+            assert_eq!(row["username"], own_key.as_str());
+        }
+        let mut names: Vec<&str> = good.iter().map(|(own_key, _)| own_key.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["alice", "bob"]);
+    }
+
+    /// A row the way pawl wrote them from the envelope until index keys:
+    /// V1, under its own key.
+    fn put_v1_row(db: &Database, table: TableDefinition<&str, Vec<u8>>, key: &[u8; 32], record_key: &str, row: serde_json::Value) {
+        let write_txn = db.begin_write().unwrap();
+        let sealed = rtp_seal_record(key, table.name(), record_key, &serde_json::to_vec(&row).unwrap()).unwrap();
+        write_txn.open_table(table).unwrap().insert(record_key, sealed).unwrap();
+        write_txn.commit().unwrap();
+    }
+
+    #[test]
+    fn migrations_bring_version_2_rows_up_to_date() {
+        let _globals = key_globals();
+        let db = scratch_db("migrate-v2");
+        let key = rand::random::<[u8; 32]>();
+        rtp_take_key(key);
+        put_v1_row(&db, RATCHET_USERS_TABLE.unwrap(), &key, "alice", serde_json::json!({ "username": "alice", "passhash": "$2b$04$x" }));
+        put_v1_row(&db, RATCHET_DEVS_TABLE.unwrap(), &key, "10.0.0.1", serde_json::json!({ "network_id": "10.0.0.1", "key": "tacacs", "description": null }));
+        put_v1_row(&db, RATCHET_USER_CMD_POLICY_TABLE.unwrap(), &key, "singleton", serde_json::json!("allow all"));
+        put_v1_row(&db, RATCHET_APIKEY_TABLE.unwrap(), &key, "v1-secret", serde_json::json!({ "api_key": "v1-secret" }));
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut meta = write_txn.open_table(RATCHET_META_TABLE).unwrap();
+            meta.insert(RATCHET_META_SCHEMA_VERSION, serde_json::to_vec(&2).unwrap()).unwrap();
+            let mut journal = write_txn.open_table(RATCHET_JOURNAL_TABLE).unwrap();
+            let entries = [
+                RatchetJournalEntry { seq: 1, table: RATCHET_USERS_TABLE.name().to_string(), record_key: String::from("alice"), previous: None, new: Some(serde_json::json!({ "username": "alice" })), actor: String::from("root"), at: 100 },
+                journal_entry(2, "v1-secret", None, Some(serde_json::json!({ "api_key": "v1-secret" }))),
+            ];
+            for e in entries.iter() {
+                journal.insert(e.seq, rtp_seal_record(&key, RATCHET_JOURNAL_TABLE.name(), &e.seq.to_string(), &serde_json::to_vec(e).unwrap()).unwrap()).unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+        rtp_note_legacy_rows(&db).unwrap();
+
+        rtp_migrate_database(&db).unwrap();
+        assert!(!RATCHET_LEGACY_ROWS.load(Ordering::SeqCst));
+        assert_eq!(rtp_parse_schema_version(rtp_read_meta(&db, RATCHET_META_SCHEMA_VERSION).unwrap()).unwrap(), RATCHET_SCHEMA_VERSION);
+        assert!(rtp_read_meta(&db, RATCHET_META_ENVELOPE_ONLY).unwrap().is_some());
+        assert_eq!(stored_keys(&db, RATCHET_USERS_TABLE.unwrap()), vec![rtp_index_key(&key, RATCHET_USERS_TABLE.name(), "alice").unwrap()]);
+
+        let read_txn = db.begin_read().unwrap();
+        let (users, bad) = RATCHET_USERS_TABLE.read_sorted::<RatchetUserEntry>(&read_txn, &key).unwrap();
+        assert!(bad.is_empty());
+        assert_eq!(users[0].1.stamp, RatchetStamp { created_by: String::from("root"), created_at: 100, updated_by: String::from("root"), updated_at: 100 });
+        let (devs, _) = RATCHET_DEVS_TABLE.read_sorted::<RatchetDevEntry>(&read_txn, &key).unwrap();
+        assert_eq!((devs[0].1.key.as_str(), devs[0].1.description.as_ref()), ("tacacs", None));
+        let (policy, _) = RATCHET_USER_CMD_POLICY_TABLE.read_sorted::<RatchetUserCmdPolicy>(&read_txn, &key).unwrap();
+        assert_eq!(policy[0].1.policy, "allow all");
+        let (api_keys, bad) = RATCHET_APIKEY_TABLE.read_sorted::<RatchetApiKey>(&read_txn, &key).unwrap();
+        assert!(bad.is_empty());
+        let hmac = rtp_api_key_secret_in(&db.begin_write().unwrap(), &key).unwrap();
+        assert_eq!(api_keys[0].0, RATCHET_DEFAULT_API_KEY);
+        assert!(rtp_api_key_verify(&hmac, "v1-secret", &api_keys[0].1.hash));
+        // its journal entry is under the name now, so that's where its stamp came from
+        assert_eq!(api_keys[0].1.stamp.created_by, TESTER);
+        let journal = read_txn.open_table(RATCHET_JOURNAL_TABLE).unwrap();
+        let entry = rtp_open_record(&key, RATCHET_JOURNAL_TABLE.name(), "2", &journal.get(2).unwrap().unwrap().value()).unwrap();
+        assert!(!String::from_utf8_lossy(&entry).contains("v1-secret"));
+    }
+
+    fn journal_entry(seq: u64, record_key: &str, previous: Option<serde_json::Value>, new: Option<serde_json::Value>) -> RatchetJournalEntry {
+        RatchetJournalEntry { seq, table: RATCHET_APIKEY_TABLE.name().to_string(), record_key: record_key.to_string(), previous, new, actor: String::from(TESTER), at: 0 }
+    }
+
+    #[test]
+    fn migration_5_hashes_api_keys_in_the_journal() {
+        let db = scratch_db("journal-api-keys");
+        let (key, hmac) = (rand::random::<[u8; 32]>(), rand::random::<[u8; 32]>());
+        let v4 = serde_json::json!({ "name": "ci", "api_key": "ci-secret", "scopes": [], "created": 1, "expires": null });
+        let entries = [
+            journal_entry(1, "v1-secret", None, Some(serde_json::json!({ "api_key": "v1-secret" }))),
+            journal_entry(2, "ci", None, Some(v4.clone())),
+            journal_entry(3, "ci", Some(v4), None),
+        ];
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut journal = write_txn.open_table(RATCHET_JOURNAL_TABLE).unwrap();
+            for e in entries.iter() {
+                journal.insert(e.seq, rtp_seal_record(&key, RATCHET_JOURNAL_TABLE.name(), &e.seq.to_string(), &serde_json::to_vec(e).unwrap()).unwrap()).unwrap();
+            }
+        }
+        assert_eq!(rtp_hash_journal_api_keys(&write_txn, &key, &hmac).unwrap(), 3);
+        write_txn.commit().unwrap();
+
+        let read_txn = db.begin_read().unwrap();
+        let journal = read_txn.open_table(RATCHET_JOURNAL_TABLE).unwrap();
+        let entries: Vec<RatchetJournalEntry> = journal.iter().unwrap().map(|tup| {
+            let (seq, stored) = tup.unwrap();
+            serde_json::from_slice(&rtp_open_record(&key, RATCHET_JOURNAL_TABLE.name(), &seq.value().to_string(), &stored.value()).unwrap()).unwrap()
+        }).collect();
+        let text = serde_json::to_string(&entries).unwrap();
+        assert!(!text.contains("ci-secret") && !text.contains("v1-secret"));
+        let first: RatchetApiKey = serde_json::from_value(entries[0].new.clone().unwrap()).unwrap();
+        assert_eq!(entries[0].record_key, first.name);
+        let ci: RatchetApiKey = serde_json::from_value(entries[2].previous.clone().unwrap()).unwrap();
+        assert!(rtp_api_key_verify(&hmac, "ci-secret", &ci.hash));
+        // and the hashed entry can be rolled back
+        assert_eq!(rtp_rollback_changes(&entries, 2, None, TESTER).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rollback_refuses_api_keys_from_before_hashing() {
+        let old = serde_json::json!({ "name": "ci", "api_key": "ci-secret", "scopes": [], "created": 1, "expires": null });
+        let entries = [journal_entry(7, "ci", Some(old), None)];
+        let refused = rtp_rollback_changes(&entries, 6, None, TESTER).err();
+        assert!(matches!(&refused, Some(RatchetStoreError::Format(e)) if e.contains("entry 7")), "{:?}", refused);
+    }
+
+    const TESTER: &str = "tester";
+    const PASSWORD: &str = "correct horse battery staple";
+
+    /// Pawl on memory storage, with the superadmin TESTER logged in. Hold on
+    /// to the guard until the test is done.
+    async fn client() -> (rocket::tokio::sync::MutexGuard<'static, bool>, Client) {
+        let mut started = STARTED.lock().await;
+        if !*started {
+            let dir = std::env::temp_dir().join(format!("ratchet-pawl-test-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::env::set_var("RATCHET_PAWL_STORAGE", "memory");
+            std::env::set_var("RATCHET_PAWL_API_KEY_FILE", dir.join("ratchet-api-key"));
+            std::env::set_var("RATCHET_PAWL_AUDIT_REPEAT_SECS", "0");
+            drop(rocket().await.unwrap());
+            // cheap to check, unlike the first admin's
+            let setup = pwhash::bcrypt::BcryptSetup { cost: Some(4), ..Default::default() };
+            let tester = RatchetAdminEntry {
+                username: String::from(TESTER),
+                passhash: bcrypt::hash_with(setup, PASSWORD).unwrap(),
+                role: RatchetRole::Superadmin,
+                stamp: RatchetStamp::new(RATCHET_SYSTEM_ACTOR),
+            };
+            RATCHET_ADMINS_TABLE.write(&tester, RATCHET_SYSTEM_ACTOR).await.unwrap();
+            RATCHET_ADMINS.lock().await.insert(tester.username.clone(), tester);
+            *started = true;
+        }
+        let client = Client::tracked(rtp_web_rocket()).await.unwrap();
+        assert_eq!(login(&client, TESTER, PASSWORD).await, Status::Ok);
+        (started, client)
+    }
+
+    /// A multipart/form-data body, `files` with a filename.
+    fn multipart(fields: &[(&str, &str)], files: &[(&str, &[u8])]) -> (ContentType, Vec<u8>) {
+        let boundary = "ratchet-pawl-test";
+        let mut body = vec![];
+        for (name, value) in fields {
+            body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", boundary, name, value).as_bytes());
+        }
+        for (name, value) in files {
+            body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n", boundary, name, name).as_bytes());
+            body.extend(b"Content-Type: application/octet-stream\r\n\r\n");
+            body.extend(*value);
+            body.extend(b"\r\n");
+        }
+        body.extend(format!("--{}--\r\n", boundary).as_bytes());
+        (ContentType::new("multipart", "form-data").with_params(("boundary", boundary)), body)
+    }
+
+    async fn post(client: &Client, uri: &str, fields: &[(&str, &str)]) -> (Status, String) {
+        let (content_type, body) = multipart(fields, &[]);
+        let r = client.post(uri.to_string()).header(content_type).body(body).dispatch().await;
+        (r.status(), r.into_string().await.unwrap_or_default())
+    }
+
+    async fn get(client: &Client, uri: &str) -> (Status, String) {
+        let r = client.get(uri.to_string()).dispatch().await;
+        (r.status(), r.into_string().await.unwrap_or_default())
+    }
+
+    async fn get_json(client: &Client, uri: &str) -> Vec<serde_json::Value> {
+        let (status, body) = get(client, uri).await;
+        assert_eq!(status, Status::Ok, "{}", uri);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    async fn api(client: &Client, uri: &str, key: &str) -> (Status, String) {
+        let r = client.get(uri.to_string()).header(rocket::http::Header::new("X-Ratchet-Api-Key", key.to_string())).dispatch().await;
+        (r.status(), r.into_string().await.unwrap_or_default())
+    }
+
+    async fn login(client: &Client, username: &str, password: &str) -> Status {
+        post(client, "/trylogin", &[("username", username), ("password", password)]).await.0
+    }
+
+    async fn add_api_key(client: &Client, name: &str, scopes: &str) -> String {
+        let (status, key) = post(client, "/addapikey", &[("name", name), ("scopes", scopes)]).await;
+        assert_eq!(status, Status::Ok);
+        key
+    }
+
+    fn has(listed: &[serde_json::Value], field: &str, value: &str) -> bool {
+        listed.iter().any(|v| v[field] == value)
+    }
+
+    #[rocket::async_test]
+    async fn sessions_and_logging_out() {
+        let (_started, client) = client().await;
+        assert_eq!(get(&client, "/logged").await, (Status::Ok, String::from("superadmin")));
+        let (status, me) = get(&client, "/whoami").await;
+        assert_eq!(status, Status::Ok);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&me).unwrap()["username"], TESTER);
+        assert!(has(&get_json(&client, "/getsessions").await, "username", TESTER));
+
+        assert_eq!(get(&client, "/hangup").await.0, Status::Ok);
+        assert_eq!(get(&client, "/logged").await.0, Status::Unauthorized);
+
+        // or everywhere at once
+        assert_eq!(login(&client, TESTER, PASSWORD).await, Status::Ok);
+        assert_eq!(post(&client, "/rmsessions", &[("username", TESTER)]).await.0, Status::Ok);
+        assert_eq!(get(&client, "/logged").await.0, Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn lockouts() {
+        let (_started, client) = client().await;
+        assert_eq!(login(&client, "mallory", "guess").await, Status::Unauthorized);
+        let lockouts = get_json(&client, "/getlockouts").await;
+        assert!(lockouts.iter().any(|l| l["username"] == "mallory" && l["failures"] == 1));
+        assert_eq!(post(&client, "/unlocklogin", &[("subject", "mallory")]).await.0, Status::Ok);
+        assert!(!has(&get_json(&client, "/getlockouts").await, "username", "mallory"));
+        assert_eq!(post(&client, "/unlocklogin", &[("subject", "mallory")]).await.0, Status::Gone);
+    }
+
+    #[rocket::async_test]
+    async fn users() {
+        let (_started, client) = client().await;
+        assert_eq!(post(&client, "/adduser", &[("username", "alice"), ("passhash", "pw")]).await.0, Status::Ok);
+        assert_eq!(post(&client, "/adduser", &[("username", "alice"), ("passhash", "pw")]).await.0, Status::Conflict);
+        let users = get_json(&client, "/getusers").await;
+        assert!(users.iter().any(|u| u["username"] == "alice" && u["created_by"] == TESTER));
+        assert_eq!(post(&client, "/edituser", &[("username", "alice"), ("passhash", "pw2")]).await.0, Status::Ok);
+        assert_eq!(post(&client, "/rmuser", &[("username", "alice")]).await.0, Status::Ok);
+        assert_eq!(post(&client, "/rmuser", &[("username", "alice")]).await.0, Status::Gone);
+        assert!(!has(&get_json(&client, "/getusers").await, "username", "alice"));
+    }
+
+    #[rocket::async_test]
+    async fn devices() {
+        let (_started, client) = client().await;
+        let dev = [("network_id", "10.9.0.1"), ("key", "k1"), ("description", "lab")];
+        assert_eq!(post(&client, "/adddev", &dev).await.0, Status::Ok);
+        assert_eq!(post(&client, "/adddev", &dev).await.0, Status::Conflict);
+        let devs = get_json(&client, "/getdevs").await;
+        assert!(devs.iter().any(|d| d["network_id"] == "10.9.0.1" && d["description"] == "lab"));
+        assert_eq!(post(&client, "/editdev", &[("network_id", "10.9.0.1"), ("key", "k2")]).await.0, Status::Ok);
+        assert_eq!(post(&client, "/rmdev", &[("network_id", "10.9.0.1")]).await.0, Status::Ok);
+        assert_eq!(post(&client, "/rmdev", &[("network_id", "10.9.0.1")]).await.0, Status::Gone);
+    }
+
+    #[rocket::async_test]
+    async fn admins_and_roles() {
+        let (_started, client) = client().await;
+        let olivia = [("username", "olivia"), ("password", "olivia's password"), ("role", "viewer")];
+        assert_eq!(post(&client, "/addadmin", &olivia).await.0, Status::Ok);
+        let admins = get_json(&client, "/getadmins").await;
+        assert!(admins.iter().any(|a| a["username"] == "olivia" && a["role"] == "viewer"));
+
+        // a viewer looks, and that's all
+        let viewer = Client::tracked(rtp_web_rocket()).await.unwrap();
+        assert_eq!(login(&viewer, "olivia", "olivia's password").await, Status::Ok);
+        assert_eq!(get(&viewer, "/getusers").await.0, Status::Ok);
+        assert_eq!(post(&viewer, "/adduser", &[("username", "bert"), ("passhash", "pw")]).await.0, Status::Forbidden);
+        assert_eq!(get(&viewer, "/getadmins").await.0, Status::Forbidden);
+
+        assert_eq!(post(&client, "/editadmin", &[("username", "olivia"), ("role", "operator")]).await.0, Status::Ok);
+        // edits log the admin out
+        assert_eq!(get(&viewer, "/logged").await.0, Status::Unauthorized);
+        assert_eq!(login(&viewer, "olivia", "olivia's password").await, Status::Ok);
+        assert_eq!(post(&viewer, "/adduser", &[("username", "bert"), ("passhash", "pw")]).await.0, Status::Ok);
+        assert_eq!(post(&viewer, "/rmuser", &[("username", "bert")]).await.0, Status::Ok);
+
+        assert_eq!(post(&client, "/rmadmin", &[("username", "olivia")]).await.0, Status::Ok);
+        assert_eq!(get(&viewer, "/logged").await.0, Status::Unauthorized);
+        assert_eq!(post(&client, "/rmadmin", &[("username", "olivia")]).await.0, Status::Gone);
+    }
+
+    #[rocket::async_test]
+    async fn policy() {
+        let (_started, client) = client().await;
+        let key = add_api_key(&client, "policy-test", "dump:policy").await;
+        let good = "$\nalice\n(\n)\n";
+        assert_eq!(post(&client, "/validatepolicy", &[("0", good)]).await.0, Status::Ok);
+        assert_eq!(post(&client, "/validatepolicy", &[("0", "$\nalice\n")]).await.0, Status::Conflict);
+        assert_eq!(post(&client, "/pushpolicy", &[("0", "$\nalice\n")]).await.0, Status::Conflict);
+        assert_eq!(post(&client, "/pushpolicy", &[("0", good)]).await.0, Status::Ok);
+        assert_eq!(get(&client, "/getpolicy").await, (Status::Ok, String::from(good)));
+        assert_eq!(api(&client, "/api/dumppolicy", &key).await, (Status::Ok, String::from(good)));
+        assert_eq!(post(&client, "/rmapikey", &[("name", "policy-test")]).await.0, Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn api_keys_and_scopes() {
+        let (_started, client) = client().await;
+        let key = add_api_key(&client, "dumper", "dump:users,dump:devs,metrics").await;
+        assert_eq!(post(&client, "/addapikey", &[("name", "dumper"), ("scopes", "poll")]).await.0, Status::Conflict);
+        assert_eq!(post(&client, "/addapikey", &[("name", "nothing"), ("scopes", "everything")]).await.0, Status::BadRequest);
+        let keys = get_json(&client, "/getapikeys").await;
+        assert!(keys.iter().any(|k| k["name"] == "dumper" && k["scopes"].as_array().is_some_and(|s| s.len() == 3)));
+        assert!(!keys.iter().any(|k| k.to_string().contains(&key)));
+
+        assert_eq!(api(&client, "/api/dumpusers", &key).await.0, Status::Ok);
+        assert_eq!(api(&client, "/api/dumpdevs", &key).await.0, Status::Ok);
+        let (status, metrics) = api(&client, "/api/metrics", &key).await;
+        assert_eq!(status, Status::Ok);
+        assert!(metrics.contains("ratchet_pawl_"));
+        assert_eq!(api(&client, "/api/dumppolicy", &key).await.0, Status::Forbidden);
+        assert_eq!(api(&client, "/api/dumpusers", "not a key").await.0, Status::NotFound);
+        assert_eq!(get(&client, "/api/dumpusers").await.0, Status::NotFound);
+
+        assert_eq!(post(&client, "/rmapikey", &[("name", "dumper")]).await.0, Status::Ok);
+        assert_eq!(api(&client, "/api/dumpusers", &key).await.0, Status::NotFound);
+        assert_eq!(post(&client, "/rmapikey", &[("name", "dumper")]).await.0, Status::Gone);
+    }
+
+    #[rocket::async_test]
+    async fn long_polls() {
+        let (_started, client) = client().await;
+        let key = add_api_key(&client, "poller", "poll").await;
+        let (status, update) = api(&client, &format!("/api/longpoll?serial={}", u64::MAX), &key).await;
+        assert_eq!(status, Status::Ok);
+        let serial = update.strip_prefix("Update ").unwrap().to_string();
+        let uri = format!("/api/longpoll?serial={}", serial);
+
+        // woken by a change
+        let change = async {
+            rocket::tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            post(&client, "/adduser", &[("username", "polly"), ("passhash", "pw")]).await
+        };
+        let (polled, changed) = rocket::tokio::join!(api(&client, &uri, &key), change);
+        assert_eq!(changed.0, Status::Ok);
+        assert_eq!(polled.0, Status::Ok);
+        assert!(polled.1.starts_with("Update "));
+        let epoch = LONG_POLL_EPOCH.load(Ordering::Relaxed);
+        assert_eq!(post(&client, "/rmuser", &[("username", "polly")]).await.0, Status::Ok);
+        // its wake-up is spawned, and would answer the next poll if late
+        while LONG_POLL_EPOCH.load(Ordering::Relaxed) == epoch {
+            rocket::tokio::task::yield_now().await;
+        }
+
+        // and turned away once revoked
+        let (_, update) = api(&client, &format!("/api/longpoll?serial={}", u64::MAX), &key).await;
+        let uri = format!("/api/longpoll?serial={}", update.strip_prefix("Update ").unwrap());
+        let revoke = async {
+            rocket::tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            post(&client, "/rmapikey", &[("name", "poller")]).await
+        };
+        let (polled, revoked) = rocket::tokio::join!(api(&client, &uri, &key), revoke);
+        assert_eq!(revoked.0, Status::Ok);
+        assert_eq!(polled, (Status::Forbidden, String::from("Revoked")));
+    }
+
+    #[rocket::async_test]
+    async fn journal_rollback_and_audit() {
+        let (_started, client) = client().await;
+        assert_eq!(post(&client, "/adduser", &[("username", "jules"), ("passhash", "pw")]).await.0, Status::Ok);
+        let journal = get_json(&client, "/getjournal?limit=100000").await;
+        let added = journal.iter().rev().find(|e| e["record_key"] == "jules").unwrap();
+        assert_eq!(added["actor"], TESTER);
+        let seq = added["seq"].as_u64().unwrap();
+        assert!(!get_json(&client, "/gethistory?table=ratchet_users&key=jules").await.is_empty());
+        assert_eq!(get_json(&client, &format!("/getjournal?after={}&limit=1", seq - 1)).await[0]["seq"], seq);
+
+        let to = (seq - 1).to_string();
+        let object = seq.to_string();
+        assert_eq!(post(&client, "/rollback", &[("to", &to), ("object", &object)]).await.0, Status::Ok);
+        assert!(!has(&get_json(&client, "/getusers").await, "username", "jules"));
+
+        let audit = get_json(&client, &format!("/getaudit?event=user_add&object=jules&actor={}", TESTER)).await;
+        assert!(audit.iter().any(|e| e["detail"].as_str().is_some_and(|d| d.starts_with(&format!("journal {}", seq)))));
+        assert!(!get_json(&client, "/getaudit?event=user_rm&object=jules").await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn backup_and_restore() {
+        let (_started, client) = client().await;
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+        let (content_type, body) = multipart(&[("recipient", &recipient)], &[]);
+        let r = client.post("/backup").header(content_type).body(body).dispatch().await;
+        assert_eq!(r.status(), Status::Ok);
+        let archive = r.into_bytes().await.unwrap();
+
+        assert_eq!(post(&client, "/adduser", &[("username", "rosa"), ("passhash", "pw")]).await.0, Status::Ok);
+        let identity = identity.to_string();
+        for dry_run in ["true", "false"] {
+            let (content_type, body) = multipart(&[("identity", identity.expose_secret()), ("dry_run", dry_run)], &[("archive", &archive)]);
+            let r = client.post("/restore").header(content_type).body(body).dispatch().await;
+            assert_eq!(r.status(), Status::Ok);
+            let summary: serde_json::Value = serde_json::from_str(&r.into_string().await.unwrap()).unwrap();
+            assert_eq!(summary["applied"], dry_run == "false");
+            assert_eq!(summary["users"]["removed"], 1);
+            assert_eq!(has(&get_json(&client, "/getusers").await, "username", "rosa"), dry_run == "true");
+        }
+        let (content_type, body) = multipart(&[("identity", "AGE-SECRET-KEY-NOPE"), ("dry_run", "true")], &[("archive", &archive)]);
+        assert_eq!(client.post("/restore").header(content_type).body(body).dispatch().await.status(), Status::Conflict);
+    }
+
+    #[rocket::async_test]
+    async fn quarantine() {
+        let (_started, client) = client().await;
+        assert!(get_json(&client, "/getquarantine").await.is_empty());
+        assert_eq!(post(&client, "/rmquarantine", &[("id", "nope")]).await.0, Status::Gone);
+    }
+
+    #[rocket::async_test]
+    async fn unseal_only_holds_this_databases_shares() {
+        let _globals = KeyGlobals::keep(STARTED.lock().await);
+        let (kdf, split, _) = split_key(&scratch_db("unseal"), 3, 3);
+        let (_, other, _) = split_key(&scratch_db("unseal-other"), 3, 3);
+        let client = Client::untracked(rtp_sealed_rocket(RatchetSealed { threshold: 3, kdf })).await.unwrap();
+        let unseal = |from: u8, share: &[u8]| {
+            let (content_type, body) = multipart(&[("share", &hex::encode(share))], &[]);
+            let remote = std::net::SocketAddr::from(([192, 0, 2, from], 4000));
+            let client = &client;
+            async move {
+                let r = client.post("/unseal").header(content_type).remote(remote).body(body).dispatch().await;
+                (r.status(), r.into_string().await.unwrap_or_default())
+            }
+        };
+
+        assert_eq!(unseal(1, b"not a share").await.0, Status::Conflict);
+        assert_eq!(unseal(2, &other[0]).await.0, Status::Conflict);
+        assert_eq!(unseal(3, &split[0]).await, (Status::Ok, String::from("Received 1 of 3 shares")));
+        // one a second from each source
+        assert_eq!(unseal(3, &split[1]).await.0, Status::TooManyRequests);
+        assert_eq!(unseal(4, &split[0]).await, (Status::Conflict, format!("Already have share {}", split[0][0])));
+        assert_eq!(unseal(5, &split[1]).await, (Status::Ok, String::from("Received 2 of 3 shares")));
+        RATCHET_UNSEAL_SHARES.lock().await.shares.clear();
+    }
+
+    #[rocket::async_test]
+    async fn rotation() {
+        let (_started, client) = client().await;
+        // nothing to rotate in memory
+        assert_eq!(post(&client, "/rotatekey", &[("old_key", "a"), ("new_key", "b")]).await.0, Status::Conflict);
+    }
+}
@@ -0,0 +1,129 @@
+// softhsm
+//
+// The PKCS#11 key provider against a throwaway SoftHSM2 token, see
+// "PKCS#11 key wrapping" in the README. Needs SoftHSM2 installed:
+//
+//     cargo test --features softhsm-tests --test softhsm
+//
+// RATCHET_PAWL_TEST_SOFTHSM2 is the module, if it isn't somewhere usual.
+//
+#![cfg(feature = "softhsm-tests")]
+
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, KeyType, ObjectClass};
+use cryptoki::session::UserType;
+use cryptoki::types::AuthPin;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+const USER_PIN: &str = "1234";
+const SO_PIN: &str = "4321";
+const KEY_LABEL: &str = "ratchet-pawl-kek";
+
+fn module() -> String {
+    if let Ok(m) = std::env::var("RATCHET_PAWL_TEST_SOFTHSM2") {
+        return m;
+    }
+    ["/usr/lib/softhsm/libsofthsm2.so",
+     "/usr/lib/x86_64-linux-gnu/softhsm/libsofthsm2.so",
+     "/usr/lib64/pkcs11/libsofthsm2.so",
+     "/usr/local/lib/softhsm/libsofthsm2.so"]
+        .into_iter()
+        .find(|p| Path::new(p).exists())
+        .expect("SoftHSM2 isn't installed, or set RATCHET_PAWL_TEST_SOFTHSM2")
+        .to_string()
+}
+
+/// A directory of its own, with a SoftHSM2 config whose tokens live in it.
+fn scratch() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ratchet-pawl-softhsm-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("tokens")).unwrap();
+    std::fs::write(dir.join("softhsm2.conf"),
+                   format!("directories.tokendir = {}\nobjectstore.backend = file\n", dir.join("tokens").display())).unwrap();
+    dir
+}
+
+/// A fresh token with an AES key on it, labelled the way pawl looks for it.
+fn init_token(dir: &Path) {
+    std::env::set_var("SOFTHSM2_CONF", dir.join("softhsm2.conf"));
+    let ctx = Pkcs11::new(module()).unwrap();
+    ctx.initialize(CInitializeArgs::OsThreads).unwrap();
+    // SoftHSM2 always has one free slot, its token uninitialized.
+    let free = ctx.get_slots_with_token().unwrap()[0];
+    ctx.init_token(free, &AuthPin::new(SO_PIN.into()), "ratchet").unwrap();
+    let slot = ctx.get_slots_with_initialized_token().unwrap()[0];
+    let session = ctx.open_rw_session(slot).unwrap();
+    session.login(UserType::So, Some(&AuthPin::new(SO_PIN.into()))).unwrap();
+    session.init_pin(&AuthPin::new(USER_PIN.into())).unwrap();
+    session.logout().unwrap();
+    session.login(UserType::User, Some(&AuthPin::new(USER_PIN.into()))).unwrap();
+    session.generate_key(&Mechanism::AesKeyGen, &[
+        Attribute::Class(ObjectClass::SECRET_KEY),
+        Attribute::KeyType(KeyType::AES),
+        Attribute::ValueLen(32.into()),
+        Attribute::Label(KEY_LABEL.as_bytes().to_vec()),
+        Attribute::Token(true),
+        Attribute::Private(true),
+        Attribute::Sensitive(true),
+        Attribute::Encrypt(true),
+        Attribute::Decrypt(true),
+    ]).unwrap();
+}
+
+fn pawl(dir: &Path, pin: &str) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_ratchet-pawl"));
+    cmd.arg("--data-dir").arg(dir.join("data"))
+       .env("SOFTHSM2_CONF", dir.join("softhsm2.conf"))
+       .env("RATCHET_PAWL_KEY_PROVIDER", "pkcs11")
+       .env("RATCHET_PAWL_PKCS11_MODULE", module())
+       .env("RATCHET_PAWL_PKCS11_PIN", pin)
+       .env_remove("RATCHET_PAWL_MASKING_KEY")
+       .stdin(Stdio::null());
+    cmd
+}
+
+fn check(dir: &Path, pin: &str) -> Output {
+    pawl(dir, pin).arg("check").output().unwrap()
+}
+
+#[test]
+fn database_key_is_wrapped_by_the_token() {
+    let dir = scratch();
+    init_token(&dir);
+
+    // The first start makes a random key, wraps it, and seeds the database.
+    let socket = dir.join("control.sock");
+    let mut running = pawl(&dir, USER_PIN)
+                          .env("RATCHET_PAWL_WEB", "off")
+                          .env("RATCHET_PAWL_CONTROL_SOCKET", &socket)
+                          .stdout(Stdio::null())
+                          .spawn()
+                          .unwrap();
+    let started = Instant::now();
+    while !socket.exists() {
+        assert!(started.elapsed() < Duration::from_secs(60), "pawl never came up on the token");
+        assert!(running.try_wait().unwrap().is_none(), "pawl exited on the token");
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    running.kill().unwrap();
+    running.wait().unwrap();
+
+    let opened = check(&dir, USER_PIN);
+    assert!(opened.status.success(), "check: {}", String::from_utf8_lossy(&opened.stdout));
+    assert!(String::from_utf8_lossy(&opened.stdout).contains("\"ok\": true"));
+
+    assert!(!check(&dir, "0000").status.success(), "a wrong PIN opened the database");
+
+    let masking_key = pawl(&dir, USER_PIN)
+                          .env_remove("RATCHET_PAWL_KEY_PROVIDER")
+                          .env("RATCHET_PAWL_MASKING_KEY", "not-the-key")
+                          .arg("check")
+                          .output()
+                          .unwrap();
+    assert!(!masking_key.status.success(), "a masking key opened a token-wrapped database");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}